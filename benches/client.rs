@@ -0,0 +1,177 @@
+//! Round trips between a client stream and servers on localhost.
+
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UdpSocket,
+    runtime::Runtime,
+};
+use udp_stream::UdpStream;
+
+fn runtime() -> Runtime {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+}
+
+/// Spawns a task sending every datagram back to its source.
+async fn echo_server() -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 65536];
+        while let Ok((len, peer_addr)) = socket.recv_from(&mut buf).await {
+            let _ = socket.send_to(&buf[..len], peer_addr).await;
+        }
+    });
+    addr
+}
+
+/// Datagrams a burst server answers each request with.
+const BURST: usize = 64;
+
+/// A burst server drops every `LOSS`th datagram of a burst.
+const LOSS: usize = 8;
+
+/// Spawns a task answering every datagram with a burst of [`BURST`]
+/// datagrams, losing some and pausing now and then as a jittery, lossy link
+/// would.
+async fn burst_server() -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 64];
+        while let Ok((_, peer_addr)) = socket.recv_from(&mut buf).await {
+            for i in 1..=BURST {
+                if i % LOSS == 0 {
+                    tokio::task::yield_now().await;
+                    continue;
+                }
+                let _ = socket.send_to(&[0u8; 1200], peer_addr).await;
+            }
+        }
+    });
+    addr
+}
+
+/// Sends `iters` datagrams through `stream`, each once the previous one came
+/// back.
+async fn round_trips(stream: &mut UdpStream, iters: u64) -> Duration {
+    let mut buf = [0u8; 64];
+    let start = Instant::now();
+    for _ in 0..iters {
+        stream.write_all(b"ping").await.unwrap();
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 4);
+    }
+    start.elapsed()
+}
+
+fn direct_vs_channel(c: &mut Criterion) {
+    let runtime = runtime();
+    let server = runtime.block_on(echo_server());
+    let mut group = c.benchmark_group("round_trip");
+    group.bench_function("channel", |b| {
+        b.iter_custom(|iters| {
+            runtime.block_on(async {
+                let mut stream = UdpStream::connect(server).await.unwrap();
+                round_trips(&mut stream, iters).await
+            })
+        })
+    });
+    group.bench_function("direct", |b| {
+        b.iter_custom(|iters| {
+            runtime.block_on(async {
+                let mut stream = UdpStream::connect_direct(server).await.unwrap();
+                round_trips(&mut stream, iters).await
+            })
+        })
+    });
+    group.finish();
+}
+
+fn prefetch(c: &mut Criterion) {
+    let runtime = runtime();
+    let server = runtime.block_on(burst_server());
+    let mut group = c.benchmark_group("lossy_burst");
+    for depth in [1, 16, BURST] {
+        group.bench_function(format!("prefetch_{}", depth), |b| {
+            b.iter_custom(|iters| {
+                runtime.block_on(async {
+                    let mut stream = UdpStream::builder()
+                        .prefetch_depth(depth)
+                        .connect(server)
+                        .await
+                        .unwrap();
+                    let mut buf = [0u8; 1500];
+                    let start = Instant::now();
+                    for _ in 0..iters {
+                        stream.write_all(b"go").await.unwrap();
+                        for _ in 0..BURST - BURST / LOSS {
+                            assert_eq!(stream.read(&mut buf).await.unwrap(), 1200);
+                        }
+                    }
+                    start.elapsed()
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Datagrams sent per iteration of the bulk send benchmark.
+const BULK: usize = 1000;
+
+/// Sends [`BULK`] small datagrams per iteration with a single `send_all`,
+/// which takes 16 `sendmmsg` calls on Linux, and with a loop of
+/// `send_datagram`, which takes one `sendto` each.
+///
+/// The server socket is never read, so the kernel drops whatever its receive
+/// buffer cannot hold: only the sending side is measured.
+fn bulk_send(c: &mut Criterion) {
+    let runtime = runtime();
+    let (server, stream) = runtime.block_on(async {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let stream = UdpStream::connect(server.local_addr().unwrap())
+            .await
+            .unwrap();
+        (server, stream)
+    });
+    let datagrams = vec![&[0u8; 32][..]; BULK];
+    let mut group = c.benchmark_group("bulk_send");
+    group.throughput(Throughput::Elements(BULK as u64));
+    group.bench_function("send_all", |b| {
+        b.iter_custom(|iters| {
+            runtime.block_on(async {
+                let start = Instant::now();
+                for _ in 0..iters {
+                    assert_eq!(stream.send_all(&datagrams).await.unwrap(), BULK);
+                }
+                start.elapsed()
+            })
+        })
+    });
+    group.bench_function("send_datagram_loop", |b| {
+        b.iter_custom(|iters| {
+            runtime.block_on(async {
+                let start = Instant::now();
+                for _ in 0..iters {
+                    for datagram in &datagrams {
+                        stream.send_datagram(datagram).await.unwrap();
+                    }
+                }
+                start.elapsed()
+            })
+        })
+    });
+    group.finish();
+    drop(server);
+}
+
+criterion_group!(benches, direct_vs_channel, prefetch, bulk_send);
+criterion_main!(benches);
@@ -0,0 +1,273 @@
+//! Datagrams received by a listener from clients on localhost.
+
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use tokio::{net::UdpSocket, runtime::Runtime};
+use udp_stream::{PeerHasher, UdpListener, UdpListenerBuilder};
+
+/// How many datagrams are in flight at most, few enough for the socket
+/// buffers to never drop any.
+const WINDOW: u64 = 32;
+
+fn runtime() -> Runtime {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+}
+
+fn localhost() -> SocketAddr {
+    "127.0.0.1:0".parse().unwrap()
+}
+
+/// Sends `iters` datagrams to `listener` from a single client, a window at a
+/// time, and returns how long the accepted stream took to read them.
+async fn one_peer(listener: &UdpListener, iters: u64) -> Duration {
+    let client = UdpSocket::bind(localhost()).await.unwrap();
+    client
+        .connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    client.send(b"hello").await.unwrap();
+    let (stream, _) = listener.accept().await.unwrap();
+    stream.recv().await.unwrap();
+
+    let datagram = [0u8; 64];
+    let start = Instant::now();
+    let mut remaining = iters;
+    while remaining > 0 {
+        let window = remaining.min(WINDOW);
+        for _ in 0..window {
+            client.send(&datagram).await.unwrap();
+        }
+        for _ in 0..window {
+            stream.recv().await.unwrap();
+        }
+        remaining -= window;
+    }
+    start.elapsed()
+}
+
+/// How many peers send to the listener at once in the many peers benchmarks.
+const PEERS: usize = 64;
+
+/// Has `PEERS` clients send `iters` datagrams to `listener` in turn, a window
+/// at a time, and returns how long their accepted streams took to read them.
+async fn many_peers(listener: &UdpListener, iters: u64) -> Duration {
+    let addr = listener.local_addr().unwrap();
+    let mut peers = Vec::with_capacity(PEERS);
+    for _ in 0..PEERS {
+        let client = UdpSocket::bind(localhost()).await.unwrap();
+        client.connect(addr).await.unwrap();
+        client.send(b"hello").await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        stream.recv().await.unwrap();
+        peers.push((client, stream));
+    }
+
+    let datagram = [0u8; 64];
+    let start = Instant::now();
+    let mut remaining = iters;
+    while remaining > 0 {
+        let window = remaining.min(WINDOW);
+        for i in 0..window {
+            peers[i as usize % PEERS].0.send(&datagram).await.unwrap();
+        }
+        for i in 0..window {
+            peers[i as usize % PEERS].1.recv().await.unwrap();
+        }
+        remaining -= window;
+    }
+    start.elapsed()
+}
+
+/// A listener configuration and its name in a benchmark group.
+type Config = (&'static str, fn() -> UdpListenerBuilder);
+
+fn bench_one_peer(c: &mut Criterion, group: &str, configs: &[Config]) {
+    let runtime = runtime();
+    let mut group = c.benchmark_group(group);
+    for (name, builder) in configs {
+        group.bench_function(*name, |b| {
+            b.iter_custom(|iters| {
+                runtime.block_on(async {
+                    let listener = builder().bind(localhost()).await.unwrap();
+                    one_peer(&listener, iters).await
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+fn pool_vs_allocator(c: &mut Criterion) {
+    bench_one_peer(
+        c,
+        "receive_buffers",
+        &[
+            ("allocator", UdpListener::builder),
+            ("pool", || UdpListener::builder().buffer_pool(16)),
+        ],
+    );
+}
+
+/// Keeps the worker threads of the runtime it is spawned on busy, yielding
+/// every `BUSY` like a CPU-heavy application task would.
+async fn busy_application() {
+    const BUSY: Duration = Duration::from_micros(500);
+    loop {
+        let start = Instant::now();
+        while start.elapsed() < BUSY {
+            std::hint::spin_loop();
+        }
+        tokio::task::yield_now().await;
+    }
+}
+
+fn peer_hashers(c: &mut Criterion) {
+    let runtime = runtime();
+    let configs: [Config; 2] = [
+        ("sip_hash", || {
+            UdpListener::builder().peer_hasher(PeerHasher::SipHash)
+        }),
+        ("fx", || UdpListener::builder().peer_hasher(PeerHasher::Fx)),
+    ];
+    let mut group = c.benchmark_group("peer_hasher");
+    for (name, builder) in configs {
+        group.bench_function(name, |b| {
+            b.iter_custom(|iters| {
+                runtime.block_on(async {
+                    let listener = builder().bind(localhost()).await.unwrap();
+                    many_peers(&listener, iters).await
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+fn dedicated_thread(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build()
+        .unwrap();
+    let configs: [Config; 2] = [
+        ("spawned", UdpListener::builder),
+        ("dedicated_thread", || {
+            UdpListener::builder().dedicated_thread(true)
+        }),
+    ];
+    let mut group = c.benchmark_group("single_worker_under_load");
+    for (name, builder) in configs {
+        group.bench_function(name, |b| {
+            b.iter_custom(|iters| {
+                runtime.block_on(async {
+                    let busy = tokio::spawn(busy_application());
+                    let listener = builder().bind(localhost()).await.unwrap();
+                    let elapsed = one_peer(&listener, iters).await;
+                    busy.abort();
+                    elapsed
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+/// How many new peers reach the listener at once in the burst benchmark.
+const BURST: usize = 1024;
+
+/// Has each of `clients` send a first datagram to a fresh listener built by
+/// `builder`, a window at a time, and returns how long it took to accept
+/// them all.
+async fn new_peers(builder: fn() -> UdpListenerBuilder, clients: &[UdpSocket]) -> Duration {
+    let listener = builder().bind(localhost()).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut streams = Vec::with_capacity(clients.len());
+    let start = Instant::now();
+    for window in clients.chunks(WINDOW as usize) {
+        for client in window {
+            client.send_to(b"hello", addr).await.unwrap();
+        }
+        for _ in window {
+            streams.push(listener.accept().await.unwrap().0);
+        }
+    }
+    start.elapsed()
+}
+
+fn expected_peers(c: &mut Criterion) {
+    let runtime = runtime();
+    let clients: Vec<_> = runtime.block_on(async {
+        let mut clients = Vec::with_capacity(BURST);
+        for _ in 0..BURST {
+            clients.push(UdpSocket::bind(localhost()).await.unwrap());
+        }
+        clients
+    });
+    let configs: [Config; 2] = [
+        ("growing", UdpListener::builder),
+        ("pre_sized", || UdpListener::builder().expected_peers(BURST)),
+    ];
+    let mut group = c.benchmark_group("burst_of_new_peers");
+    group.throughput(Throughput::Elements(BURST as u64));
+    for (name, builder) in configs {
+        group.bench_function(name, |b| {
+            b.iter_custom(|iters| {
+                runtime.block_on(async {
+                    let mut elapsed = Duration::ZERO;
+                    for _ in 0..iters {
+                        elapsed += new_peers(builder, &clients).await;
+                    }
+                    elapsed
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Demux throughput with one to four workers, on a runtime with a thread for
+/// each. SO_REUSEPORT is Linux only.
+#[cfg(target_os = "linux")]
+fn demux_workers(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(4)
+        .enable_all()
+        .build()
+        .unwrap();
+    let mut group = c.benchmark_group("demux_workers");
+    for workers in [1, 2, 4] {
+        group.bench_function(format!("workers_{}", workers), |b| {
+            b.iter_custom(|iters| {
+                runtime.block_on(async {
+                    let listener = UdpListener::builder()
+                        .demux_workers(workers)
+                        .bind(localhost())
+                        .await
+                        .unwrap();
+                    many_peers(&listener, iters).await
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+#[cfg(not(target_os = "linux"))]
+fn demux_workers(_: &mut Criterion) {}
+
+criterion_group!(
+    benches,
+    pool_vs_allocator,
+    peer_hashers,
+    dedicated_thread,
+    expected_peers,
+    demux_workers
+);
+criterion_main!(benches);
@@ -43,10 +43,10 @@ async fn main() -> Result<(), Box<dyn Error>> {
             loop {
                 let duration = Duration::from_millis(UDP_TIMEOUT);
                 let n = match timeout(duration, stream.read(&mut buf)).await.unwrap() {
-                    Ok(len) => len,
-                    Err(_) => {
+                    Ok(0) | Err(_) => {
                         return;
                     }
+                    Ok(len) => len,
                 };
 
                 stream.write_all(&buf[0..n]).await.unwrap();
@@ -22,10 +22,12 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 let duration = Duration::from_millis(UDP_TIMEOUT);
                 loop {
                     let n = timeout(duration, stream.read(&mut buf)).await??;
+                    if n == 0 {
+                        break;
+                    }
                     stream.write_all(&buf[0..n]).await?;
                     log::trace!("{:?} echoed {:?} for {} bytes", id, stream.peer_addr(), n);
                 }
-                #[allow(unreachable_code)]
                 Ok::<(), std::io::Error>(())
             };
             if let Err(e) = block.await {
@@ -0,0 +1,98 @@
+use std::{
+    fmt, io,
+    io::{Read, Write},
+    net::SocketAddr,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    runtime::Handle,
+};
+
+use crate::UdpStream;
+
+/// A [`UdpStream`] driven from synchronous code, implementing
+/// [`std::io::Read`] and [`std::io::Write`].
+///
+/// Every call blocks the current thread on the runtime of `handle` until the
+/// underlying operation completes, with the semantics of the `AsyncRead` and
+/// `AsyncWrite` implementations of [`UdpStream`]: a read returns one datagram,
+/// or the part of it that fits, and a write sends one datagram.
+///
+/// The runtime must keep running while the wrapper is used: a multi-thread
+/// runtime, or a current-thread one driven by another thread, since the
+/// stream relies on tasks and I/O driven by it. Like [`Handle::block_on`], the
+/// methods panic when called from within an asynchronous context.
+///
+/// ```no_run
+/// use std::io::{Read, Write};
+/// use udp_stream::BlockingUdpStream;
+///
+/// let runtime = tokio::runtime::Runtime::new()?;
+/// let mut stream =
+///     BlockingUdpStream::connect("127.0.0.1:8080".parse().unwrap(), runtime.handle().clone())?;
+/// stream.write_all(b"ping")?;
+/// let mut buf = [0u8; 1500];
+/// let len = stream.read(&mut buf)?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub struct BlockingUdpStream {
+    stream: UdpStream,
+    handle: Handle,
+}
+
+impl BlockingUdpStream {
+    /// Wraps `stream`, driving it on the runtime of `handle`.
+    ///
+    /// The stream must have been created on that runtime.
+    pub fn new(stream: UdpStream, handle: Handle) -> Self {
+        Self { stream, handle }
+    }
+
+    /// Connects a new stream to `addr` on the runtime of `handle`.
+    ///
+    /// See [`UdpStream::connect`].
+    pub fn connect(addr: SocketAddr, handle: Handle) -> io::Result<Self> {
+        let stream = handle.block_on(UdpStream::connect(addr))?;
+        Ok(Self::new(stream, handle))
+    }
+
+    /// Returns a reference to the underlying stream.
+    pub fn get_ref(&self) -> &UdpStream {
+        &self.stream
+    }
+
+    /// Returns a mutable reference to the underlying stream.
+    pub fn get_mut(&mut self) -> &mut UdpStream {
+        &mut self.stream
+    }
+
+    /// Returns the underlying stream.
+    pub fn into_inner(self) -> UdpStream {
+        self.stream
+    }
+}
+
+impl fmt::Debug for BlockingUdpStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlockingUdpStream")
+            .field("stream", &self.stream)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Read for BlockingUdpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.handle.block_on(self.stream.read(buf))
+    }
+}
+
+impl Write for BlockingUdpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.handle.block_on(self.stream.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.handle.block_on(self.stream.flush())
+    }
+}
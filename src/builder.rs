@@ -0,0 +1,910 @@
+use std::{
+    fmt, io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use bytes::Bytes;
+use tokio::{net::UdpSocket, runtime::Handle};
+
+use crate::{
+    codec::{DatagramCodec, SharedCodec},
+    connect_peer,
+    dedup::{DatagramHasher, DedupConfig},
+    family_unavailable,
+    sys::{self, MetaOptions},
+    tap::DatagramTap,
+    unspecified_addr, ListenerStats, PeerHasher, UdpListener, UdpStream, CHANNEL_LEN,
+};
+
+/// Decides whether a new peer gets a stream, see
+/// [`UdpListenerBuilder::admit`].
+pub(crate) type Admit = Arc<dyn Fn(SocketAddr, &ListenerStats) -> bool + Send + Sync>;
+
+/// How the queues of streams grow, see
+/// [`UdpListenerBuilder::channel_growth`].
+#[derive(Clone, Copy)]
+pub(crate) struct ChannelGrowth {
+    /// The capacity new queues start with.
+    pub(crate) initial: usize,
+    /// How many datagrams a peer must have sent before its queue grows.
+    pub(crate) sustained: u64,
+}
+
+/// Extracts the connection id of a datagram, see
+/// [`UdpListenerBuilder::connection_id`].
+pub(crate) type ConnectionId = Arc<dyn Fn(&[u8]) -> Option<u64> + Send + Sync>;
+
+/// Options applied to a listener and the streams it accepts.
+#[derive(Clone)]
+pub(crate) struct ListenerConfig {
+    pub(crate) tap: Option<Arc<dyn DatagramTap>>,
+    pub(crate) buffer_pool: Option<usize>,
+    pub(crate) channel_capacity: usize,
+    pub(crate) channel_growth: Option<ChannelGrowth>,
+    pub(crate) channel_high_watermark: Option<f64>,
+    pub(crate) accept_backlog: usize,
+    pub(crate) lazy_accept: bool,
+    pub(crate) timestamps: bool,
+    pub(crate) pktinfo: bool,
+    pub(crate) kernel_drops: bool,
+    pub(crate) ecn: bool,
+    pub(crate) max_buffered_bytes: Option<usize>,
+    pub(crate) max_peers_per_ip: Option<usize>,
+    pub(crate) max_datagram_size: Option<usize>,
+    pub(crate) delivery_interval: Option<Duration>,
+    pub(crate) max_session_duration: Option<Duration>,
+    pub(crate) close_grace: Option<Duration>,
+    pub(crate) canonical_addrs: bool,
+    pub(crate) zero_length: ZeroLengthPolicy,
+    pub(crate) queue_full: QueueFullPolicy,
+    pub(crate) dedup: Option<DedupConfig>,
+    /// Kept apart from `dedup` so that it is applied whichever of
+    /// `dedup_window` and `dedup_hasher` is called first.
+    pub(crate) dedup_hasher: Option<DatagramHasher>,
+    pub(crate) peer_hasher: PeerHasher,
+    pub(crate) expected_peers: usize,
+    pub(crate) admit: Option<Admit>,
+    pub(crate) connection_id: Option<ConnectionId>,
+    pub(crate) shutdown_payload: Option<Bytes>,
+    pub(crate) codec: Option<SharedCodec>,
+    pub(crate) stateless: bool,
+    pub(crate) dedicated_thread: bool,
+    pub(crate) demux_workers: usize,
+}
+
+impl ListenerConfig {
+    pub(crate) fn meta_options(&self) -> MetaOptions {
+        MetaOptions {
+            timestamps: self.timestamps,
+            pktinfo: self.pktinfo,
+            kernel_drops: self.kernel_drops,
+            ecn: self.ecn,
+        }
+    }
+}
+
+impl Default for ListenerConfig {
+    fn default() -> Self {
+        Self {
+            tap: None,
+            buffer_pool: None,
+            channel_capacity: CHANNEL_LEN,
+            channel_growth: None,
+            channel_high_watermark: None,
+            accept_backlog: CHANNEL_LEN,
+            lazy_accept: false,
+            timestamps: false,
+            pktinfo: false,
+            kernel_drops: false,
+            ecn: false,
+            max_buffered_bytes: None,
+            max_peers_per_ip: None,
+            max_datagram_size: None,
+            delivery_interval: None,
+            max_session_duration: None,
+            close_grace: None,
+            canonical_addrs: false,
+            zero_length: ZeroLengthPolicy::Deliver,
+            queue_full: QueueFullPolicy::Drop,
+            dedup: None,
+            dedup_hasher: None,
+            peer_hasher: PeerHasher::default(),
+            expected_peers: 0,
+            admit: None,
+            connection_id: None,
+            shutdown_payload: None,
+            codec: None,
+            stateless: false,
+            dedicated_thread: false,
+            demux_workers: 1,
+        }
+    }
+}
+
+/// What a listener does with zero-length datagrams, which protocols commonly
+/// use as keepalives.
+#[derive(Clone)]
+pub enum ZeroLengthPolicy {
+    /// Queue them like any other datagram, which is the default.
+    /// [`UdpStream::recv`](crate::UdpStream::recv) returns them as empty
+    /// payloads while `AsyncRead` skips them, as they would read as EOF.
+    Deliver,
+    /// Count them as activity of the peer, then discard them.
+    Discard,
+    /// Count them as activity of the peer and pass the peer address to the
+    /// callback instead of queueing them. For a session that
+    /// [moved](UdpListenerBuilder::connection_id), that is the address the
+    /// session started from.
+    Keepalive(Arc<dyn Fn(SocketAddr) + Send + Sync>),
+}
+
+impl fmt::Debug for ZeroLengthPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Deliver => f.write_str("Deliver"),
+            Self::Discard => f.write_str("Discard"),
+            Self::Keepalive(_) => f.write_str("Keepalive(..)"),
+        }
+    }
+}
+
+/// What a listener does with a datagram whose stream queue is full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum QueueFullPolicy {
+    /// Drop the datagram and count it in
+    /// [`ListenerStats::dropped_queue_full`](crate::ListenerStats::dropped_queue_full),
+    /// which is the default. A stream that is not read fast enough loses
+    /// datagrams, but never delays the other peers.
+    #[default]
+    Drop,
+    /// Wait for room in the queue, as earlier releases did. No datagram
+    /// is lost, but the receive loop stalls until the stream is read, delaying
+    /// every other peer it serves.
+    Wait,
+}
+
+/// A builder for configuring a [`UdpListener`] before binding it.
+///
+/// # Time
+///
+/// Every duration configured here, such as the
+/// [maximum session duration](Self::max_session_duration) or the
+/// [close grace period](Self::close_grace), is measured with tokio's clock.
+/// Tests can therefore pause it with `tokio::time::pause`, available with
+/// tokio's `test-util` feature, and step past a timeout with
+/// `tokio::time::advance` instead of sleeping.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::{error::Error, net::SocketAddr, str::FromStr, sync::Arc};
+/// use udp_stream::{MemoryTap, UdpListener};
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), Box<dyn Error>> {
+///     let tap = Arc::new(MemoryTap::new());
+///     let listener = UdpListener::builder()
+///         .tap(tap.clone())
+///         .bind(SocketAddr::from_str("127.0.0.1:8080")?)
+///         .await?;
+///     let (_stream, _) = listener.accept().await?;
+///     println!("{:?}", tap.records());
+///     Ok(())
+/// }
+/// ```
+#[derive(Default)]
+pub struct UdpListenerBuilder {
+    config: ListenerConfig,
+}
+
+impl UdpListenerBuilder {
+    /// Creates a builder with the default configuration.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Installs a tap observing every datagram the listener receives.
+    pub fn tap(mut self, tap: Arc<dyn DatagramTap>) -> Self {
+        self.config.tap = Some(tap);
+        self
+    }
+
+    /// Receives into a pool of `slabs` recycled buffers instead of letting the
+    /// receive buffer grow on demand.
+    ///
+    /// Each slab holds several datagrams and is reused once all of them have
+    /// been dropped, which reduces allocator pressure at high packet rates.
+    /// Pool usage is reported by [`UdpListener::pool_stats`].
+    pub fn buffer_pool(mut self, slabs: usize) -> Self {
+        self.config.buffer_pool = Some(slabs);
+        self
+    }
+
+    /// Sets how many received datagrams can be queued for each peer. Defaults
+    /// to 100.
+    ///
+    /// Datagrams arriving while the queue of their stream is full are dropped
+    /// and counted in [`ListenerStats`](crate::ListenerStats), so a peer whose
+    /// stream is not read fast enough never delays the other peers. Use
+    /// [`queue_full`](Self::queue_full) to wait for room instead.
+    ///
+    /// The queue only allocates memory for datagrams actually waiting in it, in
+    /// blocks of a few dozen entries, so peers sending little stay cheap even
+    /// with a large capacity; the capacity bounds what a busy peer can hold.
+    /// Use [`channel_growth`](Self::channel_growth) to bound what every other
+    /// peer can hold more tightly.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn channel_capacity(mut self, capacity: usize) -> Self {
+        assert!(capacity > 0, "channel capacity must be greater than zero");
+        self.config.channel_capacity = capacity;
+        self
+    }
+
+    /// Sets what happens to datagrams arriving while the queue of their stream
+    /// is full, see [`QueueFullPolicy`]. Defaults to dropping them.
+    ///
+    /// With [`QueueFullPolicy::Wait`], a stream that is never read and never
+    /// dropped stalls the receive loop for good, so every stream handed out
+    /// must be read.
+    pub fn queue_full(mut self, policy: QueueFullPolicy) -> Self {
+        self.config.queue_full = policy;
+        self
+    }
+
+    /// Starts the queue of every stream at `initial` datagrams, and only lets
+    /// it grow for peers showing sustained traffic.
+    ///
+    /// Once a peer has sent at least `sustained` datagrams, a datagram finding
+    /// its queue full moves the stream to a queue twice as large, up to the
+    /// [channel capacity](Self::channel_capacity), instead of being dropped.
+    /// The stream reads what was left in the smaller queue first, so no
+    /// datagram is lost or reordered by the move. A peer sending short bursts
+    /// can thus hold at most `initial` datagrams, while a busy one still gets
+    /// the full capacity.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `initial` is zero.
+    pub fn channel_growth(mut self, initial: usize, sustained: u64) -> Self {
+        assert!(
+            initial > 0,
+            "initial channel capacity must be greater than zero"
+        );
+        self.config.channel_growth = Some(ChannelGrowth { initial, sustained });
+        self
+    }
+
+    /// Warns when the queue of a stream fills up past `fraction` of its
+    /// [capacity](Self::channel_capacity), such as `0.8`, to spot streams
+    /// falling behind before their datagrams are dropped.
+    ///
+    /// The queue length is checked as datagrams are queued. Crossing the
+    /// watermark logs a warning with the peer address and is counted in
+    /// [`ListenerStats::high_watermark_crossings`](crate::ListenerStats::high_watermark_crossings),
+    /// once until a datagram is queued while the queue is below the
+    /// watermark again.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `fraction` is not greater than zero and at most one.
+    pub fn channel_high_watermark(mut self, fraction: f64) -> Self {
+        assert!(
+            fraction > 0.0 && fraction <= 1.0,
+            "channel high watermark must be in (0, 1]"
+        );
+        self.config.channel_high_watermark = Some(fraction);
+        self
+    }
+
+    /// Sets how many new connections can wait to be accepted. Defaults to
+    /// 100.
+    ///
+    /// New peers arriving while the backlog is full are dropped and counted in
+    /// [`ListenerStats`](crate::ListenerStats); the backlog can be observed with
+    /// [`UdpListener::backlog_len`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `backlog` is zero.
+    pub fn accept_backlog(mut self, backlog: usize) -> Self {
+        assert!(backlog > 0, "accept backlog must be greater than zero");
+        self.config.accept_backlog = backlog;
+        self
+    }
+
+    /// Only queues datagrams for a stream once it is accepted.
+    ///
+    /// By default, datagrams from a new peer are queued on its stream while
+    /// it waits to be accepted, up to the
+    /// [channel capacity](Self::channel_capacity), so peers that are never
+    /// accepted can hold that much memory each. With lazy accept, only the
+    /// first datagram of a peer is kept with its pending stream: later ones
+    /// are dropped until [`accept`](crate::UdpListener::accept) returns the
+    /// stream, and counted in
+    /// [`ListenerStats::dropped_unaccepted`](crate::ListenerStats::dropped_unaccepted).
+    /// Memory for connections waiting to be accepted is then bounded by the
+    /// [accept backlog](Self::accept_backlog) times one datagram.
+    pub fn lazy_accept(mut self, enabled: bool) -> Self {
+        self.config.lazy_accept = enabled;
+        self
+    }
+
+    /// Caps the total number of received bytes queued across all peers.
+    ///
+    /// Once the cap is reached, new datagrams are dropped until the streams
+    /// read enough of their queued data. Drops are counted in
+    /// [`ListenerStats::dropped_buffer_limit`](crate::ListenerStats::dropped_buffer_limit).
+    pub fn max_buffered_bytes(mut self, limit: usize) -> Self {
+        self.config.max_buffered_bytes = Some(limit);
+        self
+    }
+
+    /// Caps the number of peers tracked for a single IP address, whatever
+    /// their ports.
+    ///
+    /// This keeps one host, or spoofed datagrams claiming its address, from
+    /// filling the peers table with many source ports. Once an address has
+    /// `limit` peers, datagrams from its other ports are dropped without
+    /// creating a stream and counted in
+    /// [`ListenerStats::dropped_peers_per_ip`](crate::ListenerStats::dropped_peers_per_ip),
+    /// until one of its peers is untracked. Other addresses are not affected.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is zero.
+    pub fn max_peers_per_ip(mut self, limit: usize) -> Self {
+        assert!(limit > 0, "peers per IP limit must be greater than zero");
+        self.config.max_peers_per_ip = Some(limit);
+        self
+    }
+
+    /// Drops received datagrams larger than `size` bytes before they are
+    /// queued, so streams never see payloads above an application limit.
+    ///
+    /// Drops are counted in
+    /// [`ListenerStats::dropped_oversize`](crate::ListenerStats::dropped_oversize).
+    pub fn max_datagram_size(mut self, size: usize) -> Self {
+        self.config.max_datagram_size = Some(size);
+        self
+    }
+
+    /// Delivers datagrams to the streams in batches, once every `period`,
+    /// instead of as they arrive.
+    ///
+    /// Datagrams received from a known peer are held back and handed to its
+    /// stream together on the next tick, trading up to `period` of latency for
+    /// fewer wakeups of the reading tasks. The first datagram of a new peer is
+    /// delivered right away along with its stream.
+    pub fn delivery_interval(mut self, period: Duration) -> Self {
+        self.config.delivery_interval = Some(period);
+        self
+    }
+
+    /// Ends every peer session once it has lasted `duration`, however active
+    /// it is, for protocols that must handshake again periodically.
+    ///
+    /// Sessions are checked every quarter of `duration`, so one may outlive it
+    /// by that much. An expired stream fails to read with `TimedOut` once its
+    /// queued datagrams are read, and the next datagram from the peer is
+    /// announced as a new connection.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `duration` is zero.
+    pub fn max_session_duration(mut self, duration: Duration) -> Self {
+        assert!(!duration.is_zero(), "max session duration must be positive");
+        self.config.max_session_duration = Some(duration);
+        self
+    }
+
+    /// Keeps tracking a peer for `grace` after its stream ended the session,
+    /// such as on a send error telling the peer is unreachable, instead of
+    /// closing the stream right away.
+    ///
+    /// A datagram from the peer within the grace period revives the session:
+    /// it is delivered to the existing stream, which never notices the
+    /// interruption. Otherwise the stream reads EOF once the period is over,
+    /// checked every quarter of `grace`. The tradeoff is that the peer's entry,
+    /// queue and buffered datagrams are retained that much longer, and a
+    /// returning peer is not announced as a new connection. Dropping a stream
+    /// always stops tracking its peer at once, and sessions exceeding the
+    /// [maximum duration](Self::max_session_duration) are not kept.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `grace` is zero.
+    pub fn close_grace(mut self, grace: Duration) -> Self {
+        assert!(!grace.is_zero(), "close grace must be positive");
+        self.config.close_grace = Some(grace);
+        self
+    }
+
+    /// Drops datagrams identical to one of the last `window` datagrams
+    /// received from the same peer, such as client retransmissions.
+    ///
+    /// Datagrams are compared by hash, using SipHash unless another function is
+    /// set with [`dedup_hasher`](Self::dedup_hasher). Duplicates are counted
+    /// in [`ListenerStats::duplicates_dropped`](crate::ListenerStats::duplicates_dropped).
+    /// A `window` of zero disables duplicate detection.
+    pub fn dedup_window(mut self, window: usize) -> Self {
+        self.config.dedup = (window > 0).then(|| match &self.config.dedup_hasher {
+            Some(hasher) => DedupConfig {
+                window,
+                hasher: hasher.clone(),
+            },
+            None => DedupConfig::new(window),
+        });
+        self
+    }
+
+    /// Sets the hash function used for duplicate detection, before or after
+    /// [`dedup_window`](Self::dedup_window).
+    ///
+    /// Has no effect unless a non-zero window is set.
+    pub fn dedup_hasher<F>(mut self, hasher: F) -> Self
+    where
+        F: Fn(&[u8]) -> u64 + Send + Sync + 'static,
+    {
+        let hasher: DatagramHasher = Arc::new(hasher);
+        if let Some(dedup) = &mut self.config.dedup {
+            dedup.hasher = hasher.clone();
+        }
+        self.config.dedup_hasher = Some(hasher);
+        self
+    }
+
+    /// Encodes the datagrams written to the accepted streams and decodes
+    /// those received with `codec`.
+    ///
+    /// Decoding happens in the receive loop, after the
+    /// [maximum datagram size](Self::max_datagram_size) is checked against the
+    /// encoded datagram. Zero-length datagrams are passed through as they are.
+    /// See [`DatagramCodec`].
+    pub fn codec(mut self, codec: Arc<dyn DatagramCodec>) -> Self {
+        self.config.codec = Some(SharedCodec(codec));
+        self
+    }
+
+    /// Sends `payload` to the peer as a last datagram when an accepted stream
+    /// is shut down or dropped.
+    ///
+    /// See [`UdpStreamBuilder::shutdown_payload`].
+    pub fn shutdown_payload(mut self, payload: impl Into<Bytes>) -> Self {
+        self.config.shutdown_payload = Some(payload.into());
+        self
+    }
+
+    /// Calls `admit` with the address of every new peer and the current
+    /// counters of the listener, and only creates a stream for the peer if it
+    /// returns `true`.
+    ///
+    /// This allows custom overload protection, such as refusing new peers
+    /// above some number of peers or buffered bytes. A refused peer's datagram
+    /// is dropped and counted in
+    /// [`ListenerStats::dropped_admission`](crate::ListenerStats::dropped_admission);
+    /// its next datagram is submitted to `admit` again. Known peers are not
+    /// affected. [`ListenerStats::buffered_bytes`](crate::ListenerStats::buffered_bytes)
+    /// includes the datagram of the new peer.
+    pub fn admit<F>(mut self, admit: F) -> Self
+    where
+        F: Fn(SocketAddr, &ListenerStats) -> bool + Send + Sync + 'static,
+    {
+        self.config.admit = Some(Arc::new(admit));
+        self
+    }
+
+    /// Identifies sessions by a connection id carried in their datagrams as
+    /// well as by address, so that a peer whose source address changes, such
+    /// as after a NAT rebinding, keeps its session and receives the replies.
+    ///
+    /// `connection_id` is called with every datagram, after any
+    /// [codec](Self::codec), and returns the id it carries, if any. The first
+    /// datagram of a session registers its id. A datagram from an unknown
+    /// address whose id belongs to a tracked session is delivered to that
+    /// session, and the session's stream sends its writes to that address
+    /// from then on. [`UdpStream::peer_addr`](crate::UdpStream::peer_addr)
+    /// keeps reporting the address the session started from. Zero-length
+    /// datagrams not delivered as messages, see
+    /// [`zero_length`](Self::zero_length), count for the session that moved to
+    /// their address.
+    ///
+    /// This is off by default because anyone able to send a datagram with a
+    /// session's id can inject data into the session and redirect its
+    /// replies. Ids must therefore be hard to guess and datagrams
+    /// authenticated by the application, as with DTLS connection ids.
+    pub fn connection_id<F>(mut self, connection_id: F) -> Self
+    where
+        F: Fn(&[u8]) -> Option<u64> + Send + Sync + 'static,
+    {
+        self.config.connection_id = Some(Arc::new(connection_id));
+        self
+    }
+
+    /// Sets the hasher of the table the receive loop looks up the peer of
+    /// every datagram in.
+    ///
+    /// The default is the randomly seeded SipHash of `HashMap`, which resists
+    /// peers crafting colliding addresses. [`PeerHasher::Fx`] is faster and
+    /// can be used when every peer is trusted.
+    pub fn peer_hasher(mut self, hasher: PeerHasher) -> Self {
+        self.config.peer_hasher = hasher;
+        self
+    }
+
+    /// Sizes the table of tracked peers for `peers` entries up front, so a
+    /// burst of new peers at startup does not grow it step by step.
+    ///
+    /// The table still grows past `peers` when needed. Its memory is
+    /// allocated when the listener is bound, whether the peers come or not.
+    pub fn expected_peers(mut self, peers: usize) -> Self {
+        self.config.expected_peers = peers;
+        self
+    }
+
+    /// Reports IPv4 peers of a dual-stack listener with their IPv4 address
+    /// instead of the IPv4-mapped IPv6 form (`::ffff:a.b.c.d`).
+    ///
+    /// The canonical address keys the tracked peers and is returned by
+    /// [`UdpStream::peer_addr`](crate::UdpStream::peer_addr), while writes keep
+    /// using the mapped address the socket expects.
+    pub fn canonical_addrs(mut self, enabled: bool) -> Self {
+        self.config.canonical_addrs = enabled;
+        self
+    }
+
+    /// Sets how zero-length datagrams are handled, see [`ZeroLengthPolicy`].
+    ///
+    /// Unless they are delivered, zero-length datagrams from unknown peers
+    /// never announce a new stream.
+    pub fn zero_length(mut self, policy: ZeroLengthPolicy) -> Self {
+        self.config.zero_length = policy;
+        self
+    }
+
+    /// Records the kernel receive timestamp of every datagram, available
+    /// through [`UdpStream::recv_with_timestamp`](crate::UdpStream::recv_with_timestamp).
+    ///
+    /// This enables `SO_TIMESTAMPNS` and reads the timestamps from the control
+    /// messages of `recvmsg`, which is only supported on Linux. Elsewhere the
+    /// option has no effect and the time of reading is reported instead.
+    pub fn timestamps(mut self, enabled: bool) -> Self {
+        self.config.timestamps = enabled;
+        self
+    }
+
+    /// Records the ECN codepoint of every datagram, available through
+    /// [`UdpStream::recv_ecn`](crate::UdpStream::recv_ecn).
+    ///
+    /// This enables `IP_RECVTOS` (`IPV6_RECVTCLASS`) and reads the codepoints
+    /// from the control messages of `recvmsg`, which is only supported on
+    /// Linux. Elsewhere the option has no effect.
+    pub fn ecn(mut self, enabled: bool) -> Self {
+        self.config.ecn = enabled;
+        self
+    }
+
+    /// Records the local address every datagram was sent to, available
+    /// through [`UdpStream::recv_meta`](crate::UdpStream::recv_meta). This is
+    /// the address to reply from on a listener bound to an unspecified
+    /// address.
+    ///
+    /// This enables `IP_PKTINFO` (`IPV6_RECVPKTINFO`) and reads the addresses
+    /// from the control messages of `recvmsg`, which is only supported on
+    /// Linux. Elsewhere the option has no effect.
+    pub fn pktinfo(mut self, enabled: bool) -> Self {
+        self.config.pktinfo = enabled;
+        self
+    }
+
+    /// Counts the datagrams the kernel drops because a socket receive buffer
+    /// is full, in [`ListenerStats::dropped_kernel`](crate::ListenerStats::dropped_kernel).
+    /// This tells a listener that cannot keep up from the drops it makes
+    /// itself.
+    ///
+    /// This enables `SO_RXQ_OVFL` and reads the drop counter from the control
+    /// messages of `recvmsg`, which is only supported on Linux. Elsewhere the
+    /// option has no effect. The kernel reports the counter along with the
+    /// next datagram read, so drops are only counted once traffic resumes.
+    pub fn kernel_drops(mut self, enabled: bool) -> Self {
+        self.config.kernel_drops = enabled;
+        self
+    }
+
+    /// Binds the listener in stateless mode, for servers that handle every
+    /// datagram on its own, such as DNS.
+    ///
+    /// No receive loop is started and no streams are tracked: datagrams are
+    /// read with [`UdpListener::poll_recv_from`](crate::UdpListener::poll_recv_from)
+    /// and [`accept`](crate::UdpListener::accept) fails with `BrokenPipe`.
+    /// Options concerning streams have no effect in this mode.
+    pub fn stateless(mut self, enabled: bool) -> Self {
+        self.config.stateless = enabled;
+        self
+    }
+
+    /// Runs the receive loop on a dedicated OS thread with its own
+    /// single-threaded runtime instead of spawning it on the current one.
+    ///
+    /// The listening sockets are then drained even when every worker of the
+    /// application runtime is busy, which matters most on runtimes with a
+    /// single worker. The cost is one more thread per listener, and every
+    /// datagram crossing from that thread to the task reading its stream.
+    pub fn dedicated_thread(mut self, enabled: bool) -> Self {
+        self.config.dedicated_thread = enabled;
+        self
+    }
+
+    /// Splits the receive loop into `workers` tasks, for packet rates a
+    /// single task cannot keep up with.
+    ///
+    /// [`bind`](Self::bind) then binds one socket per worker to the address
+    /// with `SO_REUSEPORT`, and the kernel spreads incoming datagrams between
+    /// them by source address. A peer is therefore always served by the same
+    /// worker, which keeps its datagrams in order, while workers share the
+    /// tracked peers, the accept queue and the counters. Each worker has its
+    /// own [buffer pool](Self::buffer_pool), and
+    /// [`local_addrs`](crate::UdpListener::local_addrs) lists the address once
+    /// per socket. This is only supported on Linux; binding fails with
+    /// `Unsupported` elsewhere.
+    ///
+    /// Every constructor binding its own sockets does so, and
+    /// [`bind_multi`](Self::bind_multi) binds one socket per worker to each
+    /// address, every worker receiving on one socket of each. As
+    /// [`from_std`](Self::from_std) is given a single socket, it fails with
+    /// `InvalidInput` when several workers are set. Listeners with several
+    /// workers cannot be [rebound](crate::UdpListener::rebind).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `workers` is zero.
+    pub fn demux_workers(mut self, workers: usize) -> Self {
+        assert!(workers > 0, "demux workers must be non-zero");
+        self.config.demux_workers = workers;
+        self
+    }
+
+    /// Returns whether the listener is to be bound to one socket per
+    /// [demux worker](Self::demux_workers).
+    fn socket_per_worker(&self) -> bool {
+        self.config.demux_workers > 1 && !self.config.stateless
+    }
+
+    /// Binds the non-blocking sockets of a listener to `local_addr`: one per
+    /// demux worker, sharing the address with `SO_REUSEPORT`, or a single one.
+    fn bind_sockets(&self, local_addr: SocketAddr) -> io::Result<Vec<std::net::UdpSocket>> {
+        if self.socket_per_worker() {
+            return sys::bind_reuseport(local_addr, self.config.demux_workers);
+        }
+        let socket = std::net::UdpSocket::bind(local_addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(vec![socket])
+    }
+
+    /// Creates a `UdpListener` bound to the specified address.
+    pub async fn bind(self, local_addr: SocketAddr) -> io::Result<UdpListener> {
+        let udp_sockets = self.bind_sockets(local_addr)?;
+        self.listen(udp_sockets, &Handle::current())
+    }
+
+    /// Starts a listener on `udp_sockets`, registering them with `handle`.
+    fn listen(
+        self,
+        udp_sockets: Vec<std::net::UdpSocket>,
+        handle: &Handle,
+    ) -> io::Result<UdpListener> {
+        let udp_sockets = {
+            let _guard = handle.enter();
+            udp_sockets
+                .into_iter()
+                .map(UdpSocket::from_std)
+                .collect::<io::Result<Vec<_>>>()?
+        };
+        UdpListener::from_sockets(udp_sockets, handle, self.config)
+    }
+
+    /// Creates a `UdpListener` bound to a port of `ip` picked by the operating
+    /// system.
+    ///
+    /// See [`UdpListener::bind_ephemeral`].
+    pub async fn bind_ephemeral(self, ip: IpAddr) -> io::Result<UdpListener> {
+        self.bind(SocketAddr::new(ip, 0)).await
+    }
+
+    /// Creates a `UdpListener` bound to the specified address, retrying with
+    /// exponential backoff while the address is in use or not available.
+    ///
+    /// See [`UdpListener::bind_retry`].
+    pub async fn bind_retry(
+        self,
+        local_addr: SocketAddr,
+        attempts: usize,
+        backoff: Duration,
+    ) -> io::Result<UdpListener> {
+        let mut delay = backoff;
+        let mut attempt = 1;
+        let udp_sockets = loop {
+            match self.bind_sockets(local_addr) {
+                Ok(udp_sockets) => break udp_sockets,
+                Err(err)
+                    if attempt < attempts
+                        && matches!(
+                            err.kind(),
+                            io::ErrorKind::AddrInUse | io::ErrorKind::AddrNotAvailable
+                        ) =>
+                {
+                    log::debug!(
+                        "bind {} failed, retrying in {:?}: {}",
+                        local_addr,
+                        delay,
+                        err
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay = delay.saturating_mul(2);
+                    attempt += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        };
+        self.listen(udp_sockets, &Handle::current())
+    }
+
+    /// Creates a `UdpListener` bound to every address in `addrs`.
+    ///
+    /// See [`UdpListener::bind_multi`].
+    pub async fn bind_multi(self, addrs: &[SocketAddr]) -> io::Result<UdpListener> {
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no addresses to bind",
+            ));
+        }
+        // The sockets of each address are in worker order, so every worker
+        // receives on one socket of each.
+        let mut udp_sockets = Vec::with_capacity(addrs.len() * self.config.demux_workers);
+        for addr in addrs {
+            udp_sockets.extend(self.bind_sockets(*addr)?);
+        }
+        self.listen(udp_sockets, &Handle::current())
+    }
+
+    /// Creates a `UdpListener` from an already bound standard library socket.
+    ///
+    /// See [`UdpListener::from_std`].
+    pub fn from_std(self, socket: std::net::UdpSocket) -> io::Result<UdpListener> {
+        if self.socket_per_worker() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "several demux workers need a socket each, not a single given one",
+            ));
+        }
+        socket.set_nonblocking(true)?;
+        self.listen(vec![socket], &Handle::current())
+    }
+
+    /// Creates a `UdpListener` whose background task is spawned on `handle`.
+    ///
+    /// See [`UdpListener::bind_on`].
+    pub fn bind_on(self, handle: &Handle, local_addr: SocketAddr) -> io::Result<UdpListener> {
+        let udp_sockets = self.bind_sockets(local_addr)?;
+        self.listen(udp_sockets, handle)
+    }
+}
+
+/// Options applied to a client stream.
+#[derive(Clone)]
+pub(crate) struct StreamConfig {
+    pub(crate) prefetch_depth: usize,
+    pub(crate) local_port: u16,
+    pub(crate) shutdown_payload: Option<Bytes>,
+    pub(crate) codec: Option<SharedCodec>,
+}
+
+impl Default for StreamConfig {
+    fn default() -> Self {
+        Self {
+            prefetch_depth: 1,
+            local_port: 0,
+            shutdown_payload: None,
+            codec: None,
+        }
+    }
+}
+
+/// A builder for configuring a client [`UdpStream`] before connecting it.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::{error::Error, net::SocketAddr, str::FromStr};
+/// use udp_stream::UdpStream;
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), Box<dyn Error>> {
+///     let stream = UdpStream::builder()
+///         .prefetch_depth(16)
+///         .connect(SocketAddr::from_str("127.0.0.1:8080")?)
+///         .await?;
+///     println!("{:?}", stream.local_addr()?);
+///     Ok(())
+/// }
+/// ```
+#[derive(Default)]
+pub struct UdpStreamBuilder {
+    config: StreamConfig,
+}
+
+impl UdpStreamBuilder {
+    /// Creates a builder with the default configuration.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets how many datagrams the receive task reads from the socket in one
+    /// go before handing them to the stream. Defaults to 1.
+    ///
+    /// Once a datagram arrives, the task keeps reading those already waiting
+    /// on the socket, up to `depth`, so bursts on high latency links are moved
+    /// into the stream's queue at once instead of one wakeup at a time. The
+    /// queue is grown to hold at least `depth` datagrams.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `depth` is zero.
+    pub fn prefetch_depth(mut self, depth: usize) -> Self {
+        assert!(depth > 0, "prefetch depth must be greater than zero");
+        self.config.prefetch_depth = depth;
+        self
+    }
+
+    /// Binds the client socket to `port` instead of an ephemeral port, for
+    /// NAT or firewall setups expecting a fixed source port.
+    ///
+    /// Connecting fails with `AddrInUse` if the port is taken.
+    pub fn local_port(mut self, port: u16) -> Self {
+        self.config.local_port = port;
+        self
+    }
+
+    /// Encodes the datagrams written to the stream and decodes those received
+    /// with `codec`. Zero-length datagrams are passed through as they are.
+    ///
+    /// See [`DatagramCodec`].
+    pub fn codec(mut self, codec: Arc<dyn DatagramCodec>) -> Self {
+        self.config.codec = Some(SharedCodec(codec));
+        self
+    }
+
+    /// Sends `payload` to the peer as a last datagram when the stream is shut
+    /// down through `AsyncWrite` or dropped, for protocols with an explicit
+    /// goodbye that lets the peer clean up without waiting for a timeout.
+    ///
+    /// The payload is sent at most once, without waiting for the socket to be
+    /// writable, so it may be lost like any datagram.
+    pub fn shutdown_payload(mut self, payload: impl Into<Bytes>) -> Self {
+        self.config.shutdown_payload = Some(payload.into());
+        self
+    }
+
+    /// Creates a `UdpStream` connected to the specified address.
+    ///
+    /// See [`UdpStream::connect`].
+    pub async fn connect(self, addr: SocketAddr) -> io::Result<UdpStream> {
+        let socket = UdpSocket::bind(unspecified_addr(&addr, self.config.local_port))
+            .await
+            .map_err(|err| family_unavailable(err, &addr))?;
+        connect_peer(&socket, addr).await?;
+        UdpStream::from_socket(socket, addr, self.config)
+    }
+
+    /// Creates a `UdpStream` from a socket connected to `peer_addr`.
+    ///
+    /// See [`UdpStream::from_tokio`].
+    pub fn from_tokio(self, socket: UdpSocket, peer_addr: SocketAddr) -> io::Result<UdpStream> {
+        UdpStream::from_socket(socket, peer_addr, self.config)
+    }
+}
@@ -0,0 +1,207 @@
+use std::{fmt, io, ops::Deref, sync::Arc};
+
+use bytes::Bytes;
+
+/// Transforms the payload of every datagram on its way to and from the wire,
+/// for instance to compress it.
+///
+/// A codec is set with [`UdpListenerBuilder::codec`](crate::UdpListenerBuilder::codec)
+/// or [`UdpStreamBuilder::codec`](crate::UdpStreamBuilder::codec) and applied
+/// transparently: writes are encoded before being sent, and received
+/// datagrams are decoded before being queued on their stream. Both ends must
+/// use the same codec.
+pub trait DatagramCodec: Send + Sync {
+    /// Encodes a datagram written by the application into its wire form.
+    fn encode(&self, datagram: &[u8]) -> Bytes;
+
+    /// Decodes a datagram received from the wire.
+    ///
+    /// A datagram failing to decode is dropped, which a listener counts in
+    /// [`ListenerStats::dropped_decode`](crate::ListenerStats::dropped_decode).
+    fn decode(&self, datagram: Bytes) -> io::Result<Bytes>;
+}
+
+/// The codec of a listener or stream.
+#[derive(Clone)]
+pub(crate) struct SharedCodec(pub(crate) Arc<dyn DatagramCodec>);
+
+impl Deref for SharedCodec {
+    type Target = dyn DatagramCodec;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.0
+    }
+}
+
+impl fmt::Debug for SharedCodec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SharedCodec(..)")
+    }
+}
+
+/// Compresses datagrams with LZ4.
+///
+/// Each datagram is compressed on its own, as an LZ4 block preceded by its
+/// uncompressed length as a little-endian `u32`. Small or incompressible
+/// datagrams grow by a few bytes, so the codec pays off for payloads with
+/// redundancy, such as text.
+#[cfg(feature = "lz4")]
+#[derive(Debug, Clone)]
+pub struct Lz4Codec {
+    max_decoded_len: usize,
+}
+
+#[cfg(feature = "lz4")]
+impl Lz4Codec {
+    /// Creates a codec accepting decoded datagrams up to 1 MiB.
+    pub fn new() -> Self {
+        Self {
+            max_decoded_len: 1 << 20,
+        }
+    }
+
+    /// Sets the largest decoded datagram accepted. A datagram claiming to be
+    /// longer fails to decode, so a peer cannot make the receiver allocate
+    /// more.
+    pub fn max_decoded_len(mut self, len: usize) -> Self {
+        self.max_decoded_len = len;
+        self
+    }
+}
+
+#[cfg(feature = "lz4")]
+impl Default for Lz4Codec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "lz4")]
+impl DatagramCodec for Lz4Codec {
+    fn encode(&self, datagram: &[u8]) -> Bytes {
+        lz4_flex::block::compress_prepend_size(datagram).into()
+    }
+
+    fn decode(&self, datagram: Bytes) -> io::Result<Bytes> {
+        let (len, block) = datagram
+            .split_first_chunk::<4>()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing LZ4 length"))?;
+        let len = u32::from_le_bytes(*len) as usize;
+        if len > self.max_decoded_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "decoded datagram exceeds the maximum length",
+            ));
+        }
+        lz4_flex::block::decompress(block, len)
+            .map(Bytes::from)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
+
+/// The CRC-32 variant of a [`CrcCodec`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum CrcAlgorithm {
+    /// CRC-32 as used by Ethernet, zlib and PNG, polynomial `0x04C11DB7`.
+    #[default]
+    Crc32,
+    /// CRC-32C as used by iSCSI and SCTP, polynomial `0x1EDC6F41`, which
+    /// detects more errors in short messages.
+    Crc32c,
+}
+
+impl CrcAlgorithm {
+    fn table(self) -> &'static [u32; 256] {
+        match self {
+            CrcAlgorithm::Crc32 => &CRC32_TABLE,
+            CrcAlgorithm::Crc32c => &CRC32C_TABLE,
+        }
+    }
+
+    fn checksum(self, data: &[u8]) -> u32 {
+        let table = self.table();
+        !data.iter().fold(!0u32, |crc, &byte| {
+            table[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+        })
+    }
+}
+
+static CRC32_TABLE: [u32; 256] = crc_table(0xedb8_8320);
+static CRC32C_TABLE: [u32; 256] = crc_table(0x82f6_3b78);
+
+/// Builds the lookup table of a reflected CRC-32 polynomial.
+const fn crc_table(polynomial: u32) -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ polynomial
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// Frames datagrams with their length and a CRC-32, to detect datagrams
+/// corrupted on links whose own checksums are weak or disabled.
+///
+/// Each datagram is sent preceded by its length as a little-endian `u32` and
+/// followed by the CRC of the length and payload, also little-endian. A
+/// received datagram whose length or CRC does not match fails to decode and
+/// is dropped, which a listener counts in
+/// [`ListenerStats::dropped_decode`](crate::ListenerStats::dropped_decode).
+#[derive(Debug, Clone, Default)]
+pub struct CrcCodec {
+    algorithm: CrcAlgorithm,
+}
+
+impl CrcCodec {
+    /// Creates a codec using [`CrcAlgorithm::Crc32`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the CRC variant, which both ends must agree on.
+    pub fn algorithm(mut self, algorithm: CrcAlgorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+}
+
+impl DatagramCodec for CrcCodec {
+    fn encode(&self, datagram: &[u8]) -> Bytes {
+        let mut framed = Vec::with_capacity(datagram.len() + 8);
+        framed.extend_from_slice(&(datagram.len() as u32).to_le_bytes());
+        framed.extend_from_slice(datagram);
+        let crc = self.algorithm.checksum(&framed);
+        framed.extend_from_slice(&crc.to_le_bytes());
+        framed.into()
+    }
+
+    fn decode(&self, mut datagram: Bytes) -> io::Result<Bytes> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let (framed, crc) = datagram
+            .split_last_chunk::<4>()
+            .ok_or_else(|| invalid("missing CRC"))?;
+        if self.algorithm.checksum(framed) != u32::from_le_bytes(*crc) {
+            return Err(invalid("CRC mismatch"));
+        }
+        let (len, payload) = framed
+            .split_first_chunk::<4>()
+            .ok_or_else(|| invalid("missing length"))?;
+        if u32::from_le_bytes(*len) as usize != payload.len() {
+            return Err(invalid("length mismatch"));
+        }
+        datagram.truncate(datagram.len() - 4);
+        Ok(datagram.split_off(4))
+    }
+}
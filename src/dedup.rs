@@ -0,0 +1,57 @@
+use std::{
+    collections::VecDeque,
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::Arc,
+};
+
+/// Hashes a datagram for duplicate detection.
+pub(crate) type DatagramHasher = Arc<dyn Fn(&[u8]) -> u64 + Send + Sync>;
+
+#[derive(Clone)]
+pub(crate) struct DedupConfig {
+    pub(crate) window: usize,
+    pub(crate) hasher: DatagramHasher,
+}
+
+impl DedupConfig {
+    pub(crate) fn new(window: usize) -> Self {
+        Self {
+            window,
+            hasher: Arc::new(default_hash),
+        }
+    }
+}
+
+fn default_hash(datagram: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    datagram.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The hashes of the most recent datagrams received from a peer.
+#[derive(Debug)]
+pub(crate) struct DedupWindow {
+    hashes: VecDeque<u64>,
+    capacity: usize,
+}
+
+impl DedupWindow {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            hashes: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Records `hash`, returning `true` if it was already in the window.
+    pub(crate) fn check(&mut self, hash: u64) -> bool {
+        if self.hashes.contains(&hash) {
+            return true;
+        }
+        if self.hashes.len() == self.capacity {
+            self.hashes.pop_front();
+        }
+        self.hashes.push_back(hash);
+        false
+    }
+}
@@ -0,0 +1,156 @@
+use std::io;
+
+use bytes::{Buf, Bytes, BytesMut};
+
+use crate::UdpStream;
+
+/// The default limit on the size of a reassembled frame.
+const MAX_FRAME_LEN: usize = 1 << 20;
+
+/// How frames are delimited in the byte stream formed by consecutive
+/// datagrams.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// Each frame is preceded by its length, as a big-endian `u32` not
+    /// counting the prefix itself.
+    LengthPrefixed,
+    /// Each frame is terminated by the given byte, which is not part of the
+    /// frame.
+    Delimited(u8),
+}
+
+/// Reassembles application frames split across several datagrams of a
+/// [`UdpStream`].
+///
+/// Datagrams are concatenated in the order they are received until a whole
+/// frame is available. UDP guarantees neither ordering nor delivery, so a lost,
+/// duplicated or reordered datagram corrupts the frame it belongs to and, with
+/// [`Framing::LengthPrefixed`], possibly the following ones. Protocols using
+/// this adapter must either run over a path where that does not happen or
+/// detect it themselves, for instance with a checksum in each frame.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::{error::Error, net::SocketAddr, str::FromStr};
+/// use udp_stream::{FrameReassembler, Framing, UdpListener};
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), Box<dyn Error>> {
+///     let listener = UdpListener::bind(SocketAddr::from_str("127.0.0.1:8080")?).await?;
+///     let (stream, _) = listener.accept().await?;
+///     let mut frames = FrameReassembler::new(stream, Framing::Delimited(b'\n'));
+///     while let Some(frame) = frames.next_frame().await? {
+///         println!("{:?}", frame);
+///     }
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug)]
+pub struct FrameReassembler {
+    stream: UdpStream,
+    framing: Framing,
+    buf: BytesMut,
+    max_frame_len: usize,
+}
+
+impl FrameReassembler {
+    /// Creates a reassembler reading frames delimited by `framing` from
+    /// `stream`.
+    pub fn new(stream: UdpStream, framing: Framing) -> Self {
+        Self {
+            stream,
+            framing,
+            buf: BytesMut::new(),
+            max_frame_len: MAX_FRAME_LEN,
+        }
+    }
+
+    /// Sets the largest frame accepted, 1 MiB by default. A longer frame
+    /// fails with `InvalidData`, as does more buffered data without a
+    /// delimiter.
+    pub fn max_frame_len(mut self, len: usize) -> Self {
+        self.max_frame_len = len;
+        self
+    }
+
+    /// Returns the next complete frame, or `None` once the stream is closed
+    /// between two frames.
+    ///
+    /// A stream closed in the middle of a frame fails with `UnexpectedEof`.
+    pub async fn next_frame(&mut self) -> io::Result<Option<Bytes>> {
+        loop {
+            if let Some(frame) = self.decode()? {
+                return Ok(Some(frame));
+            }
+            match self.stream.read_buf(&mut self.buf).await {
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::BrokenPipe => {
+                    if self.buf.is_empty() {
+                        return Ok(None);
+                    }
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "stream closed in the middle of a frame",
+                    ));
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Splits the next frame off the buffer if it is complete.
+    fn decode(&mut self) -> io::Result<Option<Bytes>> {
+        match self.framing {
+            Framing::LengthPrefixed => {
+                if self.buf.len() < 4 {
+                    return Ok(None);
+                }
+                let len = u32::from_be_bytes(self.buf[..4].try_into().unwrap()) as usize;
+                if len > self.max_frame_len {
+                    return Err(frame_too_long());
+                }
+                if self.buf.len() < 4 + len {
+                    return Ok(None);
+                }
+                self.buf.advance(4);
+                Ok(Some(self.buf.split_to(len).freeze()))
+            }
+            Framing::Delimited(delimiter) => {
+                match self.buf.iter().position(|byte| *byte == delimiter) {
+                    Some(end) => {
+                        let frame = self.buf.split_to(end).freeze();
+                        self.buf.advance(1);
+                        Ok(Some(frame))
+                    }
+                    None if self.buf.len() > self.max_frame_len => Err(frame_too_long()),
+                    None => Ok(None),
+                }
+            }
+        }
+    }
+
+    /// Returns a reference to the underlying stream.
+    pub fn get_ref(&self) -> &UdpStream {
+        &self.stream
+    }
+
+    /// Returns a mutable reference to the underlying stream.
+    ///
+    /// Reading from it directly desynchronizes the reassembly.
+    pub fn get_mut(&mut self) -> &mut UdpStream {
+        &mut self.stream
+    }
+
+    /// Returns the underlying stream, discarding any partial frame.
+    pub fn into_inner(self) -> UdpStream {
+        self.stream
+    }
+}
+
+fn frame_too_long() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "frame exceeds the maximum length",
+    )
+}
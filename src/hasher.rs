@@ -0,0 +1,121 @@
+use std::{
+    collections::hash_map::{DefaultHasher, RandomState},
+    hash::{BuildHasher, Hasher},
+};
+
+/// The hasher of the table the receive loop of a listener looks up the peer
+/// of every datagram in, see
+/// [`UdpListenerBuilder::peer_hasher`](crate::UdpListenerBuilder::peer_hasher).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PeerHasher {
+    /// The randomly seeded SipHash of `HashMap`, which resists peers crafting
+    /// colliding addresses.
+    #[default]
+    SipHash,
+    /// FxHash, the hash of the Rust compiler: faster, but with collisions
+    /// that are easy to find, so only suited to trusted peers.
+    Fx,
+}
+
+impl PeerHasher {
+    pub(crate) fn build(self) -> PeerHashState {
+        match self {
+            PeerHasher::SipHash => PeerHashState::SipHash(RandomState::new()),
+            PeerHasher::Fx => PeerHashState::Fx,
+        }
+    }
+}
+
+/// Builds the hashers of a peers table for a [`PeerHasher`].
+#[derive(Clone, Debug)]
+pub(crate) enum PeerHashState {
+    SipHash(RandomState),
+    Fx,
+}
+
+impl BuildHasher for PeerHashState {
+    type Hasher = PeerHash;
+
+    fn build_hasher(&self) -> PeerHash {
+        match self {
+            PeerHashState::SipHash(state) => PeerHash::SipHash(state.build_hasher()),
+            PeerHashState::Fx => PeerHash::Fx(0),
+        }
+    }
+}
+
+/// The hash of a peer address being computed.
+pub(crate) enum PeerHash {
+    SipHash(DefaultHasher),
+    /// The FxHash state.
+    Fx(u64),
+}
+
+/// The multiplier of FxHash.
+const FX_SEED: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+
+impl PeerHash {
+    fn add_to_fx(hash: &mut u64, word: u64) {
+        *hash = (hash.rotate_left(5) ^ word).wrapping_mul(FX_SEED);
+    }
+}
+
+impl Hasher for PeerHash {
+    fn write(&mut self, bytes: &[u8]) {
+        match self {
+            PeerHash::SipHash(hasher) => hasher.write(bytes),
+            PeerHash::Fx(hash) => {
+                let mut chunks = bytes.chunks_exact(8);
+                for chunk in &mut chunks {
+                    Self::add_to_fx(hash, u64::from_le_bytes(chunk.try_into().unwrap()));
+                }
+                for byte in chunks.remainder() {
+                    Self::add_to_fx(hash, *byte as u64);
+                }
+            }
+        }
+    }
+
+    fn write_u8(&mut self, i: u8) {
+        match self {
+            PeerHash::SipHash(hasher) => hasher.write_u8(i),
+            PeerHash::Fx(hash) => Self::add_to_fx(hash, i as u64),
+        }
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        match self {
+            PeerHash::SipHash(hasher) => hasher.write_u16(i),
+            PeerHash::Fx(hash) => Self::add_to_fx(hash, i as u64),
+        }
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        match self {
+            PeerHash::SipHash(hasher) => hasher.write_u32(i),
+            PeerHash::Fx(hash) => Self::add_to_fx(hash, i as u64),
+        }
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        match self {
+            PeerHash::SipHash(hasher) => hasher.write_u64(i),
+            PeerHash::Fx(hash) => Self::add_to_fx(hash, i),
+        }
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        match self {
+            PeerHash::SipHash(hasher) => hasher.write_usize(i),
+            PeerHash::Fx(hash) => Self::add_to_fx(hash, i as u64),
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        match self {
+            PeerHash::SipHash(hasher) => hasher.finish(),
+            PeerHash::Fx(hash) => *hash,
+        }
+    }
+}
@@ -1,294 +1,3637 @@
 use bytes::{Buf, Bytes, BytesMut};
+use socket2::SockRef;
 use std::{
-    collections::HashMap,
+    borrow::Cow,
+    collections::{HashMap, VecDeque},
+    fmt,
     future::Future,
+    hash::BuildHasher,
     io,
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     pin::Pin,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, AtomicU8, Ordering},
+        Arc,
+    },
     task::{Context, Poll},
+    time::{Duration, SystemTime},
 };
 use tokio::{
     io::{AsyncRead, AsyncWrite, ReadBuf},
     net::UdpSocket,
-    sync::{mpsc, Mutex},
+    runtime::Handle,
+    sync::{mpsc, watch, Mutex, Notify},
+    time::{Instant, Interval, MissedTickBehavior, Sleep},
 };
 
+#[cfg(feature = "blocking")]
+mod blocking;
+mod builder;
+mod codec;
+mod dedup;
+mod frame;
+mod hasher;
+#[cfg(feature = "test-util")]
+mod mock;
+mod pool;
+mod rate;
+mod rtt;
+mod sequence;
+mod stats;
+mod substream;
+mod sys;
+mod tap;
+
+#[cfg(feature = "blocking")]
+pub use blocking::BlockingUdpStream;
+use builder::{ListenerConfig, StreamConfig};
+pub use builder::{QueueFullPolicy, UdpListenerBuilder, UdpStreamBuilder, ZeroLengthPolicy};
+#[cfg(feature = "lz4")]
+pub use codec::Lz4Codec;
+use codec::SharedCodec;
+pub use codec::{CrcAlgorithm, CrcCodec, DatagramCodec};
+use dedup::DedupWindow;
+pub use frame::{FrameReassembler, Framing};
+use hasher::PeerHashState;
+pub use hasher::PeerHasher;
+pub use pool::PoolStats;
+use pool::{BufferPool, PoolCounters};
+use rate::SendRate;
+pub use rtt::RttTracker;
+pub use sequence::SequenceTracker;
+use stats::{BufferedBytes, ListenerCounters, StreamCounters};
+pub use stats::{ListenerStats, StreamStats};
+pub use substream::{DemuxBy, SubStream};
+pub use sys::{DatagramMeta, Ecn};
+use sys::{MetaOptions, RecvMeta};
+pub use tap::{replay, DatagramTap, MemoryTap, RecordedDatagram};
+
 const UDP_BUFFER_SIZE: usize = 17480; // 17kb
                                       // const UDP_TIMEOUT: u64 = 10 * 1000; // 10sec
 const CHANNEL_LEN: usize = 100;
+/// How long the sockets of a listener shutting down must stay quiet for the
+/// drain to be complete.
+const DRAIN_QUIET: Duration = Duration::from_millis(10);
+/// The most datagrams already waiting on the sockets that the receive loop
+/// takes at once to dispatch them by priority.
+const PRIORITY_BATCH: usize = 32;
+/// How often [`UdpListener::quiesce`] checks the peer queues.
+const QUIESCE_POLL: Duration = Duration::from_millis(1);
+/// How many times a send interrupted by a signal is retried in `poll_write`.
+const SEND_RETRIES: usize = 3;
+/// The most writes queued while `nodelay` is unset, see
+/// [`UdpStream::set_nodelay`].
+const WRITE_QUEUE_LEN: usize = 64;
+const UDP_BUFFER_SIZE_ENV: &str = "UDP_STREAM_BUFFER_SIZE";
+/// The largest receive buffer size `UDP_STREAM_BUFFER_SIZE` may set, the
+/// size of the largest UDP datagram.
+const MAX_UDP_BUFFER_SIZE: usize = 65535;
+
+/// Returns the receive buffer size, honoring the `UDP_STREAM_BUFFER_SIZE`
+/// environment variable and falling back to the default when it is unset or
+/// invalid.
+///
+/// The variable is read, and the size logged, the first time a socket is set
+/// up; later changes to it have no effect.
+fn udp_buffer_size() -> usize {
+    static SIZE: std::sync::OnceLock<usize> = std::sync::OnceLock::new();
+    *SIZE.get_or_init(|| {
+        let size = parse_buffer_size(std::env::var(UDP_BUFFER_SIZE_ENV).ok().as_deref());
+        log::debug!("udp buffer size {}", size);
+        size
+    })
+}
+
+/// Parses a value of `UDP_STREAM_BUFFER_SIZE`, returning the default size
+/// when it is missing, not a number, zero or larger than a datagram.
+fn parse_buffer_size(value: Option<&str>) -> usize {
+    value
+        .and_then(|value| value.trim().parse::<usize>().ok())
+        .filter(|size| (1..=MAX_UDP_BUFFER_SIZE).contains(size))
+        .unwrap_or(UDP_BUFFER_SIZE)
+}
+
+/// State shared between the task receiving datagrams for a peer and the
+/// peer's `UdpStream`.
+#[derive(Debug)]
+struct PeerState {
+    last_seen: std::sync::Mutex<Instant>,
+    /// Set when the listener ended the session for exceeding its maximum
+    /// duration.
+    expired: AtomicBool,
+    /// The larger queue the listener moved the stream to, which the stream
+    /// switches to once its current queue is exhausted, see
+    /// [`UdpListenerBuilder::channel_growth`].
+    grown: std::sync::Mutex<Option<mpsc::Receiver<Datagram>>>,
+    /// Set, and the waiters notified, once no more datagrams will be queued
+    /// for the peer.
+    closed: AtomicBool,
+    closed_notify: Notify,
+    /// See [`UdpStream::set_priority`].
+    priority: AtomicU8,
+    /// Set while the queue of the stream is past the high watermark, see
+    /// [`UdpListenerBuilder::channel_high_watermark`].
+    above_watermark: AtomicBool,
+    /// Set once the stream is returned by [`UdpListener::accept`].
+    accepted: AtomicBool,
+    /// The address the peer sends from, once it has moved away from the one
+    /// the session started from, see [`UdpListenerBuilder::connection_id`].
+    moved_to: std::sync::Mutex<Option<SocketAddr>>,
+}
+
+impl PeerState {
+    fn new() -> Arc<Self> {
+        Arc::new(Self {
+            last_seen: std::sync::Mutex::new(Instant::now()),
+            expired: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+            closed_notify: Notify::new(),
+            priority: AtomicU8::new(0),
+            above_watermark: AtomicBool::new(false),
+            accepted: AtomicBool::new(false),
+            moved_to: std::sync::Mutex::new(None),
+            grown: std::sync::Mutex::new(None),
+        })
+    }
+
+    /// Switches `receiver` to the queue the listener moved the stream to,
+    /// returning `false` if there is none.
+    fn take_grown(&self, receiver: &mut mpsc::Receiver<Datagram>) -> bool {
+        match self.grown.lock().unwrap().take() {
+            Some(grown) => {
+                *receiver = grown;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.closed_notify.notify_waiters();
+    }
+
+    async fn closed(&self) {
+        let notified = self.closed_notify.notified();
+        if self.closed.load(Ordering::Acquire) {
+            return;
+        }
+        notified.await;
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expired.load(Ordering::Relaxed)
+    }
+
+    /// Returns the error reading a stream closed by the listener fails with.
+    fn closed_error(&self) -> io::Error {
+        if self.is_expired() {
+            io::Error::new(io::ErrorKind::TimedOut, "session duration exceeded")
+        } else {
+            io::Error::from(io::ErrorKind::BrokenPipe)
+        }
+    }
+
+    fn touch(&self) {
+        *self.last_seen.lock().unwrap() = Instant::now();
+    }
+
+    fn last_seen(&self) -> Instant {
+        *self.last_seen.lock().unwrap()
+    }
+}
+
+/// A received datagram and the metadata the kernel reported with it.
+#[derive(Debug)]
+struct Datagram {
+    payload: Bytes,
+    meta: RecvMeta,
+    buffered: Option<BufferedBytes>,
+    /// Set on the empty datagram standing for an ICMP error received from the
+    /// peer, see [`PeerUnreachable`].
+    unreachable: Option<io::ErrorKind>,
+}
+
+impl Datagram {
+    fn unreachable(kind: io::ErrorKind) -> Self {
+        Self {
+            unreachable: Some(kind),
+            ..Bytes::new().into()
+        }
+    }
+}
+
+impl From<Bytes> for Datagram {
+    fn from(payload: Bytes) -> Self {
+        Self {
+            payload,
+            meta: RecvMeta::default(),
+            buffered: None,
+            unreachable: None,
+        }
+    }
+}
+
+struct Peer {
+    sender: mpsc::Sender<Datagram>,
+    /// The address datagrams to the peer are sent to, which may differ from
+    /// its key when addresses are canonicalized.
+    wire_addr: SocketAddr,
+    state: Arc<PeerState>,
+    /// How many datagrams the peer sent, see
+    /// [`UdpListenerBuilder::channel_growth`].
+    received: u64,
+    socket: Arc<UdpSocket>,
+    dedup: Option<DedupWindow>,
+    /// Datagrams held back until the next delivery tick.
+    pending: Vec<Datagram>,
+    created_at: Instant,
+    /// When the stream ended the session, if it is kept for the close grace
+    /// period.
+    closing_since: Option<Instant>,
+    /// Accounts for the peer in the limit of peers per IP address.
+    _ip_slot: Option<IpSlot>,
+    /// Set while the datagrams of the peer are dropped, see
+    /// [`UdpListener::mute`].
+    muted: bool,
+    /// The connection id of the session, see
+    /// [`UdpListenerBuilder::connection_id`].
+    connection_id: Option<u64>,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+impl Drop for Peer {
+    fn drop(&mut self) {
+        self.state.close();
+    }
+}
+
+/// The number of peers tracked for every IP address, see
+/// [`UdpListenerBuilder::max_peers_per_ip`].
+type PeersPerIp = Arc<std::sync::Mutex<HashMap<IpAddr, usize>>>;
+
+/// Accounts for a peer in [`PeersPerIp`] until it is untracked.
+struct IpSlot {
+    peers: PeersPerIp,
+    ip: IpAddr,
+}
+
+impl IpSlot {
+    /// Counts one more peer for `ip`, failing if it already has `limit`.
+    fn reserve(peers: &PeersPerIp, ip: IpAddr, limit: usize) -> Option<Self> {
+        let mut counts = peers.lock().unwrap();
+        let count = counts.entry(ip).or_insert(0);
+        if *count >= limit {
+            return None;
+        }
+        *count += 1;
+        Some(Self {
+            peers: peers.clone(),
+            ip,
+        })
+    }
+}
+
+impl Drop for IpSlot {
+    fn drop(&mut self) {
+        let mut counts = self.peers.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// The number of separately locked shards of a [`PeerTable`].
+const PEER_SHARDS: usize = 16;
+
+/// A shard of a [`PeerTable`].
+type PeerShard = HashMap<SocketAddr, Peer, PeerHashState>;
+
+/// The peers tracked by a listener, by address.
+///
+/// The table is split into shards locked separately, so that demux workers
+/// and listener handles looking up different peers rarely wait on each
+/// other. A peer always lives in the shard its address hashes to.
+struct PeerTable {
+    shards: Box<[std::sync::Mutex<PeerShard>]>,
+    hasher: PeerHashState,
+}
+
+impl PeerTable {
+    fn new(hasher: PeerHasher, capacity: usize) -> Self {
+        let hasher = hasher.build();
+        let shards = (0..PEER_SHARDS)
+            .map(|_| {
+                std::sync::Mutex::new(HashMap::with_capacity_and_hasher(
+                    capacity.div_ceil(PEER_SHARDS),
+                    hasher.clone(),
+                ))
+            })
+            .collect();
+        Self { shards, hasher }
+    }
+
+    /// Locks the shard of `peer_addr`.
+    fn shard(&self, peer_addr: &SocketAddr) -> std::sync::MutexGuard<'_, PeerShard> {
+        // The low bits of the hash pick the bucket within the shard, so the
+        // shard is picked with higher ones.
+        let hash = self.hasher.hash_one(peer_addr);
+        self.shards[(hash >> 32) as usize % PEER_SHARDS]
+            .lock()
+            .unwrap()
+    }
+
+    /// Locks every shard in turn.
+    fn shards(&self) -> impl Iterator<Item = std::sync::MutexGuard<'_, PeerShard>> {
+        self.shards.iter().map(|shard| shard.lock().unwrap())
+    }
+
+    fn len(&self) -> usize {
+        self.shards().map(|shard| shard.len()).sum()
+    }
+
+    fn contains(&self, peer_addr: &SocketAddr) -> bool {
+        self.shard(peer_addr).contains_key(peer_addr)
+    }
+
+    fn remove(&self, peer_addr: &SocketAddr) -> Option<Peer> {
+        self.shard(peer_addr).remove(peer_addr)
+    }
+
+    /// Returns the key of a peer satisfying `f`, if any.
+    fn find(&self, mut f: impl FnMut(&Peer) -> bool) -> Option<SocketAddr> {
+        self.shards()
+            .find_map(|shard| shard.iter().find(|(_, peer)| f(peer)).map(|(key, _)| *key))
+    }
+
+    fn retain(&self, mut f: impl FnMut(&SocketAddr, &mut Peer) -> bool) {
+        for mut shard in self.shards() {
+            shard.retain(&mut f);
+        }
+    }
+
+    /// Untracks every peer, returning them.
+    fn drain(&self) -> Vec<Peer> {
+        self.shards()
+            .flat_map(|mut shard| shard.drain().map(|(_, peer)| peer).collect::<Vec<_>>())
+            .collect()
+    }
+}
+
+/// State shared between a `UdpListener` handle and its receive loop.
+struct ListenerShared {
+    streams: PeerTable,
+    /// The key of the session of every connection id, see
+    /// [`UdpListenerBuilder::connection_id`]. Entries of untracked sessions
+    /// are pruned lazily.
+    connection_ids: std::sync::Mutex<HashMap<u64, SocketAddr>>,
+    peers_per_ip: PeersPerIp,
+    counters: ListenerCounters,
+}
+
+impl ListenerShared {
+    fn new(hasher: PeerHasher, capacity: usize) -> Self {
+        Self {
+            streams: PeerTable::new(hasher, capacity),
+            connection_ids: std::sync::Mutex::new(HashMap::new()),
+            peers_per_ip: PeersPerIp::default(),
+            counters: ListenerCounters::default(),
+        }
+    }
+}
+
+/// A request from a `UdpListener` handle to its receive loop.
+enum Control {
+    /// Receive on these sockets from now on, instead of the current ones.
+    Rebind(Vec<(SocketAddr, Arc<UdpSocket>)>),
+}
+
+/// The receive loop of a listener, dispatching datagrams to per-peer streams.
+struct Demux {
+    sockets: Vec<(SocketAddr, Arc<UdpSocket>)>,
+    shared: Arc<ListenerShared>,
+    accept_tx: mpsc::Sender<(UdpStream, SocketAddr)>,
+    drop_tx: mpsc::Sender<SocketAddr>,
+    config: ListenerConfig,
+    /// Signals a graceful shutdown. Dropped with the loop, which tells
+    /// [`UdpListener::shutdown_timeout`] that the loop ended.
+    shutdown: watch::Receiver<bool>,
+    /// Set once shutting down, when new peers are no longer accepted.
+    draining: bool,
+    /// The kernel drop counter last reported by each socket, see
+    /// [`UdpListenerBuilder::kernel_drops`].
+    kernel_drops: Vec<u32>,
+    /// The datagrams waiting for room in a full queue, see
+    /// [`QueueFullPolicy::Wait`].
+    blocked: Vec<Blocked>,
+}
+
+/// Datagrams of a peer that found the queue of its stream full, in order.
+struct Blocked {
+    sender: mpsc::Sender<Datagram>,
+    datagrams: Vec<Datagram>,
+    peer_addr: SocketAddr,
+}
+
+impl Demux {
+    async fn run(
+        mut self,
+        mut drop_rx: mpsc::Receiver<SocketAddr>,
+        mut inject_rx: mpsc::Receiver<(Datagram, SocketAddr)>,
+        mut control_rx: mpsc::Receiver<Control>,
+        buffer_size: usize,
+        mut pool: Option<BufferPool>,
+    ) {
+        let mut next_socket = 0;
+        let mut own_buf = BytesMut::new();
+        let mut batch = Vec::with_capacity(PRIORITY_BATCH);
+        let with_meta = self.config.meta_options().any();
+        let mut delivery = self.config.delivery_interval.map(|period| {
+            let mut interval = tokio::time::interval(period);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            interval
+        });
+        let mut reaping = self.config.max_session_duration.map(|duration| {
+            let mut interval = tokio::time::interval(duration / 4);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            interval
+        });
+        let mut closing = self.config.close_grace.map(|grace| {
+            let mut interval = tokio::time::interval(grace / 4);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            interval
+        });
+        loop {
+            // `select!` picks a ready branch at random, so a flood of
+            // datagrams could keep delaying the removal of dropped streams.
+            // Pending removals are therefore applied before every datagram.
+            while let Ok(peer_addr) = drop_rx.try_recv() {
+                self.remove_peer(peer_addr);
+            }
+            let buf = next_buffer(&mut pool, &mut own_buf, buffer_size);
+            tokio::select! {
+                Some(peer_addr) = drop_rx.recv() => {
+                    self.remove_peer(peer_addr);
+                }
+                Some((datagram, peer_addr)) = inject_rx.recv() => {
+                    self.dispatch(0, datagram, peer_addr);
+                    self.deliver_blocked().await;
+                }
+                Some(control) = control_rx.recv() => match control {
+                    Control::Rebind(sockets) => {
+                        self.kernel_drops = vec![0; sockets.len()];
+                        self.sockets = sockets;
+                        next_socket = 0;
+                    }
+                },
+                _ = tick(&mut delivery) => {
+                    self.flush_pending();
+                    self.deliver_blocked().await;
+                }
+                _ = tick(&mut reaping) => {
+                    self.reap_expired();
+                }
+                _ = tick(&mut closing) => {
+                    self.reap_closing();
+                }
+                Ok(()) = self.shutdown.changed() => {
+                    self.drain(buffer_size, with_meta).await;
+                    return;
+                }
+                Ok((index, len, peer_addr, meta)) = recv_any(&self.sockets, &mut next_socket, buf, with_meta) => {
+                    batch.extend(self.take_received(index, buf, len, peer_addr, meta).map(|datagram| (index, datagram, peer_addr)));
+                    // Under load, the datagrams already waiting are taken too
+                    // so that those of higher priority peers go first.
+                    while batch.len() < PRIORITY_BATCH {
+                        let buf = next_buffer(&mut pool, &mut own_buf, buffer_size);
+                        let received = tokio::select! {
+                            biased;
+                            received = recv_any(&self.sockets, &mut next_socket, buf, with_meta) => received,
+                            () = std::future::ready(()) => break,
+                        };
+                        let Ok((index, len, peer_addr, meta)) = received else {
+                            break;
+                        };
+                        batch.extend(self.take_received(index, buf, len, peer_addr, meta).map(|datagram| (index, datagram, peer_addr)));
+                    }
+                    self.dispatch_by_priority(&mut batch).await;
+                }
+            }
+        }
+    }
+
+    /// Delivers the datagrams waiting on the sockets to their streams until
+    /// the sockets stay quiet for [`DRAIN_QUIET`], then flushes those held
+    /// back, for a graceful shutdown. Datagrams from new peers are dropped
+    /// from now on.
+    async fn drain(&mut self, buffer_size: usize, with_meta: bool) {
+        self.draining = true;
+        let mut buf = BytesMut::new();
+        let mut next_socket = 0;
+        loop {
+            reserve_datagram(&mut buf, buffer_size);
+            // Waiting, rather than only taking what the socket reports ready,
+            // lets the runtime notice datagrams it has not polled for yet.
+            let received = tokio::time::timeout(
+                DRAIN_QUIET,
+                recv_any(&self.sockets, &mut next_socket, &mut buf, with_meta),
+            )
+            .await;
+            let Ok(Ok((index, len, peer_addr, meta))) = received else {
+                break;
+            };
+            if let Some(datagram) = self.take_received(index, &mut buf, len, peer_addr, meta) {
+                self.dispatch(index, datagram, peer_addr);
+                self.deliver_blocked().await;
+            }
+        }
+        self.flush_pending();
+        self.deliver_blocked().await;
+    }
+
+    /// Takes the `len` bytes just received into `buf` from `peer_addr` on
+    /// socket `index` out as a datagram and records it.
+    fn take_received(
+        &mut self,
+        index: usize,
+        buf: &mut BytesMut,
+        len: usize,
+        peer_addr: SocketAddr,
+        meta: RecvMeta,
+    ) -> Option<Datagram> {
+        if let Some(drops) = meta.kernel_drops {
+            // The counter is cumulative and wraps around.
+            let last = std::mem::replace(&mut self.kernel_drops[index], drops);
+            self.shared
+                .counters
+                .dropped_kernel
+                .fetch_add(u64::from(drops.wrapping_sub(last)), Ordering::Relaxed);
+        }
+        let payload = take_datagram(buf, len, peer_addr)?;
+        let datagram = Datagram {
+            payload,
+            meta,
+            buffered: None,
+            unreachable: None,
+        };
+        if let Some(tap) = &self.config.tap {
+            tap.record(&RecordedDatagram {
+                timestamp: datagram.meta.timestamp.unwrap_or_else(SystemTime::now),
+                source: peer_addr,
+                payload: datagram.payload.clone(),
+            });
+        }
+        Some(datagram)
+    }
+
+    /// Dispatches the datagrams of `batch`, those of higher priority peers
+    /// first and otherwise in the order they were received.
+    async fn dispatch_by_priority(&mut self, batch: &mut Vec<(usize, Datagram, SocketAddr)>) {
+        if batch.len() > 1 {
+            // The priority of each peer is read once, so that a concurrent
+            // `set_priority` cannot reorder the datagrams of a peer.
+            let mut priorities = HashMap::new();
+            batch.sort_by_cached_key(|(_, _, wire_addr)| {
+                let key = self.peer_key(*wire_addr);
+                *priorities.entry(key).or_insert_with(|| {
+                    let streams = self.shared.streams.shard(&key);
+                    std::cmp::Reverse(
+                        streams
+                            .get(&key)
+                            .map_or(0, |peer| peer.state.priority.load(Ordering::Relaxed)),
+                    )
+                })
+            });
+        }
+        for (index, datagram, wire_addr) in batch.drain(..) {
+            self.dispatch(index, datagram, wire_addr);
+            self.deliver_blocked().await;
+        }
+    }
+
+    /// Returns the key the peer sending from `wire_addr` is tracked under.
+    fn peer_key(&self, wire_addr: SocketAddr) -> SocketAddr {
+        if self.config.canonical_addrs {
+            canonical_addr(wire_addr)
+        } else {
+            wire_addr
+        }
+    }
+
+    /// Delivers a datagram received on socket `index` to the stream of
+    /// `peer_addr`, creating and announcing the stream if it is a new peer.
+    fn dispatch(&mut self, index: usize, mut datagram: Datagram, wire_addr: SocketAddr) {
+        let peer_addr = self.peer_key(wire_addr);
+        if datagram.payload.is_empty() {
+            match &self.config.zero_length {
+                ZeroLengthPolicy::Deliver => {}
+                policy => {
+                    let (_, key) = self.session_key(&datagram.payload, peer_addr);
+                    let streams = &self.shared.streams;
+                    // Without a connection id to go by, a session that moved
+                    // is found by the address it moved to.
+                    let key = match self.config.connection_id {
+                        Some(_) if !streams.contains(&key) => streams
+                            .find(|peer| peer.wire_addr == wire_addr)
+                            .unwrap_or(key),
+                        _ => key,
+                    };
+                    if let Some(peer) = streams.shard(&key).get(&key) {
+                        peer.state.touch();
+                    }
+                    if let ZeroLengthPolicy::Keepalive(callback) = policy {
+                        callback(key);
+                    }
+                    return;
+                }
+            }
+        }
+        if let Some(size) = self.config.max_datagram_size {
+            if datagram.payload.len() > size {
+                log::debug!(
+                    "dropping {} bytes datagram from {}, above the maximum size",
+                    datagram.payload.len(),
+                    peer_addr
+                );
+                ListenerCounters::increment(&self.shared.counters.dropped_oversize);
+                return;
+            }
+        }
+        if let Some(codec) = self
+            .config
+            .codec
+            .as_ref()
+            .filter(|_| !datagram.payload.is_empty())
+        {
+            match codec.decode(std::mem::take(&mut datagram.payload)) {
+                Ok(payload) => datagram.payload = payload,
+                Err(err) => {
+                    log::debug!(
+                        "dropping datagram from {}, failed to decode: {}",
+                        peer_addr,
+                        err
+                    );
+                    ListenerCounters::increment(&self.shared.counters.dropped_decode);
+                    return;
+                }
+            }
+        }
+        if let Some(limit) = self.config.max_buffered_bytes {
+            let counters = &self.shared.counters;
+            match BufferedBytes::reserve(&counters.buffered_bytes, datagram.payload.len(), limit) {
+                Some(buffered) => datagram.buffered = Some(buffered),
+                None => {
+                    log::debug!(
+                        "buffered bytes limit reached, dropping datagram from {}",
+                        peer_addr
+                    );
+                    ListenerCounters::increment(&counters.dropped_buffer_limit);
+                    return;
+                }
+            }
+        }
+        let (connection_id, peer_addr) = self.session_key(&datagram.payload, peer_addr);
+        let hash = self
+            .config
+            .dedup
+            .as_ref()
+            .map(|dedup| (dedup.hasher)(&datagram.payload));
+        let mut streams = self.shared.streams.shard(&peer_addr);
+        match streams.get_mut(&peer_addr) {
+            Some(peer) if peer.muted => {
+                log::trace!("dropping datagram from muted peer {}", peer_addr);
+                ListenerCounters::increment(&self.shared.counters.dropped_muted);
+                return;
+            }
+            Some(peer)
+                if self.config.lazy_accept && !peer.state.accepted.load(Ordering::Acquire) =>
+            {
+                log::trace!("dropping datagram from {}, not accepted yet", peer_addr);
+                ListenerCounters::increment(&self.shared.counters.dropped_unaccepted);
+                return;
+            }
+            Some(peer) => {
+                if let (Some(window), Some(hash)) = (&mut peer.dedup, hash) {
+                    if window.check(hash) {
+                        log::trace!("dropping duplicate datagram from {}", peer_addr);
+                        ListenerCounters::increment(&self.shared.counters.duplicates_dropped);
+                        return;
+                    }
+                }
+                peer.state.touch();
+                peer.received += 1;
+                if self.config.connection_id.is_some() && peer.wire_addr != wire_addr {
+                    log::debug!(
+                        "peer {} moved from {} to {}",
+                        peer_addr,
+                        peer.wire_addr,
+                        wire_addr
+                    );
+                    peer.wire_addr = wire_addr;
+                    *peer.state.moved_to.lock().unwrap() = Some(wire_addr);
+                }
+                if peer.closing_since.take().is_some() {
+                    log::debug!("peer {} revived within the close grace period", peer_addr);
+                }
+                #[cfg(feature = "tracing")]
+                peer.span.in_scope(|| {
+                    tracing::trace!(len = datagram.payload.len(), "datagram received")
+                });
+                if self.config.delivery_interval.is_some() {
+                    peer.pending.push(datagram);
+                    return;
+                }
+                // Delivering under the lock keeps it atomic with the changes
+                // the listener handle makes to the peer, such as muting it.
+                grow_queue(peer, &self.config);
+                match deliver(
+                    &peer.sender,
+                    datagram,
+                    peer_addr,
+                    &self.config,
+                    &self.shared.counters,
+                ) {
+                    Delivery::Queued | Delivery::Dropped => {
+                        self.check_watermark(&peer.sender, &peer.state, peer_addr)
+                    }
+                    Delivery::Full(datagram) => self.blocked.push(Blocked {
+                        sender: peer.sender.clone(),
+                        datagrams: vec![datagram],
+                        peer_addr,
+                    }),
+                    Delivery::Closed => {
+                        streams.remove(&peer_addr);
+                    }
+                }
+                return;
+            }
+            None => drop(streams),
+        }
+        if self.draining {
+            log::debug!("listener shutting down, dropping new peer {}", peer_addr);
+            return;
+        }
+        if let Some(admit) = &self.config.admit {
+            let peers = self.shared.streams.len();
+            if !admit(peer_addr, &self.shared.counters.snapshot(peers)) {
+                log::debug!("new peer {} refused admission", peer_addr);
+                ListenerCounters::increment(&self.shared.counters.dropped_admission);
+                return;
+            }
+        }
+        let ip_slot = match self.config.max_peers_per_ip {
+            Some(limit) => {
+                match IpSlot::reserve(&self.shared.peers_per_ip, peer_addr.ip(), limit) {
+                    Some(slot) => Some(slot),
+                    None => {
+                        log::debug!(
+                            "too many peers from {}, dropping new peer {}",
+                            peer_addr.ip(),
+                            peer_addr
+                        );
+                        ListenerCounters::increment(&self.shared.counters.dropped_peers_per_ip);
+                        return;
+                    }
+                }
+            }
+            None => None,
+        };
+        // The first datagram goes into the queue before the stream
+        // exists, so it cannot be overtaken or lost while the stream
+        // waits to be accepted. A fresh queue always has room for it.
+        let capacity = match self.config.channel_growth {
+            Some(growth) => growth.initial.min(self.config.channel_capacity),
+            None => self.config.channel_capacity,
+        };
+        let (child_tx, child_rx) = mpsc::channel(capacity);
+        if let Err(err) = child_tx.try_send(datagram) {
+            log::error!("child_tx.send {:?}", err);
+            return;
+        }
+        let (local_addr, socket) = &self.sockets[index];
+        let state = PeerState::new();
+        self.check_watermark(&child_tx, &state, peer_addr);
+        let mut udp_stream = UdpStream::new(
+            *local_addr,
+            peer_addr,
+            Incoming::Channel(Mutex::new(child_rx)),
+            socket.clone(),
+            state.clone(),
+        );
+        udp_stream.drop = Some(self.drop_tx.clone());
+        udp_stream.wire_addr = wire_addr;
+        udp_stream.shutdown_payload = self.config.shutdown_payload.clone();
+        udp_stream.codec = self.config.codec.clone();
+        #[cfg(feature = "tracing")]
+        let span = {
+            let span = tracing::info_span!("udp_peer", peer = %peer_addr);
+            span.in_scope(|| tracing::debug!("new peer"));
+            udp_stream.span = span.clone();
+            span
+        };
+        // The peer is tracked before it can be accepted so that it is
+        // visible to the listener handle as soon as `accept` returns.
+        self.shared.streams.shard(&peer_addr).insert(
+            peer_addr,
+            Peer {
+                sender: child_tx,
+                wire_addr,
+                state,
+                socket: socket.clone(),
+                dedup: self.config.dedup.as_ref().zip(hash).map(|(dedup, hash)| {
+                    let mut window = DedupWindow::new(dedup.window);
+                    window.check(hash);
+                    window
+                }),
+                received: 1,
+                pending: Vec::new(),
+                created_at: Instant::now(),
+                closing_since: None,
+                _ip_slot: ip_slot,
+                muted: false,
+                connection_id,
+                #[cfg(feature = "tracing")]
+                span,
+            },
+        );
+        if let Some(id) = connection_id {
+            self.register_connection(id, peer_addr);
+        }
+        // A full accept queue drops the new peer instead of stalling
+        // the loop; its next datagram is announced again.
+        let (mut udp_stream, _) = match self.accept_tx.try_send((udp_stream, peer_addr)) {
+            Ok(()) => return,
+            Err(mpsc::error::TrySendError::Full(accepted)) => {
+                log::debug!("accept queue full, dropping new peer {}", peer_addr);
+                ListenerCounters::increment(&self.shared.counters.dropped_accept_full);
+                accepted
+            }
+            Err(mpsc::error::TrySendError::Closed(accepted)) => {
+                log::error!("tx.send {:?}", peer_addr);
+                accepted
+            }
+        };
+        // The peer is untracked below, so the dropped stream must not notify.
+        udp_stream.drop = None;
+        self.shared.streams.remove(&peer_addr);
+    }
+
+    /// Returns the connection id of `payload`, if any, along with the key of
+    /// the session the datagram belongs to: the tracked session with that id,
+    /// or `peer_addr` otherwise.
+    fn session_key(&self, payload: &[u8], peer_addr: SocketAddr) -> (Option<u64>, SocketAddr) {
+        let connection_id = self
+            .config
+            .connection_id
+            .as_ref()
+            .and_then(|connection_id| connection_id(payload));
+        let key = connection_id
+            .and_then(|id| self.session_of(id, peer_addr))
+            .unwrap_or(peer_addr);
+        (connection_id, key)
+    }
+
+    /// Returns the key of the tracked session with the connection id `id`.
+    fn session_of(&self, id: u64, peer_addr: SocketAddr) -> Option<SocketAddr> {
+        let key = *self.shared.connection_ids.lock().unwrap().get(&id)?;
+        if key == peer_addr {
+            return Some(key);
+        }
+        let streams = self.shared.streams.shard(&key);
+        // The session may have ended, and its address been reused since.
+        streams
+            .get(&key)
+            .filter(|peer| peer.connection_id == Some(id))
+            .map(|_| key)
+    }
+
+    /// Records that the new session of `peer_addr` has the connection id
+    /// `id`, pruning the ids of untracked sessions once they pile up.
+    fn register_connection(&self, id: u64, peer_addr: SocketAddr) {
+        let mut ids = self.shared.connection_ids.lock().unwrap();
+        ids.insert(id, peer_addr);
+        let streams = &self.shared.streams;
+        if ids.len() > 2 * streams.len() + 16 {
+            ids.retain(|id, key| {
+                streams
+                    .shard(key)
+                    .get(key)
+                    .is_some_and(|peer| peer.connection_id == Some(*id))
+            });
+        }
+    }
+
+    /// Hands the datagrams held back for every peer to their streams.
+    ///
+    /// Datagrams finding a queue full under [`QueueFullPolicy::Wait`] are left
+    /// to [`deliver_blocked`](Self::deliver_blocked).
+    fn flush_pending(&mut self) {
+        let counters = &self.shared.counters;
+        let mut blocked = Vec::new();
+        self.shared.streams.retain(|peer_addr, peer| {
+            let mut pending = std::mem::take(&mut peer.pending).into_iter();
+            while let Some(datagram) = pending.next() {
+                grow_queue(peer, &self.config);
+                match deliver(&peer.sender, datagram, *peer_addr, &self.config, counters) {
+                    Delivery::Queued | Delivery::Dropped => {}
+                    Delivery::Full(datagram) => {
+                        blocked.push(Blocked {
+                            sender: peer.sender.clone(),
+                            datagrams: std::iter::once(datagram).chain(pending).collect(),
+                            peer_addr: *peer_addr,
+                        });
+                        break;
+                    }
+                    Delivery::Closed => return false,
+                }
+            }
+            self.check_watermark(&peer.sender, &peer.state, *peer_addr);
+            true
+        });
+        self.blocked.extend(blocked);
+    }
+
+    /// Warns once the queue of `peer_addr` fills up past the high watermark,
+    /// if one is configured.
+    fn check_watermark(
+        &self,
+        sender: &mpsc::Sender<Datagram>,
+        state: &PeerState,
+        peer_addr: SocketAddr,
+    ) {
+        let Some(fraction) = self.config.channel_high_watermark else {
+            return;
+        };
+        let capacity = sender.max_capacity();
+        let watermark = ((capacity as f64 * fraction).ceil() as usize).max(1);
+        let len = capacity - sender.capacity();
+        let above = len >= watermark;
+        if above && !state.above_watermark.swap(true, Ordering::Relaxed) {
+            log::warn!(
+                "queue of {} past its high watermark, {} of {} datagrams",
+                peer_addr,
+                len,
+                capacity
+            );
+            ListenerCounters::increment(&self.shared.counters.high_watermark_crossings);
+        } else if !above {
+            state.above_watermark.store(false, Ordering::Relaxed);
+        }
+    }
+
+    /// Waits for room in the full queues datagrams were held back for, then
+    /// queues them, unless the peer was muted or removed meanwhile.
+    ///
+    /// The lock is only taken once there is room, so that the listener handle
+    /// can still reach the peer while the loop waits.
+    async fn deliver_blocked(&mut self) {
+        for blocked in std::mem::take(&mut self.blocked) {
+            let peer_addr = blocked.peer_addr;
+            for datagram in blocked.datagrams {
+                let Ok(permit) = blocked.sender.clone().reserve_owned().await else {
+                    log::debug!("stream of {} closed", peer_addr);
+                    break;
+                };
+                let streams = self.shared.streams.shard(&peer_addr);
+                match streams.get(&peer_addr) {
+                    Some(peer) if peer.muted => {
+                        log::trace!("dropping datagram from muted peer {}", peer_addr);
+                        ListenerCounters::increment(&self.shared.counters.dropped_muted);
+                    }
+                    Some(peer) => {
+                        permit.send(datagram);
+                        self.check_watermark(&peer.sender, &peer.state, peer_addr);
+                    }
+                    None => break,
+                }
+            }
+        }
+    }
+    /// Stops tracking `peer_addr` once its stream is dropped or ends the
+    /// session. A stream still alive is kept for the close grace period, if
+    /// one is configured.
+    fn remove_peer(&self, peer_addr: SocketAddr) {
+        let mut streams = self.shared.streams.shard(&peer_addr);
+        let Some(peer) = streams.get_mut(&peer_addr) else {
+            return;
+        };
+        if self.config.close_grace.is_some() && !peer.sender.is_closed() {
+            peer.closing_since.get_or_insert_with(Instant::now);
+        } else {
+            streams.remove(&peer_addr);
+        }
+    }
+    /// Stops tracking the peers whose close grace period passed without
+    /// traffic, and those whose stream was dropped meanwhile.
+    fn reap_closing(&mut self) {
+        let Some(grace) = self.config.close_grace else {
+            return;
+        };
+        self.shared
+            .streams
+            .retain(|_, peer| match peer.closing_since {
+                Some(since) => since.elapsed() < grace && !peer.sender.is_closed(),
+                None => true,
+            });
+    }
+    /// Ends the sessions that exceeded the maximum duration.
+    fn reap_expired(&mut self) {
+        let Some(max) = self.config.max_session_duration else {
+            return;
+        };
+        self.shared.streams.retain(|peer_addr, peer| {
+            if peer.created_at.elapsed() < max {
+                return true;
+            }
+            log::debug!("session of {} exceeded its maximum duration", peer_addr);
+            peer.state.expired.store(true, Ordering::Relaxed);
+            false
+        });
+    }
+}
+
+/// The outcome of [`deliver`].
+enum Delivery {
+    Queued,
+    /// The queue is full and the datagram was dropped.
+    Dropped,
+    /// The queue is full and the datagram must wait for room, see
+    /// [`QueueFullPolicy::Wait`].
+    Full(Datagram),
+    /// The stream is gone.
+    Closed,
+}
+
+/// Queues a datagram on the stream of `peer_addr` without waiting.
+///
+/// By default, a stream that does not keep up loses datagrams rather than
+/// stalling the loop, so a flooding peer cannot delay the others.
+fn deliver(
+    sender: &mpsc::Sender<Datagram>,
+    datagram: Datagram,
+    peer_addr: SocketAddr,
+    config: &ListenerConfig,
+    counters: &ListenerCounters,
+) -> Delivery {
+    match sender.try_send(datagram) {
+        Ok(()) => Delivery::Queued,
+        Err(mpsc::error::TrySendError::Full(datagram)) => match config.queue_full {
+            QueueFullPolicy::Drop => {
+                log::trace!("queue full, dropping datagram from {}", peer_addr);
+                ListenerCounters::increment(&counters.dropped_queue_full);
+                Delivery::Dropped
+            }
+            QueueFullPolicy::Wait => Delivery::Full(datagram),
+        },
+        Err(mpsc::error::TrySendError::Closed(_)) => {
+            log::debug!("stream of {} closed", peer_addr);
+            Delivery::Closed
+        }
+    }
+}
+
+/// Completes on the next tick of `interval`, or never if there is none.
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Moves the stream of `peer` to a queue twice as large if its current one is
+/// full and the peer has shown sustained traffic, see
+/// [`UdpListenerBuilder::channel_growth`].
+///
+/// The stream is only moved again once it switched to the previous larger
+/// queue, so it never has more than two.
+fn grow_queue(peer: &mut Peer, config: &ListenerConfig) {
+    let Some(growth) = config.channel_growth else {
+        return;
+    };
+    let current = peer.sender.max_capacity();
+    if peer.sender.capacity() > 0
+        || current >= config.channel_capacity
+        || peer.received < growth.sustained
+    {
+        return;
+    }
+    // Checking for a closed stream under the lock pairs with the stream
+    // closing its queue before clearing the slot when it is dropped.
+    let mut grown = peer.state.grown.lock().unwrap();
+    if grown.is_some() || peer.sender.is_closed() {
+        return;
+    }
+    let (sender, receiver) = mpsc::channel((current * 2).min(config.channel_capacity));
+    *grown = Some(receiver);
+    // Dropping the old sender lets the stream see the end of the old queue.
+    peer.sender = sender;
+}
+
+/// Polls `receiver` for the next datagram, switching to the queue the listener
+/// moved the stream to once `receiver` is exhausted.
+fn poll_recv_channel(
+    receiver: &mut mpsc::Receiver<Datagram>,
+    state: &PeerState,
+    cx: &mut Context,
+) -> Poll<Option<Datagram>> {
+    loop {
+        match std::task::ready!(receiver.poll_recv(cx)) {
+            None if state.take_grown(receiver) => {}
+            datagram => return Poll::Ready(datagram),
+        }
+    }
+}
+
+/// Waits for a datagram on any of `sockets` and returns the index of the
+/// socket it arrived on along with its length and source. Sockets are polled
+/// starting from `next` so a busy socket cannot starve the others. Control
+/// message metadata is only read when `with_meta` is set.
+async fn recv_any(
+    sockets: &[(SocketAddr, Arc<UdpSocket>)],
+    next: &mut usize,
+    buf: &mut BytesMut,
+    with_meta: bool,
+) -> io::Result<(usize, usize, SocketAddr, RecvMeta)> {
+    std::future::poll_fn(|cx| {
+        for offset in 0..sockets.len() {
+            let index = (*next + offset) % sockets.len();
+            let socket = &sockets[index].1;
+            while let Poll::Ready(ready) = socket.poll_recv_ready(cx) {
+                ready?;
+                let received = if with_meta {
+                    sys::try_recv_with_meta(socket, buf)
+                } else {
+                    socket
+                        .try_recv_buf_from(buf)
+                        .map(|(len, peer_addr)| (len, peer_addr, RecvMeta::default()))
+                };
+                match received {
+                    Ok((len, peer_addr, meta)) => {
+                        *next = (index + 1) % sockets.len();
+                        return Poll::Ready(Ok((index, len, peer_addr, meta)));
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                    // An ICMP error caused by an earlier send to some peer,
+                    // reported by Windows on the shared socket.
+                    Err(err) if is_unreachable_error(&err) => {
+                        log::debug!("ignoring {:?} on listening socket", err);
+                        continue;
+                    }
+                    Err(err) => return Poll::Ready(Err(err)),
+                }
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+/// An I/O object representing a UDP socket listening for incoming connections.
+///
+/// This object can be converted into a stream of incoming connections for
+/// various forms of processing.
+///
+/// Cloning a listener returns another handle to the same sockets and receive
+/// loop, which stops once every clone is dropped. Clones may accept
+/// concurrently, each stream being handed to exactly one of them. Dropping
+/// the last clone closes the streams accepted from it, which read EOF after
+/// their queued datagrams.
+///
+/// # Ordering
+///
+/// UDP does not preserve order on the network, but the listener does not
+/// add reordering of its own: a stream reads the datagrams of its peer in
+/// the order the listening socket received them. Datagrams may be dropped,
+/// as counted in [`ListenerStats`], never overtaken. This holds with
+/// [priorities](UdpStream::set_priority), which only reorder datagrams of
+/// different peers, with a
+/// [delivery interval](UdpListenerBuilder::delivery_interval), and with
+/// several [demux workers](UdpListenerBuilder::demux_workers), which serve
+/// a peer from a single worker. Datagrams a peer sends to different local
+/// addresses of a listener bound with [`bind_multi`](Self::bind_multi)
+/// arrive on different sockets and have no defined order between them.
+///
+/// # Examples
+///
+/// ```no_run
+/// use udp_stream::UdpListener;
+///
+/// use std::{io, net::SocketAddr, error::Error, str::FromStr};
+/// # async fn process_socket<T>(_socket: T) {}
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), Box<dyn Error>> {
+///     let mut listener = UdpListener::bind(SocketAddr::from_str("127.0.0.1:8080")?).await?;
+///
+///     loop {
+///         let (socket, _) = listener.accept().await?;
+///         process_socket(socket).await;
+///     }
+/// }
+/// ```
+#[derive(Clone)]
+pub struct UdpListener {
+    handler: Option<Arc<ListenerTask>>,
+    receiver: Arc<Mutex<mpsc::Receiver<(UdpStream, SocketAddr)>>>,
+    backlog: mpsc::WeakSender<(UdpStream, SocketAddr)>,
+    inject: mpsc::Sender<(Datagram, SocketAddr)>,
+    control: mpsc::Sender<Control>,
+    sockets: Vec<Arc<UdpSocket>>,
+    meta: MetaOptions,
+    pool_counters: Option<Arc<PoolCounters>>,
+    shared: Arc<ListenerShared>,
+    config: ListenerConfig,
+    local_addr: SocketAddr,
+    local_addrs: Vec<SocketAddr>,
+    /// The receive buffer size of the sockets, see [`udp_buffer_size`].
+    buffer_size: usize,
+    shutdown: Arc<watch::Sender<bool>>,
+}
+
+/// The receive loop of a listener, one task per demux worker, aborted when
+/// the last clone is dropped.
+struct ListenerTask {
+    workers: Vec<tokio::task::AbortHandle>,
+    shared: Arc<ListenerShared>,
+}
+
+impl ListenerTask {
+    /// Returns whether a worker has ended.
+    fn is_finished(&self) -> bool {
+        self.workers
+            .iter()
+            .any(tokio::task::AbortHandle::is_finished)
+    }
+
+    fn abort(&self) {
+        for worker in &self.workers {
+            worker.abort();
+        }
+    }
+}
+
+impl Drop for ListenerTask {
+    fn drop(&mut self) {
+        self.abort();
+        // An aborted worker only drops the peers table the next time the
+        // runtime polls it, which may be never if the runtime is shutting
+        // down or blocked, so the held streams are closed here to read EOF.
+        drop(self.shared.streams.drain());
+    }
+}
+
+/// Exposes the file descriptor of the listener's first socket.
+///
+/// The receive loop keeps polling the socket, so reading from the descriptor
+/// elsewhere races with it and steals datagrams from the streams; registering
+/// it with another event loop may also conflict with tokio's registration.
+/// It is best suited to setting socket options or to listeners bound in
+/// [`stateless`](UdpListenerBuilder::stateless) mode.
+#[cfg(unix)]
+impl std::os::fd::AsRawFd for UdpListener {
+    fn as_raw_fd(&self) -> std::os::fd::RawFd {
+        std::os::fd::AsRawFd::as_raw_fd(&*self.sockets[0])
+    }
+}
+
+/// Exposes the socket handle of the listener's first socket.
+///
+/// The same hazards as for `AsRawFd` on Unix apply.
+#[cfg(windows)]
+impl std::os::windows::io::AsRawSocket for UdpListener {
+    fn as_raw_socket(&self) -> std::os::windows::io::RawSocket {
+        std::os::windows::io::AsRawSocket::as_raw_socket(&*self.sockets[0])
+    }
+}
+
+impl UdpListener {
+    /// Creates a new `UdpListener` bound to the specified address.
+    ///
+    /// The receive buffer size defaults to 17480 bytes and can be overridden
+    /// through the `UDP_STREAM_BUFFER_SIZE` environment variable, which is read
+    /// once, when the first socket is set up.
+    ///
+    /// A link-local IPv6 address must carry the scope id of its interface, as
+    /// in `[fe80::1%2]:8080`. `SocketAddr` only parses numeric scope ids, which
+    /// can be looked up with `if_nametoindex`. The scope id is kept in
+    /// [`local_addr`](Self::local_addr) and in the peer address of every
+    /// accepted stream, so replies leave through the same interface.
+    pub async fn bind(local_addr: SocketAddr) -> io::Result<Self> {
+        UdpListenerBuilder::new().bind(local_addr).await
+    }
+
+    /// Creates a new `UdpListener` bound to a port of `ip` picked by the
+    /// operating system, for servers whose clients learn the address at run
+    /// time, such as in tests.
+    ///
+    /// [`local_addr`](Self::local_addr) reports the port that was picked, never
+    /// port 0.
+    pub async fn bind_ephemeral(ip: IpAddr) -> io::Result<Self> {
+        UdpListenerBuilder::new().bind_ephemeral(ip).await
+    }
+
+    /// Creates a new `UdpListener` bound to the specified address whose
+    /// background task is spawned on `handle` instead of the ambient runtime.
+    ///
+    /// This does not require being called from within a runtime, which makes it
+    /// suitable for embedding scenarios where the runtime is owned elsewhere.
+    pub fn bind_on(handle: &Handle, local_addr: SocketAddr) -> io::Result<Self> {
+        UdpListenerBuilder::new().bind_on(handle, local_addr)
+    }
+
+    /// Creates a new `UdpListener` bound to the specified address, retrying up
+    /// to `attempts` times while binding fails with `AddrInUse` or
+    /// `AddrNotAvailable`.
+    ///
+    /// The delay between attempts starts at `backoff` and doubles after every
+    /// failure. The last error is returned once the attempts are exhausted.
+    pub async fn bind_retry(
+        local_addr: SocketAddr,
+        attempts: usize,
+        backoff: Duration,
+    ) -> io::Result<Self> {
+        UdpListenerBuilder::new()
+            .bind_retry(local_addr, attempts, backoff)
+            .await
+    }
+
+    /// Creates a new `UdpListener` bound to every address in `addrs`.
+    ///
+    /// Datagrams received on any of the sockets are merged into a single
+    /// stream of incoming connections. Each accepted `UdpStream` replies
+    /// through the socket its first datagram arrived on. A peer is tracked by
+    /// its address only, so a peer sending to several of the bound addresses is
+    /// associated with the first one it used.
+    pub async fn bind_multi(addrs: &[SocketAddr]) -> io::Result<Self> {
+        UdpListenerBuilder::new().bind_multi(addrs).await
+    }
+
+    /// Creates a new `UdpListener` from a bound `std::net::UdpSocket`.
+    ///
+    /// The socket is switched to non-blocking mode, and an error is returned if
+    /// that fails. Like `tokio::net::UdpSocket::from_std`, this must be called
+    /// from within a tokio runtime.
+    pub fn from_std(socket: std::net::UdpSocket) -> io::Result<Self> {
+        UdpListenerBuilder::new().from_std(socket)
+    }
+
+    /// Returns a builder for configuring a listener before binding it.
+    pub fn builder() -> UdpListenerBuilder {
+        UdpListenerBuilder::new()
+    }
+
+    pub(crate) fn from_sockets(
+        udp_sockets: Vec<UdpSocket>,
+        handle: &Handle,
+        config: ListenerConfig,
+    ) -> io::Result<Self> {
+        let runtime = if config.dedicated_thread && !config.stateless {
+            Some(
+                tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()?,
+            )
+        } else {
+            None
+        };
+        // The sockets are registered with the runtime polling them, so that
+        // their readiness does not depend on the application runtime.
+        let udp_sockets = match &runtime {
+            Some(runtime) => {
+                let _guard = runtime.enter();
+                udp_sockets
+                    .into_iter()
+                    .map(|socket| UdpSocket::from_std(socket.into_std()?))
+                    .collect::<io::Result<Vec<_>>>()?
+            }
+            None => udp_sockets,
+        };
+        let sockets = udp_sockets
+            .into_iter()
+            .map(|socket| Ok((socket.local_addr()?, Arc::new(socket))))
+            .collect::<io::Result<Vec<_>>>()?;
+        Self::start(sockets, runtime, handle, config)
+    }
+
+    /// Spawns a receive loop on `sockets`, on `runtime` if given and on
+    /// `handle` otherwise.
+    fn start(
+        sockets: Vec<(SocketAddr, Arc<UdpSocket>)>,
+        runtime: Option<tokio::runtime::Runtime>,
+        handle: &Handle,
+        config: ListenerConfig,
+    ) -> io::Result<Self> {
+        let (tx, rx) = mpsc::channel(config.accept_backlog);
+        let backlog = tx.downgrade();
+        let (inject_tx, inject_rx) = mpsc::channel(CHANNEL_LEN);
+        let (control_tx, control_rx) = mpsc::channel(1);
+        let meta = config.meta_options();
+        for (_, socket) in &sockets {
+            meta.enable(socket)?;
+        }
+        let local_addrs: Vec<SocketAddr> = sockets.iter().map(|(addr, _)| *addr).collect();
+        let listener_sockets = sockets.iter().map(|(_, socket)| socket.clone()).collect();
+        let buffer_size = udp_buffer_size();
+        let shared = Arc::new(ListenerShared::new(
+            config.peer_hasher,
+            config.expected_peers,
+        ));
+        let pool_counters = config
+            .buffer_pool
+            .map(|_| Arc::new(PoolCounters::default()));
+
+        // Each worker receives on every `workers`-th socket. Only the first
+        // one handles injected datagrams and control messages; the channels
+        // of the others are closed from the start.
+        let workers = config.demux_workers.clamp(1, sockets.len());
+        let mut worker_sockets: Vec<Vec<_>> = vec![Vec::new(); workers];
+        for (index, socket) in sockets.into_iter().enumerate() {
+            worker_sockets[index % workers].push(socket);
+        }
+        let mut inject_rx = Some(inject_rx);
+        let mut control_rx = Some(control_rx);
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let runs: Vec<_> = worker_sockets
+            .into_iter()
+            .map(|sockets| {
+                // Streams notify their drop without waiting and a notification
+                // sent to a full channel is lost, so it has room for bursts of
+                // drops. Each worker has its own, for the streams it created.
+                let (drop_tx, drop_rx) = mpsc::channel(CHANNEL_LEN);
+                let inject_rx = inject_rx.take().unwrap_or_else(|| mpsc::channel(1).1);
+                let control_rx = control_rx.take().unwrap_or_else(|| mpsc::channel(1).1);
+                let pool = config
+                    .buffer_pool
+                    .zip(pool_counters.clone())
+                    .map(|(slabs, counters)| BufferPool::new(slabs, buffer_size * 3, counters));
+                let demux = Demux {
+                    shared: shared.clone(),
+                    accept_tx: tx.clone(),
+                    drop_tx,
+                    config: config.clone(),
+                    shutdown: shutdown_rx.clone(),
+                    draining: false,
+                    kernel_drops: vec![0; sockets.len()],
+                    blocked: Vec::new(),
+                    sockets,
+                };
+                demux.run(drop_rx, inject_rx, control_rx, buffer_size, pool)
+            })
+            .collect();
+        drop(tx);
+        drop(shutdown_rx);
+
+        let handler = match runtime {
+            _ if config.stateless => None,
+            Some(runtime) => {
+                let handlers: Vec<_> = runs.into_iter().map(|run| runtime.spawn(run)).collect();
+                let aborts = handlers
+                    .iter()
+                    .map(|handler| handler.abort_handle())
+                    .collect();
+                std::thread::Builder::new()
+                    .name("udp-stream-listener".into())
+                    .spawn(move || {
+                        // Returns once the loop is aborted, shutting the
+                        // runtime down with the thread.
+                        runtime.block_on(async {
+                            for handler in handlers {
+                                let _ = handler.await;
+                            }
+                        });
+                    })?;
+                Some(aborts)
+            }
+            None => Some(
+                runs.into_iter()
+                    .map(|run| handle.spawn(run).abort_handle())
+                    .collect(),
+            ),
+        }
+        .map(|workers| {
+            Arc::new(ListenerTask {
+                workers,
+                shared: shared.clone(),
+            })
+        });
+        Ok(Self {
+            handler,
+            receiver: Arc::new(Mutex::new(rx)),
+            backlog,
+            inject: inject_tx,
+            control: control_tx,
+            sockets: listener_sockets,
+            meta,
+            pool_counters,
+            shared,
+            config,
+            local_addr: local_addrs[0],
+            local_addrs,
+            shutdown: Arc::new(shutdown),
+            buffer_size,
+        })
+    }
+
+    /// Restarts the receive loop if it has ended, for instance because a
+    /// callback such as a [tap](UdpListenerBuilder::tap) panicked, so that the
+    /// listener accepts connections again.
+    ///
+    /// The loop is restarted on the listener's current sockets, with the
+    /// current runtime, and starts with no tracked peers: the streams accepted
+    /// before no longer receive datagrams and read EOF, and their peers are
+    /// announced as new connections. Counters and connections waiting to be
+    /// accepted are reset too, and other clones of the listener keep the ended
+    /// loop. Does nothing while the loop is running or in
+    /// [stateless](UdpListenerBuilder::stateless) mode.
+    ///
+    /// Listeners running their loop on a
+    /// [dedicated thread](UdpListenerBuilder::dedicated_thread) cannot be
+    /// restarted, as their sockets are gone with that thread's runtime: this
+    /// fails with `Unsupported`.
+    pub fn restart(&mut self) -> io::Result<()> {
+        match &self.handler {
+            Some(handler) if handler.is_finished() => {}
+            _ => return Ok(()),
+        }
+        if self.config.dedicated_thread {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "a listener running on a dedicated thread cannot be restarted",
+            ));
+        }
+        let handle = Handle::try_current().map_err(io::Error::other)?;
+        let sockets = self
+            .local_addrs
+            .iter()
+            .copied()
+            .zip(self.sockets.iter().cloned())
+            .collect();
+        *self = Self::start(sockets, None, &handle, self.config.clone())?;
+        Ok(())
+    }
+
+    /// Shuts the listener down gracefully, taking at most about `timeout`.
+    ///
+    /// New peers are no longer accepted, the datagrams still arriving are
+    /// delivered to their existing streams until the sockets stay quiet for
+    /// 10 milliseconds, and those held back
+    /// by a [delivery interval](UdpListenerBuilder::delivery_interval) are
+    /// flushed. Once that is done, or when `timeout` passes, the receive loop
+    /// is stopped and every peer untracked: the streams read EOF after their
+    /// queued datagrams, including those of connections not accepted yet.
+    /// This also shuts down every clone of the listener.
+    pub async fn shutdown_timeout(self, timeout: Duration) {
+        let _ = self.shutdown.send(true);
+        if tokio::time::timeout(timeout, self.shutdown.closed())
+            .await
+            .is_err()
+        {
+            log::debug!("listener not drained within {:?}, stopping it", timeout);
+        }
+        if let Some(handler) = &self.handler {
+            handler.abort();
+        }
+        drop(self.shared.streams.drain());
+    }
+
+    /// Hands a datagram to the receive loop as if it had arrived from
+    /// `peer_addr` on the first socket.
+    pub(crate) async fn inject(&self, datagram: Datagram, peer_addr: SocketAddr) -> io::Result<()> {
+        self.inject
+            .send((datagram, peer_addr))
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+
+    ///Returns the local address that this socket is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    /// Binds a new socket to `local_addr` and moves the receive loop to it,
+    /// replacing every socket the listener was bound to.
+    ///
+    /// Tracked peers and accepted streams are kept: datagrams they send to the
+    /// new address are delivered to their existing streams. Streams keep
+    /// writing through the old socket though, so their replies still come from
+    /// the old address, and datagrams still in flight to the old address are
+    /// lost. Socket options such as [`set_dontfrag`](Self::set_dontfrag) must be
+    /// applied again. Clones of the listener keep reporting, and applying
+    /// options to, the old sockets.
+    ///
+    /// Listeners with several [demux workers](UdpListenerBuilder::demux_workers)
+    /// cannot be rebound: this fails with `Unsupported`.
+    pub async fn rebind(&mut self, local_addr: SocketAddr) -> io::Result<()> {
+        if self
+            .handler
+            .as_ref()
+            .is_some_and(|handler| handler.workers.len() > 1)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "a listener with several demux workers cannot be rebound",
+            ));
+        }
+        let socket = UdpSocket::bind(local_addr).await?;
+        self.meta.enable(&socket)?;
+        let local_addr = socket.local_addr()?;
+        let socket = Arc::new(socket);
+        if self.handler.is_some() {
+            self.control
+                .send(Control::Rebind(vec![(local_addr, socket.clone())]))
+                .await
+                .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        }
+        self.sockets = vec![socket];
+        self.local_addr = local_addr;
+        self.local_addrs = vec![local_addr];
+        Ok(())
+    }
+
+    /// Returns every local address this listener is bound to, in the order
+    /// they were passed to [`bind_multi`](Self::bind_multi).
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// Returns the buffer pool counters, or `None` if the listener was not
+    /// configured with [`UdpListenerBuilder::buffer_pool`].
+    pub fn pool_stats(&self) -> Option<PoolStats> {
+        self.pool_counters
+            .as_ref()
+            .map(|counters| counters.snapshot())
+    }
+
+    /// Returns the number of new connections waiting to be returned by
+    /// [`accept`](Self::accept).
+    ///
+    /// Once it reaches the limit set with
+    /// [`UdpListenerBuilder::accept_backlog`], new peers are dropped and
+    /// counted in [`ListenerStats::dropped_accept_full`].
+    pub fn backlog_len(&self) -> usize {
+        self.backlog
+            .upgrade()
+            .map_or(0, |tx| tx.max_capacity() - tx.capacity())
+    }
+
+    /// Returns a snapshot of the listener's counters.
+    pub fn stats(&self) -> ListenerStats {
+        let peers = self.shared.streams.len();
+        self.shared.counters.snapshot(peers)
+    }
+
+    /// Returns whether the listener currently tracks a stream for `addr`.
+    ///
+    /// A peer is tracked from its first datagram, before its stream is
+    /// accepted, until the stream is dropped or the peer is otherwise
+    /// removed. Dropping a stream is noticed by the receive loop
+    /// asynchronously, so the peer may still be reported for a short while.
+    /// With [`canonical_addrs`](UdpListenerBuilder::canonical_addrs) enabled,
+    /// IPv4-mapped addresses are looked up as their IPv4 form.
+    pub fn contains_peer(&self, addr: &SocketAddr) -> bool {
+        let addr = self.peer_key(*addr);
+        self.shared.streams.contains(&addr)
+    }
+
+    /// Drops the datagrams of the peer `addr` from now on, without ending
+    /// its session, and returns whether the peer is tracked.
+    ///
+    /// Muting is applied atomically with the receive loop: every datagram it
+    /// handles after this returns is dropped, and none before. Dropped
+    /// datagrams are counted in [`ListenerStats::dropped_muted`] and do not
+    /// count as activity of the peer. Its stream stays open: queued
+    /// datagrams can still be read and writes to the peer still work.
+    /// Addresses are looked up as with [`contains_peer`](Self::contains_peer).
+    pub fn mute(&self, addr: &SocketAddr) -> bool {
+        self.set_muted(addr, true)
+    }
+
+    /// Delivers the datagrams of the peer `addr` to its stream again after
+    /// [`mute`](Self::mute), and returns whether the peer is tracked.
+    pub fn unmute(&self, addr: &SocketAddr) -> bool {
+        self.set_muted(addr, false)
+    }
+
+    fn set_muted(&self, addr: &SocketAddr, muted: bool) -> bool {
+        let addr = self.peer_key(*addr);
+        match self.shared.streams.shard(&addr).get_mut(&addr) {
+            Some(peer) => {
+                peer.muted = muted;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the key the peer at `addr` is tracked under.
+    fn peer_key(&self, addr: SocketAddr) -> SocketAddr {
+        if self.config.canonical_addrs {
+            canonical_addr(addr)
+        } else {
+            addr
+        }
+    }
+
+    /// Waits until every tracked peer has no datagram left queued for its
+    /// stream, failing with `TimedOut` if that does not happen within
+    /// `timeout`.
+    ///
+    /// Datagrams held back by a
+    /// [delivery interval](UdpListenerBuilder::delivery_interval) count as
+    /// queued. The queues are checked every millisecond, so this only tells
+    /// that the streams consumed what had been dispatched to them: datagrams
+    /// still waiting on the sockets, or arriving afterwards, are not accounted
+    /// for. Peers whose stream was not accepted yet keep their queue until it
+    /// is, and so does a stream not being read.
+    pub async fn quiesce(&self, timeout: Duration) -> io::Result<()> {
+        let drained = async {
+            let mut poll = tokio::time::interval(QUIESCE_POLL);
+            loop {
+                poll.tick().await;
+                if self.shared.streams.shards().all(|streams| {
+                    streams.values().all(|peer| {
+                        peer.pending.is_empty()
+                            && peer.sender.capacity() == peer.sender.max_capacity()
+                            && peer.state.grown.lock().unwrap().is_none()
+                    })
+                }) {
+                    return;
+                }
+            }
+        };
+        tokio::time::timeout(timeout, drained)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "peer queues not drained"))
+    }
+
+    /// Sends `buf` as a datagram to every peer currently tracked by the
+    /// listener, returning how many sends succeeded.
+    ///
+    /// A failed send to one peer is logged and does not abort the broadcast.
+    pub async fn broadcast(&self, buf: &[u8]) -> io::Result<usize> {
+        let peers: Vec<(SocketAddr, Arc<UdpSocket>)> = self
+            .shared
+            .streams
+            .shards()
+            .flat_map(|streams| {
+                streams
+                    .values()
+                    .map(|peer| (peer.wire_addr, peer.socket.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        let mut sent = 0;
+        for (peer_addr, socket) in peers {
+            match socket.send_to(buf, peer_addr).await {
+                Ok(_) => sent += 1,
+                Err(err) => log::debug!("broadcast to {} failed: {:?}", peer_addr, err),
+            }
+        }
+        Ok(sent)
+    }
+
+    /// Sets or clears the don't-fragment bit on every socket of the listener,
+    /// which also applies to the streams it accepts.
+    ///
+    /// See [`UdpStream::set_dontfrag`].
+    pub fn set_dontfrag(&self, dontfrag: bool) -> io::Result<()> {
+        for socket in &self.sockets {
+            sys::set_dontfrag(socket, dontfrag)?;
+        }
+        Ok(())
+    }
+
+    /// Sets the interface, by its address, that outgoing IPv4 multicast
+    /// datagrams are sent from, on a multi-homed host.
+    ///
+    /// This sets `IP_MULTICAST_IF` on every socket of the listener and is
+    /// only available on Unix platforms.
+    pub fn set_multicast_if_v4(&self, interface: Ipv4Addr) -> io::Result<()> {
+        for socket in &self.sockets {
+            sys::set_multicast_if_v4(socket, interface)?;
+        }
+        Ok(())
+    }
+
+    /// Sets the interface, by its index, that outgoing IPv6 multicast
+    /// datagrams are sent from.
+    ///
+    /// This sets `IPV6_MULTICAST_IF` on every socket of the listener and is
+    /// only available on Unix platforms.
+    pub fn set_multicast_if_v6(&self, interface: u32) -> io::Result<()> {
+        for socket in &self.sockets {
+            sys::set_multicast_if_v6(socket, interface)?;
+        }
+        Ok(())
+    }
+
+    /// Sets whether outgoing IPv4 multicast datagrams are looped back to the
+    /// local host.
+    ///
+    /// See [`UdpSocket::set_multicast_loop_v4`].
+    pub fn set_multicast_loop_v4(&self, on: bool) -> io::Result<()> {
+        for socket in &self.sockets {
+            socket.set_multicast_loop_v4(on)?;
+        }
+        Ok(())
+    }
+
+    /// Sets whether outgoing IPv6 multicast datagrams are looped back to the
+    /// local host.
+    ///
+    /// See [`UdpSocket::set_multicast_loop_v6`].
+    pub fn set_multicast_loop_v6(&self, on: bool) -> io::Result<()> {
+        for socket in &self.sockets {
+            socket.set_multicast_loop_v6(on)?;
+        }
+        Ok(())
+    }
+
+    /// Sets the time-to-live of outgoing IPv4 multicast datagrams.
+    ///
+    /// See [`UdpSocket::set_multicast_ttl_v4`].
+    pub fn set_multicast_ttl_v4(&self, ttl: u32) -> io::Result<()> {
+        for socket in &self.sockets {
+            socket.set_multicast_ttl_v4(ttl)?;
+        }
+        Ok(())
+    }
+
+    /// Attempts to receive a datagram on a listener bound in
+    /// [`stateless`](UdpListenerBuilder::stateless) mode, returning its source
+    /// address.
+    ///
+    /// The datagram is read straight from the socket without creating or
+    /// looking up a stream. If `buf` is too small, the excess bytes of the
+    /// datagram are discarded. With several sockets bound, each is polled in
+    /// turn. Listeners in stream mode return an `Unsupported` error, as their
+    /// receive loop owns the sockets.
+    pub fn poll_recv_from(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<SocketAddr>> {
+        if self.handler.is_some() {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "listener is not stateless",
+            )));
+        }
+        for socket in &self.sockets {
+            if let Poll::Ready(result) = socket.poll_recv_from(cx, buf) {
+                return Poll::Ready(result);
+            }
+        }
+        Poll::Pending
+    }
+
+    /// Returns the next datagram waiting on the listener's sockets along with
+    /// its source, without consuming it.
+    ///
+    /// This uses `MSG_PEEK`, so the datagram is still received afterwards,
+    /// which lets an application inspect it before deciding how to proceed.
+    /// It is meant for listeners bound in
+    /// [`stateless`](UdpListenerBuilder::stateless) mode, where the next
+    /// [`poll_recv_from`](Self::poll_recv_from) returns the same datagram. In
+    /// stream mode the receive loop consumes datagrams concurrently, so the
+    /// peeked datagram may already be on its way to a stream.
+    pub async fn peek_next(&self) -> io::Result<(Bytes, SocketAddr)> {
+        let mut buf = vec![0u8; self.buffer_size];
+        let mut buf = ReadBuf::new(&mut buf);
+        let peer_addr = std::future::poll_fn(|cx| {
+            for socket in &self.sockets {
+                if let Poll::Ready(result) = socket.poll_peek_from(cx, &mut buf) {
+                    return Poll::Ready(result);
+                }
+            }
+            Poll::Pending
+        })
+        .await?;
+        Ok((Bytes::copy_from_slice(buf.filled()), peer_addr))
+    }
+
+    /// Accepts a new incoming UDP connection.
+    ///
+    /// The datagram that revealed the peer is queued on the stream before the
+    /// stream is announced, so it is always the first datagram read from the
+    /// accepted stream, however long the stream waited to be accepted. Later
+    /// datagrams from the peer are queued behind it meanwhile, up to the
+    /// [channel capacity](UdpListenerBuilder::channel_capacity), unless the
+    /// listener [accepts lazily](UdpListenerBuilder::lazy_accept).
+    ///
+    /// # Cancel safety
+    ///
+    /// This method is cancel safe. If the future is dropped before it
+    /// completes, no connection has been taken from the queue and the next call
+    /// to `accept` returns it.
+    pub async fn accept(&self) -> io::Result<(UdpStream, SocketAddr)> {
+        let (stream, peer_addr) = self
+            .receiver
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or(io::Error::from(io::ErrorKind::BrokenPipe))?;
+        stream.state.accepted.store(true, Ordering::Release);
+        Ok((stream, peer_addr))
+    }
+
+    /// Accepts a new incoming UDP connection along with the datagram that
+    /// revealed the peer.
+    ///
+    /// The datagram is taken out of the stream, so reading the stream returns
+    /// the second datagram of the peer first. It is counted as read in
+    /// [`UdpStream::stats`]. With [`ZeroLengthPolicy::Deliver`] it may be
+    /// empty.
+    ///
+    /// # Cancel safety
+    ///
+    /// This method is cancel safe, as [`accept`](Self::accept) is.
+    pub async fn accept_with_first(&self) -> io::Result<(UdpStream, SocketAddr, Bytes)> {
+        let (mut stream, peer_addr) = self.accept().await?;
+        let Incoming::Channel(receiver) = &mut stream.incoming else {
+            unreachable!("accepted streams read from a channel");
+        };
+        let datagram = receiver
+            .get_mut()
+            .try_recv()
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        stream.counters.on_recv(datagram.payload.len());
+        Ok((stream, peer_addr, datagram.payload))
+    }
+}
+
+/// Converts an IPv4-mapped IPv6 address, as reported by dual-stack sockets
+/// for IPv4 peers, to the plain IPv4 address.
+fn canonical_addr(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(IpAddr::V4(ip), v6.port()),
+            None => addr,
+        },
+        SocketAddr::V4(_) => addr,
+    }
+}
+
+/// Returns the unspecified address of the same family as `addr` with the
+/// given port.
+fn unspecified_addr(addr: &SocketAddr, port: u16) -> SocketAddr {
+    if addr.is_ipv4() {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port)
+    } else {
+        SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port)
+    }
+}
+
+/// Explains a failure to bind the local socket of a client stream when it is
+/// caused by the address family of `addr` being unavailable, as for an IPv6
+/// peer on a host with IPv6 disabled.
+fn family_unavailable(err: io::Error, addr: &SocketAddr) -> io::Error {
+    #[cfg(unix)]
+    let unsupported = err.raw_os_error() == Some(libc::EAFNOSUPPORT);
+    #[cfg(not(unix))]
+    let unsupported = false;
+    if unsupported || err.kind() == io::ErrorKind::AddrNotAvailable {
+        let family = if addr.is_ipv4() { "IPv4" } else { "IPv6" };
+        return io::Error::new(
+            err.kind(),
+            format!("{} is not available on this host: {}", family, err),
+        );
+    }
+    err
+}
+
+/// Where a `UdpStream` takes its incoming datagrams from.
+#[derive(Debug)]
+enum Incoming {
+    /// Datagrams are forwarded by a background task through a channel.
+    ///
+    /// The mutex only serializes the `&self` receive methods: `poll_read` has
+    /// exclusive access and reads the channel without locking, so a task
+    /// cancelled while receiving can never leave a reader waiting.
+    Channel(Mutex<mpsc::Receiver<Datagram>>),
+    /// Datagrams are read straight from the socket, using the buffer as
+    /// scratch space.
+    Direct(BytesMut),
+}
 
-/// An I/O object representing a UDP socket listening for incoming connections.
-///
-/// This object can be converted into a stream of incoming connections for
-/// various forms of processing.
+/// An I/O object representing a UDP stream connected to a remote endpoint.
 ///
-/// # Examples
+/// A UDP stream can either be created by connecting to an endpoint, via the
+/// [`connect`] method, or by [accepting] a connection from a [listener].
 ///
-/// ```no_run
-/// use udp_stream::UdpListener;
+/// Through `AsyncRead` the stream behaves like a byte stream: reading zero
+/// bytes means the stream was closed (EOF), so zero-length datagrams are
+/// never surfaced there. The message API, [`recv`], delivers them as empty
+/// `Bytes` instead and reports a closed stream as a `BrokenPipe` error, which
+/// keeps the two cases distinct.
 ///
-/// use std::{io, net::SocketAddr, error::Error, str::FromStr};
-/// # async fn process_socket<T>(_socket: T) {}
+/// `poll_read` either copies a datagram into the caller's buffer or keeps the
+/// part that did not fit for the next read, so no data is lost when a read
+/// future is dropped.
 ///
-/// #[tokio::main]
-/// async fn main() -> Result<(), Box<dyn Error>> {
-///     let mut listener = UdpListener::bind(SocketAddr::from_str("127.0.0.1:8080")?).await?;
+/// `poll_read` takes the stream mutably, so it polls the queue or socket
+/// directly, without locking or allocating a future per call. A reader
+/// waiting on an empty stream is registered with the queue or socket and
+/// woken as soon as a datagram arrives.
 ///
-///     loop {
-///         let (socket, _) = listener.accept().await?;
-///         process_socket(socket).await;
-///     }
-/// }
-/// ```
-pub struct UdpListener {
-    handler: tokio::task::JoinHandle<()>,
-    receiver: Arc<Mutex<mpsc::Receiver<(UdpStream, SocketAddr)>>>,
+/// [`connect`]: struct.UdpStream.html#method.connect
+/// [`recv`]: struct.UdpStream.html#method.recv
+/// [accepting]: struct.UdpListener.html#method.accept
+/// [listener]: struct.UdpListener.html
+#[derive(Debug)]
+pub struct UdpStream {
     local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+    /// The address writes are sent to, see [`Peer::wire_addr`], unless the
+    /// peer moved, see [`UdpStream::target_addr`].
+    wire_addr: SocketAddr,
+    incoming: Incoming,
+    socket: Arc<tokio::net::UdpSocket>,
+    handler: Option<tokio::task::JoinHandle<()>>,
+    drop: Option<mpsc::Sender<SocketAddr>>,
+    remaining: Option<Bytes>,
+    state: Arc<PeerState>,
+    read_timeout: Option<Duration>,
+    read_deadline: Option<Pin<Box<Sleep>>>,
+    write_timeout: Option<Duration>,
+    write_deadline: Option<Pin<Box<Sleep>>>,
+    connected: bool,
+    read_shutdown: bool,
+    rtt: Option<RttTracker>,
+    sequence: Option<SequenceTracker>,
+    nodelay: bool,
+    /// Writes held back until the next flush when `nodelay` is unset. Only
+    /// locked by [`shutdown`](UdpStream::shutdown) and the sends taking
+    /// `&self`.
+    write_queue: std::sync::Mutex<VecDeque<Bytes>>,
+    /// Sent to the peer once, when the stream is shut down or dropped.
+    shutdown_payload: Option<Bytes>,
+    codec: Option<SharedCodec>,
+    send_rate: Option<SendRate>,
+    counters: StreamCounters,
+    rate_delay: Option<Pin<Box<Sleep>>>,
+    /// Receives the writes of a mock stream instead of the socket.
+    #[cfg(feature = "test-util")]
+    sink: Option<mpsc::UnboundedSender<Bytes>>,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
 }
 
-impl Drop for UdpListener {
+impl Drop for UdpStream {
     fn drop(&mut self) {
-        self.handler.abort();
+        if let Some(handler) = &self.handler {
+            handler.abort()
+        }
+
+        // Closing the queue first keeps the listener from moving the stream to
+        // a larger one that nobody would drain.
+        if let Incoming::Channel(receiver) = &mut self.incoming {
+            receiver.get_mut().close();
+            self.state.grown.lock().unwrap().take();
+        }
+
+        // Queued writes and the shutdown payload are sent on a best-effort
+        // basis.
+        self.try_send_queue_now();
+        if let Some(payload) = self.shutdown_payload.take() {
+            let _ = self.try_send_now(&payload);
+        }
+
+        // An expired session is already gone, and the peer may have started a
+        // new one since.
+        if let Some(drop) = self.drop.as_ref().filter(|_| !self.state.is_expired()) {
+            let _ = drop.try_send(self.peer_addr);
+        };
+    }
+}
+
+impl UdpStream {
+    fn new(
+        local_addr: SocketAddr,
+        peer_addr: SocketAddr,
+        incoming: Incoming,
+        socket: Arc<UdpSocket>,
+        state: Arc<PeerState>,
+    ) -> Self {
+        Self {
+            local_addr,
+            peer_addr,
+            wire_addr: peer_addr,
+            incoming,
+            socket,
+            handler: None,
+            drop: None,
+            remaining: None,
+            state,
+            read_timeout: None,
+            read_deadline: None,
+            write_timeout: None,
+            write_deadline: None,
+            connected: false,
+            read_shutdown: false,
+            rtt: None,
+            sequence: None,
+            nodelay: true,
+            write_queue: Default::default(),
+            shutdown_payload: None,
+            codec: None,
+            send_rate: None,
+            counters: StreamCounters::default(),
+            rate_delay: None,
+            #[cfg(feature = "test-util")]
+            sink: None,
+            #[cfg(feature = "tracing")]
+            span: tracing::Span::none(),
+        }
+    }
+    /// Create a new UDP stream connected to the specified address.
+    ///
+    /// This function will create a new UDP socket and attempt to connect it to
+    /// the `addr` provided. The returned future will be resolved once the
+    /// stream has successfully connected, or it will return an error if one
+    /// occurs. If the host cannot bind a socket of the address family of
+    /// `addr`, typically an IPv6 peer with IPv6 disabled, the error says so.
+    ///
+    /// Addresses a UDP socket cannot be connected to fail with an
+    /// [`InvalidPeer`] error. A stream connected to a multicast or broadcast
+    /// address, where the kernel allows it, can send but never reads
+    /// anything, as replies come from unicast addresses.
+    pub async fn connect(addr: SocketAddr) -> Result<Self, tokio::io::Error> {
+        UdpStreamBuilder::new().connect(addr).await
+    }
+    /// Create a new UDP stream connected to the specified address from the
+    /// fixed local port `local_port`.
+    ///
+    /// This fails with `AddrInUse` if the port is taken. See
+    /// [`UdpStreamBuilder::local_port`].
+    pub async fn connect_with_port(addr: SocketAddr, local_port: u16) -> io::Result<Self> {
+        UdpStreamBuilder::new()
+            .local_port(local_port)
+            .connect(addr)
+            .await
+    }
+    /// Create a new UDP stream connected to the specified address that reads
+    /// directly from its socket.
+    ///
+    /// Unlike [`connect`](Self::connect), no background task or channel is
+    /// involved: datagrams are received in `poll_read` and those not coming
+    /// from `addr` are discarded. This lowers latency and resource usage when
+    /// only a single peer is involved.
+    pub async fn connect_direct(addr: SocketAddr) -> Result<Self, tokio::io::Error> {
+        let socket = UdpSocket::bind(unspecified_addr(&addr, 0))
+            .await
+            .map_err(|err| family_unavailable(err, &addr))?;
+        connect_peer(&socket, addr).await?;
+        let local_addr = socket.local_addr()?;
+        let mut udp_stream = UdpStream::new(
+            local_addr,
+            addr,
+            Incoming::Direct(BytesMut::with_capacity(udp_buffer_size())),
+            Arc::new(socket),
+            PeerState::new(),
+        );
+        udp_stream.connected = true;
+        Ok(udp_stream)
+    }
+    /// Creates a new UdpStream from a tokio::net::UdpSocket.
+    /// This function is intended to be used to wrap a UDP socket from the tokio library.
+    /// Note: The UdpSocket must have the UdpSocket::connect method called before invoking this function.
+    pub async fn from_tokio(
+        socket: UdpSocket,
+        peer_addr: SocketAddr,
+    ) -> Result<Self, tokio::io::Error> {
+        UdpStreamBuilder::new().from_tokio(socket, peer_addr)
+    }
+
+    /// Returns a builder for configuring a client stream before connecting it.
+    pub fn builder() -> UdpStreamBuilder {
+        UdpStreamBuilder::new()
+    }
+
+    pub(crate) fn from_socket(
+        socket: UdpSocket,
+        peer_addr: SocketAddr,
+        config: StreamConfig,
+    ) -> io::Result<Self> {
+        let socket = Arc::new(socket);
+
+        let local_addr = socket.local_addr()?;
+
+        let depth = config.prefetch_depth;
+        let (child_tx, child_rx) = mpsc::channel(CHANNEL_LEN.max(depth));
+
+        let socket_inner = socket.clone();
+        let state = PeerState::new();
+        let state_inner = state.clone();
+        let codec = config.codec.clone();
+
+        let buffer_size = udp_buffer_size();
+        let handler = tokio::spawn(async move {
+            let mut buf = BytesMut::new();
+            let mut batch = Vec::with_capacity(depth);
+            'recv: loop {
+                reserve_datagram(&mut buf, buffer_size);
+                let (len, received_addr) = match socket_inner.recv_buf_from(&mut buf).await {
+                    Ok(received) => received,
+                    Err(err) if is_unreachable_error(&err) => {
+                        if child_tx
+                            .send(Datagram::unreachable(err.kind()))
+                            .await
+                            .is_err()
+                        {
+                            break;
+                        }
+                        continue;
+                    }
+                    Err(_) => break,
+                };
+                // Datagrams from other sources are taken out of the buffer
+                // all the same, so they never end up in the next one.
+                let datagram = take_datagram(&mut buf, len, received_addr);
+                if received_addr == peer_addr {
+                    batch.extend(datagram);
+                }
+                // Read ahead whatever is already waiting on the socket.
+                while batch.len() < depth {
+                    reserve_datagram(&mut buf, buffer_size);
+                    let Ok((len, received_addr)) = socket_inner.try_recv_buf_from(&mut buf) else {
+                        break;
+                    };
+                    let datagram = take_datagram(&mut buf, len, received_addr);
+                    if received_addr == peer_addr {
+                        batch.extend(datagram);
+                    }
+                }
+                if !batch.is_empty() {
+                    state_inner.touch();
+                }
+                for mut payload in batch.drain(..) {
+                    if let Some(codec) = codec.as_ref().filter(|_| !payload.is_empty()) {
+                        payload = match codec.decode(payload) {
+                            Ok(payload) => payload,
+                            Err(err) => {
+                                log::debug!("dropping datagram failing to decode: {}", err);
+                                continue;
+                            }
+                        };
+                    }
+                    if child_tx.send(payload.into()).await.is_err() {
+                        child_tx.closed().await;
+                        break 'recv;
+                    }
+                }
+            }
+            state_inner.close();
+        });
+
+        let mut udp_stream = UdpStream::new(
+            local_addr,
+            peer_addr,
+            Incoming::Channel(Mutex::new(child_rx)),
+            socket,
+            state,
+        );
+        udp_stream.handler = Some(handler);
+        udp_stream.connected = udp_stream.socket.peer_addr().is_ok();
+        udp_stream.shutdown_payload = config.shutdown_payload;
+        udp_stream.codec = config.codec;
+        Ok(udp_stream)
+    }
+
+    /// Returns the address of the peer, under which the datagrams read from
+    /// the stream are received.
+    ///
+    /// Writes go to this address too, unless the listener moved the session
+    /// to another one, see [`UdpListenerBuilder::connection_id`]. With
+    /// [`canonical_addrs`](UdpListenerBuilder::canonical_addrs), IPv4 peers
+    /// of a dual-stack listener are reported with their IPv4 address.
+    pub fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        Ok(self.peer_addr)
+    }
+    /// Sets the priority of the stream's peer in the listener's receive loop,
+    /// higher first. The default is 0.
+    ///
+    /// When datagrams pile up on the listening sockets, the loop takes up to
+    /// 32 of them at once and hands those of higher priority peers to their
+    /// streams first. This is best-effort: it does not reorder datagrams
+    /// beyond such a batch, nor across sockets read by different
+    /// [demux workers](UdpListenerBuilder::demux_workers), and a peer's
+    /// first datagram, received before its stream exists, has the default
+    /// priority. It has no effect on client streams.
+    pub fn set_priority(&self, priority: u8) {
+        self.state.priority.store(priority, Ordering::Relaxed);
+    }
+    /// Returns the priority of the stream's peer in the listener's receive
+    /// loop.
+    pub fn priority(&self) -> u8 {
+        self.state.priority.load(Ordering::Relaxed)
+    }
+    /// Returns the local address of the socket the stream sends from.
+    ///
+    /// Streams accepted by a listener share its socket, so this is the address
+    /// the listener is bound to or, with
+    /// [`bind_multi`](UdpListener::bind_multi), the one the first datagram of
+    /// the peer arrived on. A wildcard address is reported as such, see
+    /// [`recv_meta`](Self::recv_meta) for the address a datagram was sent to.
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+    /// Sets the timeout applied to each `AsyncRead` read.
+    ///
+    /// A read that does not complete within the timeout fails with
+    /// `TimedOut`. `None`, the default, waits indefinitely.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+        self.read_deadline = None;
+    }
+    /// Returns the timeout applied to each `AsyncRead` read.
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.read_timeout
+    }
+    /// Sets the timeout applied to each `AsyncWrite` write.
+    ///
+    /// A write that does not complete within the timeout fails with
+    /// `TimedOut`. `None`, the default, waits indefinitely.
+    pub fn set_write_timeout(&mut self, timeout: Option<Duration>) {
+        self.write_timeout = timeout;
+        self.write_deadline = None;
+    }
+    /// Returns the timeout applied to each `AsyncWrite` write.
+    pub fn write_timeout(&self) -> Option<Duration> {
+        self.write_timeout
+    }
+    /// Sets whether each `AsyncWrite` write is sent immediately, the default.
+    ///
+    /// While set, writes are not buffered: `poll_write` hands each datagram
+    /// to the socket before returning, so shutting down or dropping the
+    /// stream never loses data reported as written.
+    ///
+    /// When unset, writes are queued as separate datagrams and sent on the
+    /// next flush, so a burst of small writes is handed to the kernel at once
+    /// rather than interleaved with other work of the task. At most 64 writes
+    /// are queued: a write finding the queue full sends it first, returning
+    /// `Pending` until the socket took it. Queued writes are also sent before
+    /// the next immediate write, before datagrams sent with methods such as
+    /// [`send_datagram`](Self::send_datagram), and, best-effort, by
+    /// [`shutdown`](Self::shutdown) and when the stream is dropped: a datagram
+    /// the socket cannot take at once is then lost.
+    pub fn set_nodelay(&mut self, nodelay: bool) {
+        self.nodelay = nodelay;
+    }
+    /// Returns whether each `AsyncWrite` write is sent immediately.
+    pub fn nodelay(&self) -> bool {
+        self.nodelay
+    }
+    /// Limits the rate at which datagrams are sent to the peer to
+    /// `bytes_per_sec`, or lifts the limit with `None`, the default.
+    ///
+    /// Writes through [`AsyncWrite`] return `Pending`, and
+    /// [`send_datagram`](Self::send_datagram) and
+    /// [`send_all`](Self::send_all) wait, until the rate allows the next
+    /// datagram, counted with its encoded length. Bursts of up to a tenth of a
+    /// second worth of bytes go out at once. The datagrams sent best-effort
+    /// when the stream is dropped are not paced.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes_per_sec` is zero.
+    pub fn set_send_rate(&mut self, bytes_per_sec: Option<u64>) {
+        assert!(bytes_per_sec != Some(0), "send rate must be non-zero");
+        self.send_rate = bytes_per_sec.map(SendRate::new);
+        self.rate_delay = None;
+    }
+    /// Returns the rate at which datagrams are sent to the peer, in bytes per
+    /// second, if limited.
+    pub fn send_rate(&self) -> Option<u64> {
+        self.send_rate.as_ref().map(SendRate::bytes_per_sec)
+    }
+    /// Boxes the stream as a trait object, for code handling several
+    /// transports such as TCP or Unix sockets through dynamic dispatch.
+    pub fn into_boxed_io(self) -> Box<dyn AsyncReadWrite + Unpin + Send> {
+        Box::new(self)
+    }
+    /// Stops the stream and returns its socket, to use tokio's API directly.
+    ///
+    /// The background receive task is stopped first, which is why this is
+    /// async. Datagrams received but not read yet are discarded, writes held
+    /// back by [`set_nodelay`](Self::set_nodelay) are sent, and the
+    /// [shutdown payload](UdpStreamBuilder::shutdown_payload) is not. The
+    /// socket stays connected to the peer if the stream was.
+    ///
+    /// Streams accepted by a listener share its socket: they are handed back
+    /// unchanged as the error.
+    pub async fn into_socket(mut self) -> Result<UdpSocket, Self> {
+        if self.drop.is_some() {
+            return Err(self);
+        }
+        if let Some(handler) = self.handler.take() {
+            handler.abort();
+            // The task holds a reference to the socket until it is dropped.
+            let _ = handler.await;
+        }
+        self.shutdown_payload = None;
+        let socket = self.socket.clone();
+        drop(self);
+        Ok(Arc::into_inner(socket).expect("client stream socket is not shared"))
+    }
+    /// Splits the stream into logical sub-streams by a key computed from each
+    /// datagram, such as a stream id carried in a header.
+    ///
+    /// The stream is consumed and its datagrams are routed to the sub-stream
+    /// of their key, which is announced by [`DemuxBy::next`] when its first
+    /// datagram arrives. Data left over from a partial `AsyncRead` is not
+    /// routed.
+    pub fn demux_by<K, F>(self, key_fn: F) -> DemuxBy<K>
+    where
+        K: Eq + std::hash::Hash + Clone + Send + 'static,
+        F: Fn(&[u8]) -> K + Send + 'static,
+    {
+        DemuxBy::new(self, key_fn)
+    }
+    /// Returns whether the stream owns a socket connected to its peer.
+    ///
+    /// This is the case for streams created by [`connect`](Self::connect) and
+    /// [`connect_direct`](Self::connect_direct), and for those built
+    /// [`from_tokio`](Self::from_tokio) with a connected socket. The kernel
+    /// then filters datagrams by source and reports ICMP errors, such as
+    /// `ConnectionRefused`, on later reads and writes. Streams accepted by a
+    /// listener share its unconnected socket and return `false`.
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+    /// Returns the kernel's path MTU estimate towards the peer.
+    ///
+    /// This requires a socket connected to the peer (see
+    /// [`is_connected`](Self::is_connected)) and is only available on Linux,
+    /// where it reads `IP_MTU` or `IPV6_MTU`. An error is returned otherwise.
+    pub fn path_mtu(&self) -> io::Result<usize> {
+        sys::path_mtu(&self.socket)
+    }
+    /// Sets or clears the don't-fragment bit on datagrams sent by this stream.
+    ///
+    /// When enabled, writes larger than the path MTU fail with `EMSGSIZE`
+    /// instead of being fragmented, as needed by protocols doing their own path
+    /// MTU discovery. On Linux this uses `IP_MTU_DISCOVER` (`IPV6_MTU_DISCOVER`)
+    /// and on macOS and FreeBSD `IP_DONTFRAG` (`IPV6_DONTFRAG`); other platforms
+    /// return an error. Streams accepted by a listener share its socket, so the
+    /// setting affects every stream of that listener.
+    pub fn set_dontfrag(&self, dontfrag: bool) -> io::Result<()> {
+        sys::set_dontfrag(&self.socket, dontfrag)
+    }
+    /// Sends a datagram to `addr` through the stream's socket.
+    ///
+    /// Unlike writes through [`AsyncWrite`], the datagram is not sent to
+    /// [`peer_addr`](Self::peer_addr), which stays the key under which incoming
+    /// datagrams are delivered to this stream. This is useful for protocols
+    /// that reply to a control address distinct from the data source.
+    /// [Connected](Self::is_connected) streams can only reach their peer.
+    pub async fn send_to_addr(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.socket.send_to(buf, addr).await
+    }
+    /// Sends a datagram to the peer, waiting until the socket accepts it.
+    ///
+    /// Sends that would block or are interrupted by a signal are retried once
+    /// the socket is writable again, so this only fails with an error the
+    /// datagram cannot be sent past. Unlike writes through [`AsyncWrite`], no
+    /// write timeout applies.
+    ///
+    /// Writes still queued because [`nodelay`](Self::set_nodelay) is unset
+    /// are sent first, so the datagram never overtakes them.
+    pub async fn send_datagram(&self, buf: &[u8]) -> io::Result<usize> {
+        self.send_queued().await?;
+        self.send_wire(buf, self.encode(buf), None).await
+    }
+    /// Sends `buf` as a datagram to the peer, as
+    /// [`send_datagram`](Self::send_datagram) does, without copying it.
+    ///
+    /// The socket reads the datagram straight from `buf`, and a codec's
+    /// encoded form is sent as it is returned, so a datagram received with
+    /// [`recv`](Self::recv) can be forwarded to another stream as is.
+    pub async fn send_bytes(&self, buf: Bytes) -> io::Result<usize> {
+        let wire = match &self.codec {
+            Some(codec) => codec.encode(&buf),
+            None => buf.clone(),
+        };
+        self.send_queued().await?;
+        self.send_wire(&buf, wire, None).await
+    }
+    /// Sends the writes queued while [`nodelay`](Self::set_nodelay) is unset,
+    /// ahead of a datagram sent directly. A queued datagram failing to send is
+    /// discarded and its error returned.
+    async fn send_queued(&self) -> io::Result<()> {
+        loop {
+            let Some(datagram) = self.write_queue.lock().unwrap().pop_front() else {
+                return Ok(());
+            };
+            self.send_wire(&datagram, self.encode(&datagram), None)
+                .await?;
+        }
+    }
+    /// Sends `wire`, the wire form of the datagram `buf` written by the
+    /// application, marked with the ECN codepoint `ecn` if there is one.
+    async fn send_wire(
+        &self,
+        buf: &[u8],
+        wire: impl AsRef<[u8]>,
+        ecn: Option<Ecn>,
+    ) -> io::Result<usize> {
+        let wire = wire.as_ref();
+        if let Some(rate) = &self.send_rate {
+            while let Some(wait) = rate.delay(wire.len()) {
+                tokio::time::sleep(wait).await;
+            }
+        }
+        let sent = match self.mock_send(wire) {
+            Some(sent) => sent,
+            None => {
+                send_when_writable(
+                    || self.socket.writable(),
+                    || match ecn {
+                        Some(ecn) => {
+                            sys::try_send_with_ecn(&self.socket, wire, self.target_addr(), ecn)
+                        }
+                        None => self.socket.try_send_to(wire, self.target_addr()),
+                    },
+                )
+                .await
+            }
+        };
+        match sent {
+            // A datagram is always sent whole.
+            Ok(_) => {
+                if let Some(rate) = &self.send_rate {
+                    rate.consume(wire.len());
+                }
+                if let Some(rtt) = &self.rtt {
+                    rtt.on_send(buf);
+                }
+                self.counters.on_send(buf.len());
+                Ok(buf.len())
+            }
+            Err(err) => {
+                self.on_send_error(&err);
+                Err(err)
+            }
+        }
+    }
+    /// Sends each of `datagrams` as a separate datagram to the peer, in
+    /// order, and returns how many were sent.
+    ///
+    /// Every datagram is sent as by [`send_datagram`](Self::send_datagram). The
+    /// first failure stops the batch and is returned as a [`SendAllError`]
+    /// telling how many datagrams went out before it; `?` converts it into an
+    /// `io::Error` keeping the kind of the failure.
+    ///
+    /// On Linux the datagrams are handed to the kernel up to 64 at a time with
+    /// `sendmmsg`, saving a system call per datagram, unless a
+    /// [send rate](Self::set_send_rate) paces them.
+    #[cfg(target_os = "linux")]
+    pub async fn send_all(&self, datagrams: &[&[u8]]) -> Result<usize, SendAllError> {
+        self.send_queued()
+            .await
+            .map_err(|error| SendAllError { sent: 0, error })?;
+        if self.send_rate.is_some() {
+            return self.send_each(datagrams).await;
+        }
+        #[cfg(feature = "test-util")]
+        if self.sink.is_some() {
+            return self.send_each(datagrams).await;
+        }
+        let encoded: Vec<Cow<[u8]>> = datagrams
+            .iter()
+            .map(|datagram| self.encode(datagram))
+            .collect();
+        let wire: Vec<&[u8]> = encoded.iter().map(|datagram| &**datagram).collect();
+        let mut sent = 0;
+        while sent < datagrams.len() {
+            match sys::send_batch(&self.socket, &wire[sent..], self.target_addr()).await {
+                Ok(batch) => {
+                    for datagram in &datagrams[sent..sent + batch] {
+                        if let Some(rtt) = &self.rtt {
+                            rtt.on_send(datagram);
+                        }
+                        self.counters.on_send(datagram.len());
+                    }
+                    sent += batch;
+                }
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) => {
+                    self.on_send_error(&error);
+                    return Err(SendAllError { sent, error });
+                }
+            }
+        }
+        Ok(sent)
+    }
+    /// Sends each of `datagrams` as a separate datagram to the peer, in
+    /// order, and returns how many were sent.
+    ///
+    /// Every datagram is sent as by [`send_datagram`](Self::send_datagram). The
+    /// first failure stops the batch and is returned as a [`SendAllError`]
+    /// telling how many datagrams went out before it; `?` converts it into an
+    /// `io::Error` keeping the kind of the failure.
+    #[cfg(not(target_os = "linux"))]
+    pub async fn send_all(&self, datagrams: &[&[u8]]) -> Result<usize, SendAllError> {
+        self.send_each(datagrams).await
+    }
+    async fn send_each(&self, datagrams: &[&[u8]]) -> Result<usize, SendAllError> {
+        for (sent, datagram) in datagrams.iter().enumerate() {
+            if let Err(error) = self.send_datagram(datagram).await {
+                return Err(SendAllError { sent, error });
+            }
+        }
+        Ok(datagrams.len())
+    }
+    /// Sends `buf` to the peer without waiting for the socket to be writable.
+    ///
+    /// The datagram is handed to the socket directly rather than through
+    /// tokio, whose readiness tracking would refuse to send on a socket it has
+    /// not seen writable yet, for instance one that never sent anything.
+    fn try_send_now(&self, buf: &[u8]) -> io::Result<usize> {
+        let wire = self.encode(buf);
+        let sent = match self.mock_send(&wire) {
+            Some(sent) => sent,
+            None => SockRef::from(&*self.socket).send_to(&wire, &self.target_addr().into()),
+        };
+        if sent.is_ok() {
+            self.counters.on_send(buf.len());
+        }
+        sent
+    }
+    /// Sends the queued writes with [`try_send_now`](Self::try_send_now),
+    /// dropping those that fail.
+    fn try_send_queue_now(&self) {
+        let queued = std::mem::take(&mut *self.write_queue.lock().unwrap());
+        for datagram in queued {
+            let _ = self.try_send_now(&datagram);
+        }
+    }
+    /// Returns the wire form of `buf`, encoded if the stream has a codec.
+    fn encode<'a>(&self, buf: &'a [u8]) -> Cow<'a, [u8]> {
+        match &self.codec {
+            Some(codec) => Cow::Owned(codec.encode(buf).into()),
+            None => Cow::Borrowed(buf),
+        }
+    }
+    /// Hands `buf` to the sink of a mock stream, or returns `None` for a
+    /// stream sending through its socket.
+    #[cfg(feature = "test-util")]
+    fn mock_send(&self, buf: &[u8]) -> Option<io::Result<usize>> {
+        let sink = self.sink.as_ref()?;
+        Some(
+            sink.send(Bytes::copy_from_slice(buf))
+                .map(|()| buf.len())
+                .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe)),
+        )
+    }
+    #[cfg(not(feature = "test-util"))]
+    fn mock_send(&self, _buf: &[u8]) -> Option<io::Result<usize>> {
+        None
+    }
+    /// Returns the address writes are sent to: where the peer's datagrams
+    /// come from, see [`UdpListenerBuilder::connection_id`].
+    fn target_addr(&self) -> SocketAddr {
+        self.state
+            .moved_to
+            .lock()
+            .unwrap()
+            .unwrap_or(self.wire_addr)
+    }
+    /// Counts a send error and notifies the listener if it ends the peer.
+    fn on_send_error(&self, err: &io::Error) {
+        self.counters.on_send_error();
+        if is_fatal_send_error(err) {
+            if let Some(drop) = &self.drop {
+                let _ = drop.try_send(self.peer_addr);
+            }
+        }
+    }
+    /// Measures round-trip times with `tracker` from now on.
+    ///
+    /// See [`RttTracker`] for how datagrams are correlated.
+    pub fn set_rtt_tracker(&mut self, tracker: RttTracker) {
+        self.rtt = Some(tracker);
+    }
+    /// Returns the round-trip time of the most recently completed exchange,
+    /// or `None` if no tracker is set or no exchange completed yet.
+    pub fn last_rtt(&self) -> Option<Duration> {
+        self.rtt.as_ref().and_then(RttTracker::last_rtt)
+    }
+    /// Returns a snapshot of the stream's counters.
+    ///
+    /// Datagrams are counted as received once read, through `AsyncRead` or
+    /// any of the `recv` methods, and as sent once handed to the socket.
+    pub fn stats(&self) -> StreamStats {
+        self.counters.snapshot()
+    }
+    /// Counts sequence gaps and reorders with `tracker` from now on.
+    ///
+    /// See [`SequenceTracker`] for how sequence numbers are read.
+    pub fn set_sequence_tracker(&mut self, tracker: SequenceTracker) {
+        self.sequence = Some(tracker);
+    }
+    /// Returns the number of gaps in the sequence numbers received so far, or
+    /// 0 if no tracker is set.
+    pub fn sequence_gaps(&self) -> u64 {
+        self.sequence.as_ref().map_or(0, SequenceTracker::gaps)
+    }
+    /// Returns the number of datagrams received out of order or duplicated
+    /// so far, or 0 if no tracker is set.
+    pub fn sequence_reorders(&self) -> u64 {
+        self.sequence.as_ref().map_or(0, SequenceTracker::reorders)
+    }
+    /// Returns the instant at which the last datagram from the peer was
+    /// received, or the creation time of the stream if none arrived since.
+    pub fn last_activity(&self) -> Instant {
+        self.state.last_seen()
+    }
+    /// Waits until no more datagrams will be received on the stream, without
+    /// reading it.
+    ///
+    /// This happens when the listener stops tracking the peer, for instance
+    /// because its session expired, the listener was dropped or
+    /// [`shutdown`](Self::shutdown) was called, or when the receive task of
+    /// a client stream ends. Datagrams already queued can still be read.
+    /// Streams created by [`connect_direct`](Self::connect_direct) read their
+    /// socket directly and never close this way.
+    pub async fn closed(&self) {
+        self.state.closed().await
+    }
+    /// Receives the next datagram from the peer.
+    ///
+    /// Zero-length datagrams are returned as an empty `Bytes`, which makes this
+    /// the way to observe keepalives that `AsyncRead` cannot represent. Once
+    /// the stream is closed a `BrokenPipe` error is returned.
+    ///
+    /// # Cancel safety
+    ///
+    /// This method is cancel safe. If the future is dropped before it
+    /// completes, no datagram has been consumed.
+    pub async fn recv(&self) -> io::Result<Bytes> {
+        Ok(self.next_datagram().await?.payload)
+    }
+    /// Receives the next datagram from the peer along with the time it was
+    /// received.
+    ///
+    /// The kernel receive timestamp is reported for streams accepted by a
+    /// listener built with
+    /// [`UdpListenerBuilder::timestamps`](crate::UdpListenerBuilder::timestamps)
+    /// on Linux. Otherwise the time at which the datagram is read is returned.
+    pub async fn recv_with_timestamp(&self) -> io::Result<(Bytes, SystemTime)> {
+        let datagram = self.next_datagram().await?;
+        let timestamp = datagram.meta.timestamp.unwrap_or_else(SystemTime::now);
+        Ok((datagram.payload, timestamp))
+    }
+    /// Receives the next datagram from the peer along with its ECN codepoint.
+    ///
+    /// The codepoint is only reported for streams accepted by a listener built
+    /// with [`UdpListenerBuilder::ecn`](crate::UdpListenerBuilder::ecn) on
+    /// Linux; `None` is returned otherwise.
+    pub async fn recv_ecn(&self) -> io::Result<(Bytes, Option<Ecn>)> {
+        let datagram = self.next_datagram().await?;
+        Ok((datagram.payload, datagram.meta.ecn))
+    }
+    /// Receives the next datagram from the peer along with its metadata.
+    ///
+    /// The source address is always reported. The local address, kernel
+    /// timestamp and ECN codepoint are reported for streams accepted by a
+    /// listener built to record them on Linux, see [`DatagramMeta`], and are
+    /// `None` otherwise.
+    pub async fn recv_meta(&self) -> io::Result<(Bytes, DatagramMeta)> {
+        let datagram = self.next_datagram().await?;
+        let meta = DatagramMeta {
+            source: self.peer_addr,
+            local_addr: datagram
+                .meta
+                .local_ip
+                .map(|ip| SocketAddr::new(ip, self.local_addr.port())),
+            timestamp: datagram.meta.timestamp,
+            ecn: datagram.meta.ecn,
+        };
+        Ok((datagram.payload, meta))
+    }
+    /// Sends a datagram to the peer marked with the ECN codepoint `ecn`.
+    ///
+    /// The codepoint is passed as an `IP_TOS` or `IPV6_TCLASS` control message
+    /// with the DSCP bits cleared. Otherwise the datagram is sent as by
+    /// [`send_datagram`](Self::send_datagram), through the codec, send rate and
+    /// counters of the stream. This is only supported on Linux; other
+    /// platforms return an `Unsupported` error.
+    pub async fn send_ecn(&self, buf: &[u8], ecn: Ecn) -> io::Result<usize> {
+        self.send_queued().await?;
+        self.send_wire(buf, self.encode(buf), Some(ecn)).await
+    }
+    /// Receives the next datagram from the peer and appends it to `dst`,
+    /// returning the number of bytes appended.
+    ///
+    /// Exactly one datagram is consumed per call, so calling it repeatedly
+    /// accumulates consecutive datagrams in `dst`. Bytes left over from a
+    /// partial `AsyncRead` are not included.
+    ///
+    /// # Cancel safety
+    ///
+    /// This method is cancel safe. If the future is dropped before it
+    /// completes, `dst` is left untouched and no datagram has been consumed.
+    pub async fn read_buf(&self, dst: &mut BytesMut) -> io::Result<usize> {
+        let datagram = self.next_datagram().await?.payload;
+        dst.extend_from_slice(&datagram);
+        Ok(datagram.len())
+    }
+    /// Discards every datagram that has been received but not read yet,
+    /// returning how many were dropped.
+    ///
+    /// The stream stays open and keeps receiving new datagrams, which makes it
+    /// possible to resynchronize after a protocol error. Nothing is drained if
+    /// another task is currently receiving on the stream.
+    pub fn drain_queued(&self) -> usize {
+        let mut drained = 0;
+        match &self.incoming {
+            Incoming::Channel(receiver) => {
+                if let Ok(mut receiver) = receiver.try_lock() {
+                    loop {
+                        match receiver.try_recv() {
+                            Ok(datagram) => {
+                                if datagram.unreachable.is_none() {
+                                    drained += 1;
+                                }
+                            }
+                            Err(mpsc::error::TryRecvError::Disconnected)
+                                if self.state.take_grown(&mut receiver) => {}
+                            Err(_) => break,
+                        }
+                    }
+                }
+            }
+            Incoming::Direct(_) => {
+                let mut buf = BytesMut::with_capacity(udp_buffer_size());
+                while let Ok((_, received_addr)) = self.socket.try_recv_buf_from(&mut buf) {
+                    if received_addr == self.peer_addr {
+                        drained += 1;
+                    }
+                    buf.clear();
+                }
+            }
+        }
+        drained
+    }
+    async fn next_datagram(&self) -> io::Result<Datagram> {
+        let datagram = self.recv_datagram().await?;
+        if let Some(kind) = datagram.unreachable {
+            return Err(peer_unreachable(kind));
+        }
+        if let Some(rtt) = &self.rtt {
+            rtt.on_recv(&datagram.payload);
+        }
+        if let Some(sequence) = &self.sequence {
+            sequence.on_recv(&datagram.payload);
+        }
+        self.counters.on_recv(datagram.payload.len());
+        Ok(datagram)
+    }
+    async fn recv_datagram(&self) -> io::Result<Datagram> {
+        if self.read_shutdown {
+            return Err(io::Error::from(io::ErrorKind::BrokenPipe));
+        }
+        match &self.incoming {
+            Incoming::Channel(receiver) => {
+                let mut receiver = receiver.lock().await;
+                loop {
+                    match receiver.recv().await {
+                        Some(datagram) => return Ok(datagram),
+                        None if self.state.take_grown(&mut receiver) => {}
+                        None => return Err(self.state.closed_error()),
+                    }
+                }
+            }
+            Incoming::Direct(_) => loop {
+                let mut buf = BytesMut::with_capacity(udp_buffer_size());
+                let (len, received_addr) = match self.socket.recv_buf_from(&mut buf).await {
+                    Ok(received) => received,
+                    Err(err) if is_unreachable_error(&err) => {
+                        return Ok(Datagram::unreachable(err.kind()))
+                    }
+                    Err(err) => return Err(err),
+                };
+                if received_addr == self.peer_addr {
+                    self.state.touch();
+                    return Ok(buf.split_to(len).freeze().into());
+                }
+            },
+        }
+    }
+    /// Stops tracking the peer on the listener side so that a new datagram
+    /// from it is announced as a new connection.
+    ///
+    /// Writes queued while [`nodelay`](Self::set_nodelay) is unset are sent
+    /// first, on a best-effort basis as when the stream is dropped: a
+    /// datagram the socket cannot take at once is lost. Flush the stream
+    /// beforehand to wait for them to be sent.
+    pub fn shutdown(&self) {
+        self.try_send_queue_now();
+        if let Some(drop) = &self.drop {
+            let _ = drop.try_send(self.peer_addr);
+        };
+    }
+    /// Stops receiving while keeping the stream usable for writes.
+    ///
+    /// The background receive task, if any, is stopped and datagrams queued
+    /// but not read yet are discarded, so reads return EOF from now on and
+    /// nothing more is buffered. For streams accepted by a listener this also
+    /// stops tracking the peer, as [`shutdown`](Self::shutdown) does.
+    pub fn shutdown_read(&mut self) {
+        self.read_shutdown = true;
+        self.remaining = None;
+        if let Some(handler) = self.handler.take() {
+            handler.abort();
+        }
+        if let Incoming::Channel(receiver) = &mut self.incoming {
+            let receiver = receiver.get_mut();
+            receiver.close();
+            while receiver.try_recv().is_ok() {}
+            self.state.grown.lock().unwrap().take();
+        }
+        self.shutdown();
     }
 }
 
-impl UdpListener {
-    pub async fn bind(local_addr: SocketAddr) -> io::Result<Self> {
-        let (tx, rx) = mpsc::channel(CHANNEL_LEN);
-        let udp_socket = UdpSocket::bind(local_addr).await?;
-        let local_addr = udp_socket.local_addr()?;
+impl UdpStream {
+    fn poll_read_datagram(&mut self, cx: &mut Context, buf: &mut ReadBuf) -> Poll<io::Result<()>> {
+        let this = self;
+        if let Some(remaining) = this.remaining.as_mut() {
+            if buf.remaining() < remaining.len() {
+                buf.put_slice(&remaining.split_to(buf.remaining())[..]);
+            } else {
+                buf.put_slice(&remaining[..]);
+                this.remaining = None;
+            }
+            return Poll::Ready(Ok(()));
+        }
+        if this.read_shutdown {
+            return Poll::Ready(Ok(()));
+        }
 
-        let handler = tokio::spawn(async move {
-            let mut streams: HashMap<SocketAddr, mpsc::Sender<Bytes>> = HashMap::new();
-            let socket = Arc::new(udp_socket);
-            let (drop_tx, mut drop_rx) = mpsc::channel(1);
+        loop {
+            let received = match &mut this.incoming {
+                Incoming::Channel(receiver) => {
+                    poll_recv_channel(receiver.get_mut(), &this.state, cx).map(|datagram| {
+                        match datagram {
+                            Some(Datagram {
+                                unreachable: Some(kind),
+                                ..
+                            }) => Some(Err(peer_unreachable(kind))),
+                            datagram => datagram.map(|datagram| Ok(datagram.payload)),
+                        }
+                    })
+                }
+                Incoming::Direct(recv_buf) => {
+                    match poll_recv_direct(&this.socket, this.peer_addr, recv_buf, cx) {
+                        Poll::Ready(Ok(datagram)) => {
+                            this.state.touch();
+                            Poll::Ready(Some(Ok(datagram)))
+                        }
+                        Poll::Ready(Err(err)) if is_unreachable_error(&err) => {
+                            Poll::Ready(Some(Err(peer_unreachable(err.kind()))))
+                        }
+                        Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                        Poll::Pending => Poll::Pending,
+                    }
+                }
+            };
 
-            let mut buf = BytesMut::with_capacity(UDP_BUFFER_SIZE * 3);
-            loop {
-                if buf.capacity() < UDP_BUFFER_SIZE {
-                    buf.reserve(UDP_BUFFER_SIZE * 3);
+            return match received {
+                // A zero-length datagram would read as EOF, so it is skipped.
+                Poll::Ready(Some(Ok(inner_buf))) if inner_buf.is_empty() => {
+                    this.counters.on_recv(0);
+                    continue;
                 }
-                tokio::select! {
-                    Some(peer_addr) = drop_rx.recv() => {
-                        streams.remove(&peer_addr);
+                Poll::Ready(Some(Err(err))) => Poll::Ready(Err(err)),
+                Poll::Ready(Some(Ok(mut inner_buf))) => {
+                    if let Some(rtt) = &this.rtt {
+                        rtt.on_recv(&inner_buf);
                     }
-                    Ok((len, peer_addr)) = socket.recv_buf_from(&mut buf) => {
-                        match streams.get_mut(&peer_addr) {
-                            Some(child_tx) => {
-                                if let Err(err) = child_tx.send(buf.copy_to_bytes(len)).await {
-                                    log::error!("child_tx.send {:?}", err);
-                                    child_tx.closed().await;
-                                    streams.remove(&peer_addr);
-                                    continue;
-                                }
-                            }
-                            None => {
-                                let (child_tx, child_rx) = mpsc::channel(CHANNEL_LEN);
-                                if let Err(err) = child_tx.send(buf.copy_to_bytes(len)).await {
-                                    log::error!("child_tx.send {:?}", err);
-                                    continue;
-                                }
-                                let udp_stream = UdpStream {
-                                    local_addr,
-                                    peer_addr,
-                                    receiver: Arc::new(Mutex::new(child_rx)),
-                                    socket: socket.clone(),
-                                    handler: None,
-                                    drop: Some(drop_tx.clone()),
-                                    remaining: None,
-                                };
-                                if let Err(err) = tx.send((udp_stream, peer_addr)).await {
-                                    log::error!("tx.send {:?}", err);
-                                    continue;
-                                }
-                                streams.insert(peer_addr, child_tx.clone());
-                            }
-                        }
+                    if let Some(sequence) = &this.sequence {
+                        sequence.on_recv(&inner_buf);
                     }
+                    this.counters.on_recv(inner_buf.len());
+                    if buf.remaining() < inner_buf.len() {
+                        this.remaining = Some(inner_buf.split_off(buf.remaining()));
+                    };
+                    buf.put_slice(&inner_buf[..]);
+                    Poll::Ready(Ok(()))
                 }
-            }
-        });
-        Ok(Self {
-            handler,
-            receiver: Arc::new(Mutex::new(rx)),
-            local_addr,
-        })
+                Poll::Ready(None) if this.state.is_expired() => {
+                    Poll::Ready(Err(this.state.closed_error()))
+                }
+                // The stream was closed, which reads as EOF.
+                Poll::Ready(None) => Poll::Ready(Ok(())),
+                Poll::Pending => Poll::Pending,
+            };
+        }
     }
+}
 
-    ///Returns the local address that this socket is bound to.
-    pub fn local_addr(&self) -> io::Result<SocketAddr> {
-        Ok(self.local_addr)
+/// Makes room in `buf` for a whole datagram of up to `size` bytes, as a
+/// receive truncates the datagram to the spare capacity of the buffer.
+fn reserve_datagram(buf: &mut BytesMut, size: usize) {
+    if buf.capacity() - buf.len() < size {
+        buf.reserve(size * 3);
     }
+}
 
-    /// Accepts a new incoming UDP connection.
-    pub async fn accept(&self) -> io::Result<(UdpStream, SocketAddr)> {
-        self.receiver
-            .lock()
-            .await
-            .recv()
-            .await
-            .ok_or(io::Error::from(io::ErrorKind::BrokenPipe))
+/// Sends with `try_send` once `writable` resolves, until it fails with an
+/// error other than `WouldBlock` or `Interrupted` or the datagram is accepted.
+async fn send_when_writable<W>(
+    mut writable: impl FnMut() -> W,
+    mut try_send: impl FnMut() -> io::Result<usize>,
+) -> io::Result<usize>
+where
+    W: Future<Output = io::Result<()>>,
+{
+    loop {
+        writable().await?;
+        match try_send() {
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+                ) => {}
+            sent => return sent,
+        }
     }
 }
 
-/// An I/O object representing a UDP stream connected to a remote endpoint.
+/// Takes the `len` bytes just received into `buf` out as a datagram.
 ///
-/// A UDP stream can either be created by connecting to an endpoint, via the
-/// [`connect`] method, or by [accepting] a connection from a [listener].
+/// Returns `None` and empties the buffer if `len` exceeds the received bytes,
+/// which is never expected but must not panic a receive loop.
+fn take_datagram(buf: &mut BytesMut, len: usize, peer_addr: SocketAddr) -> Option<Bytes> {
+    if len > buf.len() {
+        log::error!(
+            "received length {} exceeds the {} buffered bytes, dropping datagram from {}",
+            len,
+            buf.len(),
+            peer_addr
+        );
+        buf.clear();
+        return None;
+    }
+    Some(buf.copy_to_bytes(len))
+}
+
+/// Returns the buffer the receive loop of a listener receives the next
+/// datagram into: a slab of `pool` if it has one, so that the slab is only
+/// ever grown by the pool, and `own_buf` otherwise.
+fn next_buffer<'a>(
+    pool: &'a mut Option<BufferPool>,
+    own_buf: &'a mut BytesMut,
+    buffer_size: usize,
+) -> &'a mut BytesMut {
+    match pool {
+        Some(pool) => pool.buffer(buffer_size),
+        None => {
+            reserve_datagram(own_buf, buffer_size);
+            own_buf
+        }
+    }
+}
+
+/// An object implementing both [`AsyncRead`] and [`AsyncWrite`], usable as a
+/// trait object as returned by [`UdpStream::into_boxed_io`].
 ///
-/// [`connect`]: struct.UdpStream.html#method.connect
-/// [accepting]: struct.UdpListener.html#method.accept
-/// [listener]: struct.UdpListener.html
+/// It is implemented for every such type, so `TcpStream`s or `UnixStream`s can
+/// be boxed alongside UDP streams.
+pub trait AsyncReadWrite: AsyncRead + AsyncWrite {}
+
+impl<T: AsyncRead + AsyncWrite + ?Sized> AsyncReadWrite for T {}
+
+/// The error reading a [connected](UdpStream::is_connected) stream fails with
+/// when the kernel reports an ICMP error from the peer, typically caused by an
+/// earlier write to a closed port.
+///
+/// It is wrapped in an `io::Error` of kind `ConnectionRefused` or
+/// `ConnectionReset`, depending on the platform, and can be told apart from
+/// other errors with `err.get_ref().is_some_and(|err| err.is::<PeerUnreachable>())`.
+/// The stream stays usable: the next read returns the next datagram.
 #[derive(Debug)]
-pub struct UdpStream {
-    local_addr: SocketAddr,
-    peer_addr: SocketAddr,
-    receiver: Arc<Mutex<mpsc::Receiver<Bytes>>>,
-    socket: Arc<tokio::net::UdpSocket>,
-    handler: Option<tokio::task::JoinHandle<()>>,
-    drop: Option<mpsc::Sender<SocketAddr>>,
-    remaining: Option<Bytes>,
+pub struct PeerUnreachable;
+
+impl fmt::Display for PeerUnreachable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the peer was reported unreachable")
+    }
 }
 
-impl Drop for UdpStream {
-    fn drop(&mut self) {
-        if let Some(handler) = &self.handler {
-            handler.abort()
-        }
+impl std::error::Error for PeerUnreachable {}
 
-        if let Some(drop) = &self.drop {
-            let _ = drop.try_send(self.peer_addr);
-        };
+fn peer_unreachable(kind: io::ErrorKind) -> io::Error {
+    io::Error::new(kind, PeerUnreachable)
+}
+
+/// The error connecting a stream fails with when a UDP socket cannot be
+/// connected to the peer address, as opposed to failures of the local
+/// socket.
+///
+/// An unspecified address, such as `0.0.0.0`, or port 0 is refused before
+/// connecting, with kind `InvalidInput`. Addresses the kernel refuses, such
+/// as the IPv4 broadcast address or a link-local multicast address without
+/// a scope, fail with the kind of the kernel's error, typically
+/// `PermissionDenied` or `InvalidInput`. It can be told apart from other
+/// errors with `err.get_ref().is_some_and(|err| err.is::<InvalidPeer>())`.
+#[derive(Debug)]
+pub struct InvalidPeer {
+    addr: SocketAddr,
+    error: Option<io::Error>,
+}
+
+impl InvalidPeer {
+    /// Returns the address the stream could not be connected to.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
     }
 }
 
-impl UdpStream {
-    /// Create a new UDP stream connected to the specified address.
-    ///
-    /// This function will create a new UDP socket and attempt to connect it to
-    /// the `addr` provided. The returned future will be resolved once the
-    /// stream has successfully connected, or it will return an error if one
-    /// occurs.
-    pub async fn connect(addr: SocketAddr) -> Result<Self, tokio::io::Error> {
-        let local_addr: SocketAddr = if addr.is_ipv4() {
-            SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)
-        } else {
-            SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0)
-        };
-        let socket = UdpSocket::bind(local_addr).await?;
-        Self::from_tokio(socket, addr).await
+impl fmt::Display for InvalidPeer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.error {
+            Some(error) => write!(f, "cannot connect to {}: {}", self.addr, error),
+            None => write!(f, "cannot connect to {}: not a peer address", self.addr),
+        }
     }
-    /// Creates a new UdpStream from a tokio::net::UdpSocket.
-    /// This function is intended to be used to wrap a UDP socket from the tokio library.
-    /// Note: The UdpSocket must have the UdpSocket::connect method called before invoking this function.
-    pub async fn from_tokio(
-        socket: UdpSocket,
-        peer_addr: SocketAddr,
-    ) -> Result<Self, tokio::io::Error> {
-        let socket = Arc::new(socket);
+}
 
-        let local_addr = socket.local_addr()?;
+impl std::error::Error for InvalidPeer {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error
+            .as_ref()
+            .map(|error| error as &(dyn std::error::Error + 'static))
+    }
+}
 
-        let (child_tx, child_rx) = mpsc::channel(CHANNEL_LEN);
+/// Connects `socket` to `addr`, failing with [`InvalidPeer`] if `addr`
+/// cannot be a peer.
+async fn connect_peer(socket: &UdpSocket, addr: SocketAddr) -> io::Result<()> {
+    if addr.ip().is_unspecified() || addr.port() == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            InvalidPeer { addr, error: None },
+        ));
+    }
+    socket.connect(addr).await.map_err(|err| {
+        if matches!(
+            err.kind(),
+            io::ErrorKind::PermissionDenied | io::ErrorKind::InvalidInput
+        ) {
+            io::Error::new(
+                err.kind(),
+                InvalidPeer {
+                    addr,
+                    error: Some(err),
+                },
+            )
+        } else {
+            err
+        }
+    })
+}
 
-        let socket_inner = socket.clone();
+/// Returns whether `err` reports an ICMP error from a peer rather than a
+/// failure of the socket.
+fn is_unreachable_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset
+    )
+}
 
-        let handler = tokio::spawn(async move {
-            let mut buf = BytesMut::with_capacity(UDP_BUFFER_SIZE);
-            while let Ok((len, received_addr)) = socket_inner.clone().recv_buf_from(&mut buf).await
-            {
-                if received_addr != peer_addr {
-                    continue;
-                }
-                if child_tx.send(buf.copy_to_bytes(len)).await.is_err() {
-                    child_tx.closed().await;
-                    break;
-                }
+/// The error returned by [`UdpStream::send_all`] when a datagram fails to
+/// send, recording how many datagrams were sent before it.
+#[derive(Debug)]
+pub struct SendAllError {
+    sent: usize,
+    error: io::Error,
+}
 
-                if buf.capacity() < UDP_BUFFER_SIZE {
-                    buf.reserve(UDP_BUFFER_SIZE * 3);
-                }
-            }
-        });
+impl SendAllError {
+    /// Returns the number of datagrams sent before the failure, which is also
+    /// the index of the datagram that failed.
+    pub fn sent(&self) -> usize {
+        self.sent
+    }
 
-        Ok(UdpStream {
-            local_addr,
-            peer_addr,
-            receiver: Arc::new(Mutex::new(child_rx)),
-            socket: socket.clone(),
-            handler: Some(handler),
-            drop: None,
-            remaining: None,
-        })
+    /// Returns the error the failing datagram was sent with.
+    pub fn error(&self) -> &io::Error {
+        &self.error
     }
 
-    pub fn peer_addr(&self) -> std::io::Result<SocketAddr> {
-        Ok(self.peer_addr)
+    /// Returns the error the failing datagram was sent with, discarding the
+    /// count.
+    pub fn into_error(self) -> io::Error {
+        self.error
     }
-    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
-        Ok(self.local_addr)
+}
+
+impl fmt::Display for SendAllError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "failed after sending {} datagrams: {}",
+            self.sent, self.error
+        )
     }
-    pub fn shutdown(&self) {
-        if let Some(drop) = &self.drop {
-            let _ = drop.try_send(self.peer_addr);
-        };
+}
+
+impl std::error::Error for SendAllError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl From<SendAllError> for io::Error {
+    fn from(err: SendAllError) -> Self {
+        io::Error::new(err.error.kind(), err)
     }
 }
 
+// `into_boxed_io` and spawned tasks rely on the stream being `Send` and
+// `Unpin`; fail the build if a field ever breaks that.
+const _: fn() = || {
+    fn assert_send_unpin<T: Send + Unpin>() {}
+    assert_send_unpin::<UdpStream>();
+};
+
 impl AsyncRead for UdpStream {
     fn poll_read(
-        mut self: Pin<&mut Self>,
+        self: Pin<&mut Self>,
         cx: &mut Context,
         buf: &mut ReadBuf,
     ) -> Poll<io::Result<()>> {
-        if let Some(remaining) = self.remaining.as_mut() {
-            if buf.remaining() < remaining.len() {
-                buf.put_slice(&remaining.split_to(buf.remaining())[..]);
-            } else {
-                buf.put_slice(&remaining[..]);
-                self.remaining = None;
-            }
-            return Poll::Ready(Ok(()));
+        let this = self.get_mut();
+        let poll = this.poll_read_datagram(cx, buf);
+        poll_with_deadline(poll, this.read_timeout, &mut this.read_deadline, cx)
+    }
+}
+
+/// Returns whether a send error means the peer can no longer be reached, in
+/// which case the stream is torn down. Other errors, such as a datagram too
+/// large for the path (`EMSGSIZE`) or an interrupted call, only fail the
+/// current write.
+fn is_fatal_send_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionRefused | io::ErrorKind::NetworkUnreachable
+    )
+}
+
+/// Polls `send`, retrying it up to [`SEND_RETRIES`] times while it is
+/// interrupted by a signal, then waking the task to try again later, so
+/// `Interrupted` never surfaces.
+fn retry_interrupted(
+    cx: &mut Context,
+    mut send: impl FnMut(&mut Context) -> Poll<io::Result<usize>>,
+) -> Poll<io::Result<usize>> {
+    for _ in 0..SEND_RETRIES {
+        match send(cx) {
+            Poll::Ready(Err(err)) if err.kind() == io::ErrorKind::Interrupted => {}
+            poll => return poll,
         }
+    }
+    cx.waker().wake_by_ref();
+    Poll::Pending
+}
 
-        let receiver = self.receiver.clone();
-        let mut socket = match Pin::new(&mut Box::pin(receiver.lock())).poll(cx) {
-            Poll::Ready(socket) => socket,
-            Poll::Pending => return Poll::Pending,
-        };
+/// Applies an operation timeout to `poll`: the deadline is armed when the
+/// operation first returns `Pending` and cleared once it completes.
+fn poll_with_deadline<T>(
+    poll: Poll<io::Result<T>>,
+    timeout: Option<Duration>,
+    deadline: &mut Option<Pin<Box<Sleep>>>,
+    cx: &mut Context,
+) -> Poll<io::Result<T>> {
+    match (poll, timeout) {
+        (Poll::Ready(result), _) => {
+            *deadline = None;
+            Poll::Ready(result)
+        }
+        (Poll::Pending, None) => Poll::Pending,
+        (Poll::Pending, Some(timeout)) => {
+            let sleep = deadline.get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+            match sleep.as_mut().poll(cx) {
+                Poll::Ready(()) => {
+                    *deadline = None;
+                    Poll::Ready(Err(io::Error::from(io::ErrorKind::TimedOut)))
+                }
+                Poll::Pending => Poll::Pending,
+            }
+        }
+    }
+}
 
-        match socket.poll_recv(cx) {
-            Poll::Ready(Some(mut inner_buf)) => {
-                if buf.remaining() < inner_buf.len() {
-                    self.remaining = Some(inner_buf.split_off(buf.remaining()));
-                };
-                buf.put_slice(&inner_buf[..]);
-                Poll::Ready(Ok(()))
+/// Polls `socket` for the next datagram sent by `peer_addr`, discarding
+/// datagrams from any other source.
+fn poll_recv_direct(
+    socket: &UdpSocket,
+    peer_addr: SocketAddr,
+    buf: &mut BytesMut,
+    cx: &mut Context,
+) -> Poll<io::Result<Bytes>> {
+    let buffer_size = udp_buffer_size();
+    loop {
+        reserve_datagram(buf, buffer_size);
+        if let Err(err) = std::task::ready!(socket.poll_recv_ready(cx)) {
+            return Poll::Ready(Err(err));
+        }
+        match socket.try_recv_buf_from(buf) {
+            Ok((len, received_addr)) => {
+                let datagram = buf.split_to(len).freeze();
+                if received_addr == peer_addr {
+                    return Poll::Ready(Ok(datagram));
+                }
             }
-            Poll::Ready(None) => Poll::Ready(Err(io::Error::from(io::ErrorKind::BrokenPipe))),
-            Poll::Pending => Poll::Pending,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(err) => return Poll::Ready(Err(err)),
         }
     }
 }
 
-impl AsyncWrite for UdpStream {
-    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
-        match self.socket.poll_send_to(cx, buf, self.peer_addr) {
-            Poll::Ready(Ok(r)) => Poll::Ready(Ok(r)),
+impl UdpStream {
+    /// Sends `buf` as one datagram, notifying the listener of fatal errors.
+    fn poll_send(&mut self, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let wire = self.encode(buf);
+        std::task::ready!(self.poll_send_rate(cx, wire.len()));
+        let poll = retry_interrupted(cx, |cx| match self.mock_send(&wire) {
+            Some(sent) => Poll::Ready(sent),
+            None => self.socket.poll_send_to(cx, &wire, self.target_addr()),
+        });
+        match poll {
+            // A datagram is always sent whole.
+            Poll::Ready(Ok(_)) => {
+                if let Some(rate) = &self.send_rate {
+                    rate.consume(wire.len());
+                }
+                if let Some(rtt) = &self.rtt {
+                    rtt.on_send(buf);
+                }
+                self.counters.on_send(buf.len());
+                #[cfg(feature = "tracing")]
+                self.span
+                    .in_scope(|| tracing::trace!(len = buf.len(), "datagram sent"));
+                Poll::Ready(Ok(buf.len()))
+            }
             Poll::Ready(Err(e)) => {
-                if let Some(drop) = &self.drop {
-                    let _ = drop.try_send(self.peer_addr);
-                };
+                self.on_send_error(&e);
                 Poll::Ready(Err(e))
             }
             Poll::Pending => Poll::Pending,
         }
     }
-    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+    /// Waits until the send rate, if any, allows a datagram of `len` bytes.
+    fn poll_send_rate(&mut self, cx: &mut Context, len: usize) -> Poll<()> {
+        let Some(rate) = &self.send_rate else {
+            return Poll::Ready(());
+        };
+        loop {
+            if let Some(delay) = &mut self.rate_delay {
+                std::task::ready!(delay.as_mut().poll(cx));
+                self.rate_delay = None;
+            }
+            match rate.delay(len) {
+                None => return Poll::Ready(()),
+                Some(wait) => self.rate_delay = Some(Box::pin(tokio::time::sleep(wait))),
+            }
+        }
+    }
+    /// Sends the queued writes in order. A datagram failing to send is
+    /// discarded and its error returned.
+    fn poll_send_queue(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        while let Some(datagram) = self.write_queue.get_mut().unwrap().front().cloned() {
+            let sent = self.poll_send(cx, &datagram);
+            if sent.is_pending() {
+                return Poll::Pending;
+            }
+            self.write_queue.get_mut().unwrap().pop_front();
+            if let Poll::Ready(Err(e)) = sent {
+                return Poll::Ready(Err(e));
+            }
+        }
         Poll::Ready(Ok(()))
     }
-    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+}
+
+impl AsyncWrite for UdpStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = if this.nodelay {
+            match this.poll_send_queue(cx) {
+                Poll::Ready(Ok(())) => this.poll_send(cx, buf),
+                Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+                Poll::Pending => Poll::Pending,
+            }
+        } else {
+            // A full queue is sent before taking another write, so that it
+            // stays bounded.
+            let full = this.write_queue.get_mut().unwrap().len() >= WRITE_QUEUE_LEN;
+            let sent = if full {
+                this.poll_send_queue(cx)
+            } else {
+                Poll::Ready(Ok(()))
+            };
+            match sent {
+                Poll::Ready(Ok(())) => {
+                    this.write_queue
+                        .get_mut()
+                        .unwrap()
+                        .push_back(Bytes::copy_from_slice(buf));
+                    Poll::Ready(Ok(buf.len()))
+                }
+                Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+                Poll::Pending => Poll::Pending,
+            }
+        };
+        poll_with_deadline(poll, this.write_timeout, &mut this.write_deadline, cx)
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let poll = this.poll_send_queue(cx);
+        poll_with_deadline(poll, this.write_timeout, &mut this.write_deadline, cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        std::task::ready!(self.as_mut().poll_flush(cx))?;
+        if let Some(payload) = self.shutdown_payload.take() {
+            let _ = self.try_send_now(&payload);
+        }
         Poll::Ready(Ok(()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::atomic::AtomicUsize,
+        task::{Wake, Waker},
+    };
+
+    /// Counts how many times it is woken.
+    #[derive(Default)]
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn buffer_sizes_outside_a_datagram_fall_back_to_the_default() {
+        assert_eq!(parse_buffer_size(None), UDP_BUFFER_SIZE);
+        assert_eq!(parse_buffer_size(Some(" 65507 ")), 65507);
+        assert_eq!(parse_buffer_size(Some("65535")), 65535);
+        for invalid in ["", "large", "-1", "0", "65536", "100000000000"] {
+            assert_eq!(
+                parse_buffer_size(Some(invalid)),
+                UDP_BUFFER_SIZE,
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    fn interrupted() -> Poll<io::Result<usize>> {
+        Poll::Ready(Err(io::Error::from(io::ErrorKind::Interrupted)))
+    }
+
+    #[test]
+    fn interrupted_sends_are_retried() {
+        let wakes = Arc::new(CountingWaker::default());
+        let waker = Waker::from(wakes.clone());
+        let mut cx = Context::from_waker(&waker);
+        let mut attempts = 0;
+        let poll = retry_interrupted(&mut cx, |_| {
+            attempts += 1;
+            if attempts < SEND_RETRIES {
+                interrupted()
+            } else {
+                Poll::Ready(Ok(4))
+            }
+        });
+        assert!(matches!(poll, Poll::Ready(Ok(4))));
+        assert_eq!(attempts, SEND_RETRIES);
+        assert_eq!(wakes.0.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn persistently_interrupted_sends_try_again_later() {
+        let wakes = Arc::new(CountingWaker::default());
+        let waker = Waker::from(wakes.clone());
+        let mut cx = Context::from_waker(&waker);
+        let mut attempts = 0;
+        let poll = retry_interrupted(&mut cx, |_| {
+            attempts += 1;
+            interrupted()
+        });
+        assert!(poll.is_pending());
+        assert_eq!(attempts, SEND_RETRIES);
+        assert_eq!(wakes.0.load(Ordering::Relaxed), 1);
+        assert!(!is_fatal_send_error(&io::Error::from(
+            io::ErrorKind::Interrupted
+        )));
+    }
+
+    #[test]
+    fn other_send_errors_surface_at_once() {
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut attempts = 0;
+        let poll = retry_interrupted(&mut cx, |_| {
+            attempts += 1;
+            Poll::Ready(Err(io::Error::from(io::ErrorKind::ConnectionRefused)))
+        });
+        let Poll::Ready(Err(err)) = poll else {
+            panic!("the error was not returned");
+        };
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(attempts, 1);
+        assert!(is_fatal_send_error(&err));
+    }
+
+    /// Polls `send` once, which completes it if it never waits for the
+    /// socket.
+    fn poll_once(send: impl Future<Output = io::Result<usize>>) -> Poll<io::Result<usize>> {
+        std::pin::pin!(send).poll(&mut Context::from_waker(Waker::noop()))
+    }
+
+    #[test]
+    fn sends_that_would_block_wait_for_the_socket_and_are_retried() {
+        let mut waits = 0;
+        let mut attempts = 0;
+        let poll = poll_once(send_when_writable(
+            || {
+                waits += 1;
+                std::future::ready(Ok(()))
+            },
+            || {
+                attempts += 1;
+                match attempts {
+                    1 => Err(io::Error::from(io::ErrorKind::WouldBlock)),
+                    2 => Err(io::Error::from(io::ErrorKind::Interrupted)),
+                    _ => Ok(5),
+                }
+            },
+        ));
+        assert!(matches!(poll, Poll::Ready(Ok(5))));
+        assert_eq!((waits, attempts), (3, 3));
+    }
+
+    #[test]
+    fn sends_stop_at_the_first_other_error() {
+        let mut attempts = 0;
+        let poll = poll_once(send_when_writable(
+            || std::future::ready(Ok(())),
+            || {
+                attempts += 1;
+                Err(io::Error::from(io::ErrorKind::ConnectionRefused))
+            },
+        ));
+        let Poll::Ready(Err(err)) = poll else {
+            panic!("the error was not returned");
+        };
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(attempts, 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unavailable_address_families_are_named() {
+        let addr = "[::1]:4000".parse().unwrap();
+        let err = family_unavailable(io::Error::from_raw_os_error(libc::EAFNOSUPPORT), &addr);
+        assert!(
+            err.to_string()
+                .starts_with("IPv6 is not available on this host"),
+            "{}",
+            err
+        );
+
+        let err = family_unavailable(io::Error::from(io::ErrorKind::PermissionDenied), &addr);
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(!err.to_string().contains("not available"));
+    }
+
+    #[test]
+    fn inconsistent_lengths_drop_the_datagram_and_keep_the_buffer_usable() {
+        let peer_addr = "127.0.0.1:4000".parse().unwrap();
+        let mut buf = BytesMut::from(&b"short"[..]);
+        assert_eq!(take_datagram(&mut buf, 64, peer_addr), None);
+        assert!(buf.is_empty());
+
+        buf.extend_from_slice(b"next datagram");
+        let datagram = take_datagram(&mut buf, 4, peer_addr).unwrap();
+        assert_eq!(&datagram[..], b"next");
+        assert_eq!(&buf[..], b" datagram");
+    }
+}
@@ -0,0 +1,63 @@
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
+
+use bytes::Bytes;
+use tokio::{
+    net::UdpSocket,
+    sync::{mpsc, Mutex},
+};
+
+use crate::{Incoming, PeerState, UdpStream, CHANNEL_LEN};
+
+impl UdpStream {
+    /// Creates a stream fed by `incoming` whose writes are captured, for
+    /// testing protocol logic without a peer.
+    ///
+    /// Datagrams sent on `incoming` are read from the stream as if received
+    /// from `peer_addr`, and the stream reads EOF once `incoming` is closed.
+    /// Datagrams written through `AsyncWrite`,
+    /// [`send_datagram`](Self::send_datagram) and [`send_all`](Self::send_all)
+    /// are returned by the receiver instead of being sent.
+    ///
+    /// The stream still owns a socket bound to an ephemeral loopback port,
+    /// which methods such as [`send_to_addr`](Self::send_to_addr) or
+    /// [`set_dontfrag`](Self::set_dontfrag) act on. Must be called within a
+    /// tokio runtime.
+    pub fn mock(
+        peer_addr: SocketAddr,
+        mut incoming: mpsc::Receiver<Bytes>,
+    ) -> io::Result<(Self, mpsc::UnboundedReceiver<Bytes>)> {
+        let socket = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+        socket.set_nonblocking(true)?;
+        let socket = Arc::new(UdpSocket::from_std(socket)?);
+        let local_addr = socket.local_addr()?;
+        let (child_tx, child_rx) = mpsc::channel(CHANNEL_LEN);
+        let (sink_tx, sink_rx) = mpsc::unbounded_channel();
+        let state = PeerState::new();
+        let state_inner = state.clone();
+
+        let handler = tokio::spawn(async move {
+            while let Some(payload) = incoming.recv().await {
+                state_inner.touch();
+                if child_tx.send(payload.into()).await.is_err() {
+                    break;
+                }
+            }
+            state_inner.close();
+        });
+
+        let mut udp_stream = UdpStream::new(
+            local_addr,
+            peer_addr,
+            Incoming::Channel(Mutex::new(child_rx)),
+            socket,
+            state,
+        );
+        udp_stream.handler = Some(handler);
+        udp_stream.sink = Some(sink_tx);
+        Ok((udp_stream, sink_rx))
+    }
+}
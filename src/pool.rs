@@ -0,0 +1,82 @@
+use bytes::BytesMut;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+/// Counters describing how a listener's buffer pool served receive buffers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Number of slabs that had to be freshly allocated.
+    pub allocations: u64,
+    /// Number of times a slab was reclaimed after its datagrams were dropped.
+    pub reuses: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct PoolCounters {
+    allocations: AtomicU64,
+    reuses: AtomicU64,
+}
+
+impl PoolCounters {
+    pub(crate) fn snapshot(&self) -> PoolStats {
+        PoolStats {
+            allocations: self.allocations.load(Ordering::Relaxed),
+            reuses: self.reuses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A fixed set of receive slabs that datagrams are split off from.
+///
+/// A slab can be reclaimed once every datagram split off from it has been
+/// dropped by its consumer, in which case its allocation is reused. Only when
+/// no slab can be reclaimed is a new one allocated in place of the current one.
+pub(crate) struct BufferPool {
+    slabs: Vec<BytesMut>,
+    current: usize,
+    slab_size: usize,
+    counters: Arc<PoolCounters>,
+}
+
+impl BufferPool {
+    pub(crate) fn new(slabs: usize, slab_size: usize, counters: Arc<PoolCounters>) -> Self {
+        let slabs: Vec<BytesMut> = (0..slabs.max(1))
+            .map(|_| BytesMut::with_capacity(slab_size))
+            .collect();
+        counters
+            .allocations
+            .fetch_add(slabs.len() as u64, Ordering::Relaxed);
+        Self {
+            slabs,
+            current: 0,
+            slab_size,
+            counters,
+        }
+    }
+
+    /// Returns a slab with room for at least `min` bytes.
+    pub(crate) fn buffer(&mut self, min: usize) -> &mut BytesMut {
+        if self.slabs[self.current].capacity() < min {
+            let len = self.slabs.len();
+            let reclaimed = (1..=len)
+                .map(|offset| (self.current + offset) % len)
+                .find(|&index| {
+                    let slab = &mut self.slabs[index];
+                    slab.capacity() >= min || slab.try_reclaim(self.slab_size)
+                });
+            match reclaimed {
+                Some(index) => {
+                    self.counters.reuses.fetch_add(1, Ordering::Relaxed);
+                    self.current = index;
+                }
+                None => {
+                    self.counters.allocations.fetch_add(1, Ordering::Relaxed);
+                    self.slabs[self.current] = BytesMut::with_capacity(self.slab_size.max(min));
+                }
+            }
+        }
+        &mut self.slabs[self.current]
+    }
+}
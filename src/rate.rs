@@ -0,0 +1,68 @@
+use std::{sync::Mutex, time::Duration};
+
+use tokio::time::Instant;
+
+/// A token bucket pacing the datagrams sent by a stream, see
+/// [`UdpStream::set_send_rate`](crate::UdpStream::set_send_rate).
+///
+/// The bucket holds up to a tenth of a second worth of bytes, which bounds
+/// the bursts it lets through. A datagram larger than the bucket is sent once
+/// the bucket is full and leaves it in debt, so any datagram eventually goes
+/// out at the configured rate.
+#[derive(Debug)]
+pub(crate) struct SendRate {
+    bytes_per_sec: u64,
+    capacity: f64,
+    state: Mutex<RateState>,
+}
+
+#[derive(Debug)]
+struct RateState {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl SendRate {
+    pub(crate) fn new(bytes_per_sec: u64) -> Self {
+        let capacity = (bytes_per_sec as f64 / 10.0).max(1.0);
+        Self {
+            bytes_per_sec,
+            capacity,
+            state: Mutex::new(RateState {
+                tokens: capacity,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    pub(crate) fn bytes_per_sec(&self) -> u64 {
+        self.bytes_per_sec
+    }
+
+    /// Returns how long to wait before a datagram of `len` bytes may be sent,
+    /// or `None` if it may be sent now.
+    pub(crate) fn delay(&self, len: usize) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state);
+        let needed = (len as f64).min(self.capacity);
+        if state.tokens >= needed {
+            return None;
+        }
+        let wait = (needed - state.tokens) / self.bytes_per_sec as f64;
+        Some(Duration::from_secs_f64(wait))
+    }
+
+    /// Takes `len` bytes out of the bucket once a datagram is sent.
+    pub(crate) fn consume(&self, len: usize) {
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state);
+        state.tokens -= len as f64;
+    }
+
+    fn refill(&self, state: &mut RateState) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.refilled).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * self.bytes_per_sec as f64).min(self.capacity);
+        state.refilled = now;
+    }
+}
@@ -0,0 +1,96 @@
+use std::{collections::VecDeque, fmt, sync::Arc, sync::Mutex, time::Duration};
+
+use tokio::time::Instant;
+
+/// Extracts the correlation key of a datagram, if it carries one.
+type Correlate = Arc<dyn Fn(&[u8]) -> Option<u64> + Send + Sync>;
+
+/// The number of sent datagrams awaiting a response that are remembered.
+const IN_FLIGHT: usize = 64;
+
+/// Measures round-trip times of request/response exchanges on a stream.
+///
+/// The correlation function maps a datagram to a key identifying the exchange
+/// it belongs to, such as a sequence number or request id, or `None` for
+/// datagrams that should be ignored. It is applied to every datagram written
+/// and read: a received datagram whose key matches one sent earlier completes
+/// an exchange, and the elapsed time becomes the stream's
+/// [`last_rtt`](crate::UdpStream::last_rtt).
+///
+/// Only the 64 most recent unanswered datagrams are remembered.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::{error::Error, net::SocketAddr, str::FromStr};
+/// use tokio::io::AsyncWriteExt;
+/// use udp_stream::{RttTracker, UdpStream};
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), Box<dyn Error>> {
+///     let mut stream = UdpStream::connect(SocketAddr::from_str("127.0.0.1:8080")?).await?;
+///     // The first byte of every message is its sequence number.
+///     stream.set_rtt_tracker(RttTracker::new(|datagram| {
+///         datagram.first().map(|seq| *seq as u64)
+///     }));
+///     stream.write_all(&[1, 0xff]).await?;
+///     stream.recv().await?;
+///     println!("{:?}", stream.last_rtt());
+///     Ok(())
+/// }
+/// ```
+pub struct RttTracker {
+    correlate: Correlate,
+    state: Mutex<RttState>,
+}
+
+#[derive(Default)]
+struct RttState {
+    in_flight: VecDeque<(u64, Instant)>,
+    last_rtt: Option<Duration>,
+}
+
+impl RttTracker {
+    /// Creates a tracker correlating datagrams with `correlate`.
+    pub fn new<F>(correlate: F) -> Self
+    where
+        F: Fn(&[u8]) -> Option<u64> + Send + Sync + 'static,
+    {
+        Self {
+            correlate: Arc::new(correlate),
+            state: Mutex::new(RttState::default()),
+        }
+    }
+
+    pub(crate) fn on_send(&self, datagram: &[u8]) {
+        if let Some(key) = (self.correlate)(datagram) {
+            let mut state = self.state.lock().unwrap();
+            if state.in_flight.len() == IN_FLIGHT {
+                state.in_flight.pop_front();
+            }
+            state.in_flight.push_back((key, Instant::now()));
+        }
+    }
+
+    pub(crate) fn on_recv(&self, datagram: &[u8]) {
+        if let Some(key) = (self.correlate)(datagram) {
+            let mut state = self.state.lock().unwrap();
+            if let Some(index) = state.in_flight.iter().position(|(sent, _)| *sent == key) {
+                let (_, sent_at) = state.in_flight.remove(index).unwrap();
+                state.last_rtt = Some(sent_at.elapsed());
+            }
+        }
+    }
+
+    pub(crate) fn last_rtt(&self) -> Option<Duration> {
+        self.state.lock().unwrap().last_rtt
+    }
+}
+
+impl fmt::Debug for RttTracker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RttTracker")
+            .field("last_rtt", &self.last_rtt())
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,79 @@
+use std::sync::Mutex;
+
+/// Detects lost and reordered datagrams of a stream from the sequence number
+/// they carry.
+///
+/// The sequence number is read as a big-endian unsigned integer of `width`
+/// bytes at `offset` in each received datagram; datagrams too short to hold
+/// it are ignored. A number more than one past the highest seen so far counts
+/// as a gap, and a number not past it as a reorder, which includes
+/// duplicates. Numbers wrap around at the maximum value of their width.
+///
+/// The counts are available through
+/// [`UdpStream::sequence_gaps`](crate::UdpStream::sequence_gaps) and
+/// [`UdpStream::sequence_reorders`](crate::UdpStream::sequence_reorders).
+#[derive(Debug)]
+pub struct SequenceTracker {
+    offset: usize,
+    width: usize,
+    state: Mutex<SequenceState>,
+}
+
+#[derive(Debug, Default)]
+struct SequenceState {
+    highest: Option<u64>,
+    gaps: u64,
+    reorders: u64,
+}
+
+impl SequenceTracker {
+    /// Creates a tracker reading sequence numbers of `width` bytes at
+    /// `offset`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `width` is not between 1 and 8.
+    pub fn new(offset: usize, width: usize) -> Self {
+        assert!(
+            (1..=8).contains(&width),
+            "sequence width must be between 1 and 8 bytes"
+        );
+        Self {
+            offset,
+            width,
+            state: Mutex::new(SequenceState::default()),
+        }
+    }
+
+    pub(crate) fn on_recv(&self, datagram: &[u8]) {
+        let Some(field) = datagram.get(self.offset..self.offset + self.width) else {
+            return;
+        };
+        let seq = field
+            .iter()
+            .fold(0u64, |seq, byte| (seq << 8) | *byte as u64);
+        let mask = u64::MAX >> (64 - 8 * self.width);
+        let mut state = self.state.lock().unwrap();
+        let Some(highest) = state.highest else {
+            state.highest = Some(seq);
+            return;
+        };
+        let ahead = seq.wrapping_sub(highest) & mask;
+        if ahead == 0 || ahead > mask / 2 {
+            state.reorders += 1;
+            return;
+        }
+        if ahead > 1 {
+            state.gaps += 1;
+        }
+        state.highest = Some(seq);
+    }
+
+    pub(crate) fn gaps(&self) -> u64 {
+        self.state.lock().unwrap().gaps
+    }
+
+    pub(crate) fn reorders(&self) -> u64 {
+        self.state.lock().unwrap().reorders
+    }
+}
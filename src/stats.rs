@@ -0,0 +1,170 @@
+use std::sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Arc,
+};
+
+/// A snapshot of a listener's counters, returned by
+/// [`UdpListener::stats`](crate::UdpListener::stats).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ListenerStats {
+    /// Peers currently tracked by the listener.
+    pub peers: usize,
+    /// Bytes received but not yet read by the streams. Only tracked when a
+    /// buffered bytes limit is configured.
+    pub buffered_bytes: usize,
+    /// Datagrams dropped because the buffered bytes limit was reached.
+    pub dropped_buffer_limit: u64,
+    /// Datagrams dropped because the queue of their stream was full. Always
+    /// zero with [`QueueFullPolicy::Wait`](crate::QueueFullPolicy::Wait).
+    pub dropped_queue_full: u64,
+    /// Datagrams dropped as duplicates of a recent one from the same peer, see
+    /// [`UdpListenerBuilder::dedup_window`](crate::UdpListenerBuilder::dedup_window).
+    pub duplicates_dropped: u64,
+    /// Datagrams dropped because they exceeded the maximum datagram size.
+    pub dropped_oversize: u64,
+    /// New peers dropped because the accept queue was full.
+    pub dropped_accept_full: u64,
+    /// New peers refused by the admission callback.
+    pub dropped_admission: u64,
+    /// Datagrams dropped because the codec failed to decode them.
+    pub dropped_decode: u64,
+    /// New peers dropped because their IP address already had the maximum
+    /// number of peers.
+    pub dropped_peers_per_ip: u64,
+    /// Times the queue of a stream filled up past the high watermark, see
+    /// [`UdpListenerBuilder::channel_high_watermark`](crate::UdpListenerBuilder::channel_high_watermark).
+    pub high_watermark_crossings: u64,
+    /// Datagrams dropped by the kernel because a socket receive buffer was
+    /// full, before the listener could read them. Only tracked when enabled
+    /// with [`UdpListenerBuilder::kernel_drops`](crate::UdpListenerBuilder::kernel_drops).
+    pub dropped_kernel: u64,
+    /// Datagrams dropped because their peer was
+    /// [muted](crate::UdpListener::mute).
+    pub dropped_muted: u64,
+    /// Datagrams dropped because their stream was not accepted yet, see
+    /// [`UdpListenerBuilder::lazy_accept`](crate::UdpListenerBuilder::lazy_accept).
+    pub dropped_unaccepted: u64,
+}
+
+/// A snapshot of a stream's counters, returned by
+/// [`UdpStream::stats`](crate::UdpStream::stats).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct StreamStats {
+    /// Datagrams sent to the peer.
+    pub datagrams_sent: u64,
+    /// Bytes sent to the peer, as written before any codec encodes them.
+    pub bytes_sent: u64,
+    /// Datagrams from the peer read by the application.
+    pub datagrams_received: u64,
+    /// Bytes of the datagrams from the peer read by the application.
+    pub bytes_received: u64,
+    /// Sends to the peer that failed.
+    pub send_errors: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct StreamCounters {
+    datagrams_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    datagrams_received: AtomicU64,
+    bytes_received: AtomicU64,
+    send_errors: AtomicU64,
+}
+
+impl StreamCounters {
+    pub(crate) fn snapshot(&self) -> StreamStats {
+        StreamStats {
+            datagrams_sent: self.datagrams_sent.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            datagrams_received: self.datagrams_received.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            send_errors: self.send_errors.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn on_send(&self, len: usize) {
+        self.datagrams_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_recv(&self, len: usize) {
+        self.datagrams_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_send_error(&self) {
+        self.send_errors.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct ListenerCounters {
+    pub(crate) buffered_bytes: Arc<AtomicUsize>,
+    pub(crate) dropped_buffer_limit: AtomicU64,
+    pub(crate) dropped_queue_full: AtomicU64,
+    pub(crate) duplicates_dropped: AtomicU64,
+    pub(crate) dropped_oversize: AtomicU64,
+    pub(crate) dropped_accept_full: AtomicU64,
+    pub(crate) dropped_admission: AtomicU64,
+    pub(crate) dropped_decode: AtomicU64,
+    pub(crate) dropped_peers_per_ip: AtomicU64,
+    pub(crate) high_watermark_crossings: AtomicU64,
+    pub(crate) dropped_kernel: AtomicU64,
+    pub(crate) dropped_muted: AtomicU64,
+    pub(crate) dropped_unaccepted: AtomicU64,
+}
+
+impl ListenerCounters {
+    pub(crate) fn snapshot(&self, peers: usize) -> ListenerStats {
+        ListenerStats {
+            peers,
+            buffered_bytes: self.buffered_bytes.load(Ordering::Relaxed),
+            dropped_buffer_limit: self.dropped_buffer_limit.load(Ordering::Relaxed),
+            dropped_queue_full: self.dropped_queue_full.load(Ordering::Relaxed),
+            duplicates_dropped: self.duplicates_dropped.load(Ordering::Relaxed),
+            dropped_oversize: self.dropped_oversize.load(Ordering::Relaxed),
+            dropped_accept_full: self.dropped_accept_full.load(Ordering::Relaxed),
+            dropped_admission: self.dropped_admission.load(Ordering::Relaxed),
+            dropped_decode: self.dropped_decode.load(Ordering::Relaxed),
+            dropped_peers_per_ip: self.dropped_peers_per_ip.load(Ordering::Relaxed),
+            high_watermark_crossings: self.high_watermark_crossings.load(Ordering::Relaxed),
+            dropped_kernel: self.dropped_kernel.load(Ordering::Relaxed),
+            dropped_muted: self.dropped_muted.load(Ordering::Relaxed),
+            dropped_unaccepted: self.dropped_unaccepted.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn increment(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Accounts for the bytes of a queued datagram until it is dropped.
+#[derive(Debug)]
+pub(crate) struct BufferedBytes {
+    counter: Arc<AtomicUsize>,
+    len: usize,
+}
+
+impl BufferedBytes {
+    /// Reserves `len` bytes on `counter`, failing if that would exceed `limit`.
+    pub(crate) fn reserve(counter: &Arc<AtomicUsize>, len: usize, limit: usize) -> Option<Self> {
+        counter
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |buffered| {
+                buffered.checked_add(len).filter(|total| *total <= limit)
+            })
+            .ok()?;
+        Some(Self {
+            counter: counter.clone(),
+            len,
+        })
+    }
+}
+
+impl Drop for BufferedBytes {
+    fn drop(&mut self) {
+        self.counter.fetch_sub(self.len, Ordering::AcqRel);
+    }
+}
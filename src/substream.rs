@@ -0,0 +1,163 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::UdpSocket,
+    sync::mpsc,
+    task::JoinHandle,
+};
+
+use crate::{UdpStream, CHANNEL_LEN};
+
+/// The logical sub-streams of a [`UdpStream`], split by a routing key
+/// computed from each datagram.
+///
+/// Created by [`UdpStream::demux_by`]. Sub-streams are announced by
+/// [`next`](Self::next) when the first datagram with their key arrives.
+/// Dropping this handle stops the routing, after which every sub-stream reads
+/// EOF once its queue is drained.
+#[derive(Debug)]
+pub struct DemuxBy<K> {
+    receiver: mpsc::Receiver<(K, SubStream)>,
+    handler: JoinHandle<()>,
+}
+
+impl<K> DemuxBy<K>
+where
+    K: Eq + Hash + Clone + Send + 'static,
+{
+    pub(crate) fn new<F>(stream: UdpStream, key_fn: F) -> Self
+    where
+        F: Fn(&[u8]) -> K + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel(CHANNEL_LEN);
+        let socket = stream.socket.clone();
+        let wire_addr = stream.wire_addr;
+        let handler = tokio::spawn(async move {
+            let mut routes: HashMap<K, mpsc::Sender<Bytes>> = HashMap::new();
+            while let Ok(datagram) = stream.recv().await {
+                let key = key_fn(&datagram);
+                if let Some(sender) = routes.get(&key) {
+                    match sender.try_send(datagram) {
+                        Err(mpsc::error::TrySendError::Full(_)) => {
+                            log::trace!("sub-stream queue full, dropping datagram");
+                        }
+                        // The sub-stream was dropped, so is its traffic.
+                        Ok(()) | Err(mpsc::error::TrySendError::Closed(_)) => {}
+                    }
+                    continue;
+                }
+                let (child_tx, child_rx) = mpsc::channel(CHANNEL_LEN);
+                let _ = child_tx.try_send(datagram);
+                let sub_stream = SubStream {
+                    receiver: child_rx,
+                    remaining: None,
+                    socket: socket.clone(),
+                    wire_addr,
+                };
+                if tx.try_send((key.clone(), sub_stream)).is_err() {
+                    log::debug!("sub-stream announcement dropped");
+                    continue;
+                }
+                routes.insert(key, child_tx);
+            }
+        });
+        Self {
+            receiver: rx,
+            handler,
+        }
+    }
+
+    /// Returns the next new sub-stream along with its key, or `None` once the
+    /// underlying stream is closed.
+    pub async fn next(&mut self) -> Option<(K, SubStream)> {
+        self.receiver.recv().await
+    }
+}
+
+impl<K> Drop for DemuxBy<K> {
+    fn drop(&mut self) {
+        self.handler.abort();
+    }
+}
+
+/// The datagrams of a [`UdpStream`] sharing one routing key.
+///
+/// Writes are sent to the peer of the original stream as they are, so the
+/// application must include the key in them if the peer expects it.
+///
+/// See [`UdpStream::demux_by`].
+#[derive(Debug)]
+pub struct SubStream {
+    receiver: mpsc::Receiver<Bytes>,
+    remaining: Option<Bytes>,
+    socket: Arc<UdpSocket>,
+    wire_addr: SocketAddr,
+}
+
+impl SubStream {
+    /// Receives the next datagram of the sub-stream.
+    ///
+    /// Fails with `BrokenPipe` once the sub-stream is closed.
+    pub async fn recv(&mut self) -> io::Result<Bytes> {
+        self.receiver
+            .recv()
+            .await
+            .ok_or(io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+}
+
+impl AsyncRead for SubStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            let mut datagram = match this.remaining.take() {
+                Some(remaining) => remaining,
+                None => match this.receiver.poll_recv(cx) {
+                    // A zero-length datagram would read as EOF, so it is skipped.
+                    Poll::Ready(Some(datagram)) if datagram.is_empty() => continue,
+                    Poll::Ready(Some(datagram)) => datagram,
+                    // The sub-stream was closed, which reads as EOF.
+                    Poll::Ready(None) => return Poll::Ready(Ok(())),
+                    Poll::Pending => return Poll::Pending,
+                },
+            };
+            if buf.remaining() < datagram.len() {
+                this.remaining = Some(datagram.split_off(buf.remaining()));
+            }
+            buf.put_slice(&datagram);
+            return Poll::Ready(Ok(()));
+        }
+    }
+}
+
+impl AsyncWrite for SubStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.socket.poll_send_to(cx, buf, self.wire_addr)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
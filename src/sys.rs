@@ -0,0 +1,704 @@
+//! Socket options not exposed by tokio or the standard library.
+//!
+//! Options are set through [`socket2`] where it supports them. The few it does
+//! not, and `sendmmsg`, go through `libc` directly; control messages are
+//! encoded and decoded here.
+
+use bytes::BytesMut;
+use socket2::SockRef;
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::SystemTime,
+};
+
+use tokio::net::UdpSocket;
+
+/// Reads an int socket option socket2 has no accessor for.
+#[cfg(target_os = "linux")]
+fn getsockopt_int(
+    socket: &UdpSocket,
+    level: libc::c_int,
+    name: libc::c_int,
+) -> io::Result<libc::c_int> {
+    use std::os::fd::AsRawFd;
+
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: `value` and `len` are valid for writes and describe an int.
+    let ret = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(value)
+}
+
+/// Sets an int socket option socket2 has no accessor for.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd"
+))]
+fn setsockopt_int(
+    socket: &UdpSocket,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: `value` is valid for reads of an int for the duration of the
+    // call.
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Returns the kernel's current path MTU estimate for a connected socket.
+#[cfg(target_os = "linux")]
+pub(crate) fn path_mtu(socket: &UdpSocket) -> io::Result<usize> {
+    let mtu = if socket.local_addr()?.is_ipv6() {
+        getsockopt_int(socket, libc::IPPROTO_IPV6, libc::IPV6_MTU)?
+    } else {
+        getsockopt_int(socket, libc::IPPROTO_IP, libc::IP_MTU)?
+    };
+    Ok(mtu as usize)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn path_mtu(_socket: &UdpSocket) -> io::Result<usize> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "path MTU is not available on this platform",
+    ))
+}
+
+/// Binds `count` sockets to `addr` with `SO_REUSEPORT`, so that the kernel
+/// spreads incoming datagrams between them by source address.
+///
+/// When the port of `addr` is zero, the port picked for the first socket is
+/// used for the others.
+#[cfg(target_os = "linux")]
+pub(crate) fn bind_reuseport(
+    addr: SocketAddr,
+    count: usize,
+) -> io::Result<Vec<std::net::UdpSocket>> {
+    use socket2::{Domain, Socket, Type};
+
+    let mut addr = addr;
+    let mut sockets = Vec::with_capacity(count);
+    for _ in 0..count {
+        let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, None)?;
+        socket.set_reuse_port(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        let socket = std::net::UdpSocket::from(socket);
+        addr = socket.local_addr()?;
+        sockets.push(socket);
+    }
+    Ok(sockets)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn bind_reuseport(
+    _addr: SocketAddr,
+    _count: usize,
+) -> io::Result<Vec<std::net::UdpSocket>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "several demux workers require SO_REUSEPORT load balancing, only available on Linux",
+    ))
+}
+
+/// Sets or clears the don't-fragment bit on outgoing datagrams.
+///
+/// On Linux this switches `IP_MTU_DISCOVER`/`IPV6_MTU_DISCOVER` between
+/// `PMTUDISC_DO` and the default `PMTUDISC_WANT`.
+#[cfg(target_os = "linux")]
+pub(crate) fn set_dontfrag(socket: &UdpSocket, dontfrag: bool) -> io::Result<()> {
+    if socket.local_addr()?.is_ipv6() {
+        let value = if dontfrag {
+            libc::IPV6_PMTUDISC_DO
+        } else {
+            libc::IPV6_PMTUDISC_WANT
+        };
+        setsockopt_int(socket, libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER, value)
+    } else {
+        let value = if dontfrag {
+            libc::IP_PMTUDISC_DO
+        } else {
+            libc::IP_PMTUDISC_WANT
+        };
+        setsockopt_int(socket, libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, value)
+    }
+}
+
+/// Sets or clears the don't-fragment bit on outgoing datagrams.
+///
+/// On macOS and FreeBSD this sets `IP_DONTFRAG`/`IPV6_DONTFRAG`.
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd"))]
+pub(crate) fn set_dontfrag(socket: &UdpSocket, dontfrag: bool) -> io::Result<()> {
+    if socket.local_addr()?.is_ipv6() {
+        setsockopt_int(
+            socket,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_DONTFRAG,
+            dontfrag as libc::c_int,
+        )
+    } else {
+        setsockopt_int(
+            socket,
+            libc::IPPROTO_IP,
+            libc::IP_DONTFRAG,
+            dontfrag as libc::c_int,
+        )
+    }
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd"
+)))]
+pub(crate) fn set_dontfrag(_socket: &UdpSocket, _dontfrag: bool) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "don't-fragment control is not available on this platform",
+    ))
+}
+
+/// Sets the interface outgoing IPv4 multicast datagrams are sent from.
+pub(crate) fn set_multicast_if_v4(socket: &UdpSocket, interface: Ipv4Addr) -> io::Result<()> {
+    SockRef::from(socket).set_multicast_if_v4(&interface)
+}
+
+/// Sets the index of the interface outgoing IPv6 multicast datagrams are sent
+/// from.
+pub(crate) fn set_multicast_if_v6(socket: &UdpSocket, interface: u32) -> io::Result<()> {
+    SockRef::from(socket).set_multicast_if_v6(interface)
+}
+
+/// The Explicit Congestion Notification codepoint of a datagram, carried in
+/// the two low bits of the IPv4 TOS or IPv6 traffic class field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Ecn {
+    /// Not ECN-capable transport.
+    NotEct,
+    /// ECN-capable transport, codepoint `ECT(1)`.
+    Ect1,
+    /// ECN-capable transport, codepoint `ECT(0)`.
+    Ect0,
+    /// Congestion experienced.
+    Ce,
+}
+
+impl Ecn {
+    /// Extracts the codepoint from a TOS or traffic class value.
+    pub fn from_tos(tos: u8) -> Self {
+        match tos & 0b11 {
+            0b00 => Ecn::NotEct,
+            0b01 => Ecn::Ect1,
+            0b10 => Ecn::Ect0,
+            _ => Ecn::Ce,
+        }
+    }
+
+    /// Returns the codepoint as the two low bits of a TOS value.
+    pub fn to_tos(self) -> u8 {
+        match self {
+            Ecn::NotEct => 0b00,
+            Ecn::Ect1 => 0b01,
+            Ecn::Ect0 => 0b10,
+            Ecn::Ce => 0b11,
+        }
+    }
+}
+
+/// The metadata of a received datagram, returned by
+/// [`UdpStream::recv_meta`](crate::UdpStream::recv_meta).
+///
+/// Fields other than the source address are only reported when the listener
+/// that accepted the stream was built to record them, and are `None`
+/// otherwise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DatagramMeta {
+    /// The address of the peer the datagram came from.
+    pub source: SocketAddr,
+    /// The local address the datagram was sent to, see
+    /// [`UdpListenerBuilder::pktinfo`](crate::UdpListenerBuilder::pktinfo).
+    pub local_addr: Option<SocketAddr>,
+    /// The kernel receive timestamp, see
+    /// [`UdpListenerBuilder::timestamps`](crate::UdpListenerBuilder::timestamps).
+    pub timestamp: Option<SystemTime>,
+    /// The ECN codepoint, see
+    /// [`UdpListenerBuilder::ecn`](crate::UdpListenerBuilder::ecn).
+    pub ecn: Option<Ecn>,
+}
+
+/// Metadata carried by the control messages of a received datagram.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct RecvMeta {
+    pub(crate) timestamp: Option<SystemTime>,
+    pub(crate) ecn: Option<Ecn>,
+    /// The destination address of the datagram, without the port.
+    pub(crate) local_ip: Option<IpAddr>,
+    /// The number of datagrams the kernel dropped on the socket so far.
+    pub(crate) kernel_drops: Option<u32>,
+}
+
+/// The receive metadata enabled on the sockets of a listener.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct MetaOptions {
+    pub(crate) timestamps: bool,
+    pub(crate) ecn: bool,
+    pub(crate) pktinfo: bool,
+    pub(crate) kernel_drops: bool,
+}
+
+impl MetaOptions {
+    /// Returns whether any metadata must be read along with datagrams.
+    pub(crate) fn any(&self) -> bool {
+        self.timestamps || self.ecn || self.pktinfo || self.kernel_drops
+    }
+
+    /// Enables the selected metadata on `socket`.
+    pub(crate) fn enable(&self, socket: &UdpSocket) -> io::Result<()> {
+        if self.timestamps {
+            enable_timestamps(socket)?;
+        }
+        if self.ecn {
+            enable_ecn(socket)?;
+        }
+        if self.pktinfo {
+            enable_pktinfo(socket)?;
+        }
+        if self.kernel_drops {
+            enable_kernel_drops(socket)?;
+        }
+        Ok(())
+    }
+}
+
+/// Asks the kernel to attach a receive timestamp to every datagram.
+#[cfg(target_os = "linux")]
+pub(crate) fn enable_timestamps(socket: &UdpSocket) -> io::Result<()> {
+    setsockopt_int(socket, libc::SOL_SOCKET, libc::SO_TIMESTAMPNS, 1)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn enable_timestamps(_socket: &UdpSocket) -> io::Result<()> {
+    log::debug!("kernel receive timestamps are not supported on this platform");
+    Ok(())
+}
+
+/// Asks the kernel to report the ECN codepoint of every datagram.
+///
+/// IPv6 sockets also enable `IP_RECVTOS`, so that IPv4 peers of a dual-stack
+/// socket are covered.
+#[cfg(target_os = "linux")]
+pub(crate) fn enable_ecn(socket: &UdpSocket) -> io::Result<()> {
+    let sock = SockRef::from(socket);
+    if socket.local_addr()?.is_ipv6() {
+        sock.set_recv_tclass_v6(true)?;
+        if let Err(err) = sock.set_recv_tos_v4(true) {
+            log::debug!("IP_RECVTOS on an IPv6 socket failed: {}", err);
+        }
+        Ok(())
+    } else {
+        sock.set_recv_tos_v4(true)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn enable_ecn(_socket: &UdpSocket) -> io::Result<()> {
+    log::debug!("receiving ECN codepoints is not supported on this platform");
+    Ok(())
+}
+
+/// Asks the kernel to report the destination address of every datagram.
+///
+/// IPv6 sockets also enable `IP_PKTINFO`, so that IPv4 peers of a dual-stack
+/// socket are covered.
+#[cfg(target_os = "linux")]
+pub(crate) fn enable_pktinfo(socket: &UdpSocket) -> io::Result<()> {
+    if socket.local_addr()?.is_ipv6() {
+        setsockopt_int(socket, libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO, 1)?;
+        if let Err(err) = setsockopt_int(socket, libc::IPPROTO_IP, libc::IP_PKTINFO, 1) {
+            log::debug!("IP_PKTINFO on an IPv6 socket failed: {}", err);
+        }
+        Ok(())
+    } else {
+        setsockopt_int(socket, libc::IPPROTO_IP, libc::IP_PKTINFO, 1)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn enable_pktinfo(_socket: &UdpSocket) -> io::Result<()> {
+    log::debug!("receiving destination addresses is not supported on this platform");
+    Ok(())
+}
+
+/// Asks the kernel to report how many datagrams it dropped on the socket.
+#[cfg(target_os = "linux")]
+pub(crate) fn enable_kernel_drops(socket: &UdpSocket) -> io::Result<()> {
+    setsockopt_int(socket, libc::SOL_SOCKET, libc::SO_RXQ_OVFL, 1)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn enable_kernel_drops(_socket: &UdpSocket) -> io::Result<()> {
+    log::debug!("counting kernel drops is not supported on this platform");
+    Ok(())
+}
+
+/// Tries to send a datagram to `addr` with the ECN codepoint `ecn`, passed as
+/// an `IP_TOS` or `IPV6_TCLASS` control message, failing with `WouldBlock` if
+/// the socket is not writable.
+#[cfg(target_os = "linux")]
+pub(crate) fn try_send_with_ecn(
+    socket: &UdpSocket,
+    buf: &[u8],
+    addr: SocketAddr,
+    ecn: Ecn,
+) -> io::Result<usize> {
+    socket.try_io(tokio::io::Interest::WRITABLE, || {
+        sendmsg(socket, buf, addr, ecn)
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn try_send_with_ecn(
+    _socket: &UdpSocket,
+    _buf: &[u8],
+    _addr: SocketAddr,
+    _ecn: Ecn,
+) -> io::Result<usize> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "sending ECN codepoints is not available on this platform",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn sendmsg(socket: &UdpSocket, buf: &[u8], addr: SocketAddr, ecn: Ecn) -> io::Result<usize> {
+    use socket2::{MsgHdr, SockAddr};
+    use std::io::IoSlice;
+
+    let (level, kind) = if addr.is_ipv6() {
+        (libc::IPPROTO_IPV6, libc::IPV6_TCLASS)
+    } else {
+        (libc::IPPROTO_IP, libc::IP_TOS)
+    };
+    let tos = libc::c_int::from(ecn.to_tos());
+    let control = cmsg::encode(level, kind, &tos.to_ne_bytes());
+    let addr = SockAddr::from(addr);
+    let bufs = [IoSlice::new(buf)];
+    let msg = MsgHdr::new()
+        .with_addr(&addr)
+        .with_buffers(&bufs)
+        .with_control(&control);
+    SockRef::from(socket).sendmsg(&msg, 0)
+}
+
+/// The most datagrams handed to a single `sendmmsg` call.
+#[cfg(target_os = "linux")]
+const SEND_BATCH: usize = 64;
+
+/// Sends the leading `datagrams` to `addr` with a single `sendmmsg` call once
+/// the socket is writable, and returns how many were sent.
+///
+/// An error is only returned if the first datagram fails; a later failure
+/// cuts the batch short and is reported by the next call.
+#[cfg(target_os = "linux")]
+pub(crate) async fn send_batch(
+    socket: &UdpSocket,
+    datagrams: &[&[u8]],
+    addr: SocketAddr,
+) -> io::Result<usize> {
+    socket
+        .async_io(tokio::io::Interest::WRITABLE, || {
+            sendmmsg(socket, datagrams, addr)
+        })
+        .await
+}
+
+/// Sends `datagrams` to `addr` with `sendmmsg`, which socket2 does not wrap.
+#[cfg(target_os = "linux")]
+fn sendmmsg(socket: &UdpSocket, datagrams: &[&[u8]], addr: SocketAddr) -> io::Result<usize> {
+    use std::os::fd::AsRawFd;
+
+    let datagrams = &datagrams[..datagrams.len().min(SEND_BATCH)];
+    let addr = socket2::SockAddr::from(addr);
+    let mut iovs: Vec<libc::iovec> = datagrams
+        .iter()
+        .map(|datagram| libc::iovec {
+            iov_base: datagram.as_ptr() as *mut libc::c_void,
+            iov_len: datagram.len(),
+        })
+        .collect();
+    let mut msgs: Vec<libc::mmsghdr> = iovs
+        .iter_mut()
+        .map(|iov| {
+            // SAFETY: all-zero is a valid `mmsghdr`.
+            let mut msg: libc::mmsghdr = unsafe { std::mem::zeroed() };
+            msg.msg_hdr.msg_name = addr.as_ptr() as *mut libc::c_void;
+            msg.msg_hdr.msg_namelen = addr.len();
+            msg.msg_hdr.msg_iov = iov;
+            msg.msg_hdr.msg_iovlen = 1;
+            msg
+        })
+        .collect();
+
+    // SAFETY: every header refers to the live address and to one live buffer
+    // of the given size, which the kernel only reads.
+    let sent = unsafe {
+        libc::sendmmsg(
+            socket.as_raw_fd(),
+            msgs.as_mut_ptr(),
+            msgs.len() as libc::c_uint,
+            0,
+        )
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(sent as usize)
+}
+
+/// Receives a datagram into the spare capacity of `buf` along with the
+/// metadata enabled on the socket, without waiting for readiness.
+#[cfg(target_os = "linux")]
+pub(crate) fn try_recv_with_meta(
+    socket: &UdpSocket,
+    buf: &mut BytesMut,
+) -> io::Result<(usize, SocketAddr, RecvMeta)> {
+    socket.try_io(tokio::io::Interest::READABLE, || recvmsg(socket, buf))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn try_recv_with_meta(
+    socket: &UdpSocket,
+    buf: &mut BytesMut,
+) -> io::Result<(usize, SocketAddr, RecvMeta)> {
+    let (len, peer_addr) = socket.try_recv_buf_from(buf)?;
+    Ok((len, peer_addr, RecvMeta::default()))
+}
+
+/// Room for every control message enabled by [`MetaOptions`].
+#[cfg(target_os = "linux")]
+const CONTROL_LEN: usize = 128;
+
+#[cfg(target_os = "linux")]
+fn recvmsg(socket: &UdpSocket, buf: &mut BytesMut) -> io::Result<(usize, SocketAddr, RecvMeta)> {
+    use bytes::BufMut;
+    use socket2::{MaybeUninitSlice, MsgHdrMut, SockAddr};
+    use std::mem::MaybeUninit;
+
+    // Large enough for an address of either family, which the kernel
+    // overwrites along with the family.
+    let mut source = SockAddr::from(SocketAddr::from((std::net::Ipv6Addr::UNSPECIFIED, 0)));
+    let mut control = [MaybeUninit::<u8>::uninit(); CONTROL_LEN];
+    let mut bufs = [MaybeUninitSlice::new(buf.spare_capacity_mut())];
+    let mut msg = MsgHdrMut::new()
+        .with_addr(&mut source)
+        .with_buffers(&mut bufs)
+        .with_control(&mut control);
+    let len = SockRef::from(socket).recvmsg(&mut msg, 0)?;
+    let control_len = msg.control_len();
+    // SAFETY: the kernel initialized the first `len` bytes of the spare
+    // capacity.
+    unsafe { buf.advance_mut(len) };
+    // SAFETY: the kernel initialized the first `control_len` bytes of
+    // `control`.
+    let control = unsafe { std::slice::from_raw_parts(control.as_ptr() as *const u8, control_len) };
+
+    let peer_addr = source
+        .as_socket()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unsupported address family"))?;
+    Ok((len, peer_addr, cmsg::decode(control)))
+}
+
+/// Encoding and decoding of control messages, laid out as the kernel does:
+/// a `cmsghdr` of a `size_t` length and two ints, followed by the data, each
+/// aligned to a `size_t`.
+#[cfg(target_os = "linux")]
+mod cmsg {
+    use std::{
+        mem::{offset_of, size_of},
+        net::{Ipv4Addr, Ipv6Addr},
+        time::{Duration, SystemTime},
+    };
+
+    use super::{Ecn, RecvMeta};
+
+    const fn align(len: usize) -> usize {
+        (len + size_of::<usize>() - 1) & !(size_of::<usize>() - 1)
+    }
+
+    /// The offset of the data in a control message.
+    const DATA: usize = align(size_of::<libc::cmsghdr>());
+    const LEVEL: usize = offset_of!(libc::cmsghdr, cmsg_level);
+    const KIND: usize = offset_of!(libc::cmsghdr, cmsg_type);
+
+    fn read_int(bytes: &[u8], offset: usize) -> Option<libc::c_int> {
+        let bytes = bytes.get(offset..offset + size_of::<libc::c_int>())?;
+        Some(libc::c_int::from_ne_bytes(bytes.try_into().ok()?))
+    }
+
+    /// Returns a buffer holding a single control message.
+    pub(super) fn encode(level: libc::c_int, kind: libc::c_int, data: &[u8]) -> Vec<u8> {
+        let mut control = vec![0; DATA + align(data.len())];
+        control[..size_of::<usize>()].copy_from_slice(&(DATA + data.len()).to_ne_bytes());
+        control[LEVEL..LEVEL + size_of::<libc::c_int>()].copy_from_slice(&level.to_ne_bytes());
+        control[KIND..KIND + size_of::<libc::c_int>()].copy_from_slice(&kind.to_ne_bytes());
+        control[DATA..DATA + data.len()].copy_from_slice(data);
+        control
+    }
+
+    /// Collects the metadata found in the control messages `recvmsg` wrote to
+    /// `control`, ignoring unknown and malformed ones.
+    pub(super) fn decode(mut control: &[u8]) -> RecvMeta {
+        let mut meta = RecvMeta::default();
+        while control.len() >= DATA {
+            let len = usize::from_ne_bytes(control[..size_of::<usize>()].try_into().unwrap());
+            let (Some(level), Some(kind)) = (read_int(control, LEVEL), read_int(control, KIND))
+            else {
+                break;
+            };
+            if len < DATA || len > control.len() {
+                break;
+            }
+            decode_one(&mut meta, level, kind, &control[DATA..len]);
+            control = control.get(align(len)..).unwrap_or_default();
+        }
+        meta
+    }
+
+    fn decode_one(meta: &mut RecvMeta, level: libc::c_int, kind: libc::c_int, data: &[u8]) {
+        match (level, kind) {
+            (libc::SOL_SOCKET, libc::SCM_TIMESTAMPNS) => {
+                // A `timespec` of two longs, 32 bits wide on older 32-bit
+                // targets.
+                let (secs, nanos) = match data.len() {
+                    16 => (
+                        i64::from_ne_bytes(data[..8].try_into().unwrap()),
+                        i64::from_ne_bytes(data[8..].try_into().unwrap()),
+                    ),
+                    8 => (
+                        i32::from_ne_bytes(data[..4].try_into().unwrap()).into(),
+                        i32::from_ne_bytes(data[4..].try_into().unwrap()).into(),
+                    ),
+                    _ => return,
+                };
+                meta.timestamp =
+                    Some(SystemTime::UNIX_EPOCH + Duration::new(secs as u64, nanos as u32));
+            }
+            (libc::SOL_SOCKET, libc::SO_RXQ_OVFL) => {
+                if let Ok(drops) = data.try_into() {
+                    meta.kernel_drops = Some(u32::from_ne_bytes(drops));
+                }
+            }
+            // A single byte for IPv4, an int for IPv6.
+            (libc::IPPROTO_IP, libc::IP_TOS) => {
+                if let Some(tos) = data.first() {
+                    meta.ecn = Some(Ecn::from_tos(*tos));
+                }
+            }
+            (libc::IPPROTO_IPV6, libc::IPV6_TCLASS) => {
+                if let Some(tclass) = read_int(data, 0) {
+                    meta.ecn = Some(Ecn::from_tos(tclass as u8));
+                }
+            }
+            // An `in_pktinfo` of the interface index, then the local and the
+            // destination addresses.
+            (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+                if let Some(addr) = data.get(8..12) {
+                    let octets: [u8; 4] = addr.try_into().unwrap();
+                    meta.local_ip = Some(Ipv4Addr::from(octets).into());
+                }
+            }
+            // An `in6_pktinfo` of the destination address, then the interface
+            // index.
+            (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                if let Some(addr) = data.get(..16) {
+                    let octets: [u8; 16] = addr.try_into().unwrap();
+                    meta.local_ip = Some(Ipv6Addr::from(octets).into());
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_every_known_control_message() {
+        let mut control = cmsg::encode(libc::SOL_SOCKET, libc::SO_RXQ_OVFL, &7u32.to_ne_bytes());
+        control.extend(cmsg::encode(
+            libc::IPPROTO_IPV6,
+            libc::IPV6_TCLASS,
+            &0b10i32.to_ne_bytes(),
+        ));
+        let mut timespec = 1_700_000_000i64.to_ne_bytes().to_vec();
+        timespec.extend(5i64.to_ne_bytes());
+        control.extend(cmsg::encode(
+            libc::SOL_SOCKET,
+            libc::SCM_TIMESTAMPNS,
+            &timespec,
+        ));
+        let mut pktinfo = 3i32.to_ne_bytes().to_vec();
+        pktinfo.extend([0; 4]);
+        pktinfo.extend([127, 0, 0, 2]);
+        control.extend(cmsg::encode(libc::IPPROTO_IP, libc::IP_PKTINFO, &pktinfo));
+
+        let meta = cmsg::decode(&control);
+        assert_eq!(meta.kernel_drops, Some(7));
+        assert_eq!(meta.ecn, Some(Ecn::Ect0));
+        assert_eq!(
+            meta.timestamp,
+            Some(SystemTime::UNIX_EPOCH + std::time::Duration::new(1_700_000_000, 5))
+        );
+        assert_eq!(meta.local_ip, Some(Ipv4Addr::new(127, 0, 0, 2).into()));
+    }
+
+    #[test]
+    fn decodes_the_single_byte_ipv4_tos() {
+        let meta = cmsg::decode(&cmsg::encode(libc::IPPROTO_IP, libc::IP_TOS, &[0b11]));
+        assert_eq!(meta.ecn, Some(Ecn::Ce));
+    }
+
+    #[test]
+    fn ignores_truncated_and_unknown_control_messages() {
+        let mut control = cmsg::encode(libc::SOL_SOCKET, libc::SO_RXQ_OVFL, &7u32.to_ne_bytes());
+        control.truncate(control.len() - 6);
+        assert_eq!(cmsg::decode(&control).kernel_drops, None);
+
+        let unknown = cmsg::encode(libc::SOL_SOCKET, libc::SO_MARK, &1u32.to_ne_bytes());
+        let meta = cmsg::decode(&unknown);
+        assert!(meta.kernel_drops.is_none() && meta.ecn.is_none());
+        assert!(cmsg::decode(&[]).timestamp.is_none());
+    }
+}
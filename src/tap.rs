@@ -0,0 +1,73 @@
+use bytes::Bytes;
+use std::{io, net::SocketAddr, sync::Mutex, time::SystemTime};
+
+use crate::{sys::RecvMeta, Datagram, UdpListener};
+
+/// A datagram received by a listener, as seen by a [`DatagramTap`].
+#[derive(Debug, Clone)]
+pub struct RecordedDatagram {
+    /// When the datagram was received.
+    pub timestamp: SystemTime,
+    /// The address the datagram was sent from.
+    pub source: SocketAddr,
+    /// The datagram contents.
+    pub payload: Bytes,
+}
+
+/// A sink observing every datagram received by a listener.
+///
+/// Taps are installed with
+/// [`UdpListenerBuilder::tap`](crate::UdpListenerBuilder::tap) and are invoked
+/// from the listener's receive loop, so implementations should return quickly.
+pub trait DatagramTap: Send + Sync {
+    /// Called for each datagram received on the listener's sockets.
+    fn record(&self, datagram: &RecordedDatagram);
+}
+
+/// A [`DatagramTap`] keeping every recorded datagram in memory.
+#[derive(Debug, Default)]
+pub struct MemoryTap {
+    records: Mutex<Vec<RecordedDatagram>>,
+}
+
+impl MemoryTap {
+    /// Creates an empty `MemoryTap`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the datagrams recorded so far, in the order they were received.
+    pub fn records(&self) -> Vec<RecordedDatagram> {
+        self.records.lock().unwrap().clone()
+    }
+}
+
+impl DatagramTap for MemoryTap {
+    fn record(&self, datagram: &RecordedDatagram) {
+        self.records.lock().unwrap().push(datagram.clone());
+    }
+}
+
+/// Feeds previously recorded datagrams into `listener` as if they had arrived
+/// on its socket.
+///
+/// Datagrams are replayed in order and without the original delays. Replayed
+/// datagrams are not passed to the listener's tap.
+pub async fn replay<I>(records: I, listener: &UdpListener) -> io::Result<()>
+where
+    I: IntoIterator<Item = RecordedDatagram>,
+{
+    for record in records {
+        let datagram = Datagram {
+            payload: record.payload,
+            meta: RecvMeta {
+                timestamp: Some(record.timestamp),
+                ..RecvMeta::default()
+            },
+            buffered: None,
+            unreachable: None,
+        };
+        listener.inject(datagram, record.source).await?;
+    }
+    Ok(())
+}
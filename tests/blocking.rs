@@ -0,0 +1,58 @@
+//! Streams driven from synchronous code with the `blocking` feature.
+#![cfg(feature = "blocking")]
+
+mod common;
+
+use std::{
+    io::{Read, Write},
+    net::UdpSocket as StdUdpSocket,
+};
+
+use common::{client, localhost, recv, PATIENCE};
+use tokio::runtime::Runtime;
+use udp_stream::{BlockingUdpStream, UdpListener};
+
+fn runtime() -> Runtime {
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build()
+        .unwrap()
+}
+
+#[test]
+fn connected_streams_round_trip_synchronously() {
+    let runtime = runtime();
+    let server = StdUdpSocket::bind(localhost()).unwrap();
+    server.set_read_timeout(Some(PATIENCE)).unwrap();
+    let mut stream =
+        BlockingUdpStream::connect(server.local_addr().unwrap(), runtime.handle().clone()).unwrap();
+
+    stream.write_all(b"ping").unwrap();
+    stream.flush().unwrap();
+    let mut buf = [0u8; 16];
+    let (len, client_addr) = server.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..len], b"ping");
+    assert_eq!(client_addr, stream.get_ref().local_addr().unwrap());
+
+    server.send_to(b"pong", client_addr).unwrap();
+    let len = stream.read(&mut buf).unwrap();
+    assert_eq!(&buf[..len], b"pong");
+}
+
+#[test]
+fn accepted_streams_round_trip_synchronously() {
+    let runtime = runtime();
+    let listener = runtime.block_on(UdpListener::bind(localhost())).unwrap();
+    let client = client(listener.local_addr().unwrap());
+    client.send(b"ping").unwrap();
+    let (stream, _) = runtime.block_on(listener.accept()).unwrap();
+    let mut stream = BlockingUdpStream::new(stream, runtime.handle().clone());
+
+    let mut buf = [0u8; 16];
+    let len = stream.read(&mut buf).unwrap();
+    assert_eq!(&buf[..len], b"ping");
+    stream.write_all(b"pong").unwrap();
+    stream.flush().unwrap();
+    assert_eq!(recv(&client), b"pong");
+}
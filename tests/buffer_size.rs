@@ -0,0 +1,97 @@
+//! The receive buffer size set through `UDP_STREAM_BUFFER_SIZE`.
+//!
+//! The variable is read once, when the first socket is set up, so these tests
+//! have a binary of their own and all set the same value before creating
+//! sockets.
+
+use std::{
+    net::{SocketAddr, UdpSocket as StdUdpSocket},
+    sync::Once,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UdpSocket,
+};
+use udp_stream::{UdpListener, UdpStream};
+
+/// Larger than the default buffer size, and than what fits in it.
+const LARGE: usize = 60_000;
+
+fn large_buffers() {
+    static ONCE: Once = Once::new();
+    ONCE.call_once(|| std::env::set_var("UDP_STREAM_BUFFER_SIZE", "65507"));
+}
+
+fn localhost() -> SocketAddr {
+    "127.0.0.1:0".parse().unwrap()
+}
+
+/// Answers the first datagram received on `server` with a `LARGE` one.
+async fn reply_large(server: UdpSocket) {
+    let mut buf = [0u8; 16];
+    let (_, client_addr) = server.recv_from(&mut buf).await.unwrap();
+    server
+        .send_to(&vec![7u8; LARGE], client_addr)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn listener_receives_datagrams_up_to_the_configured_size() {
+    large_buffers();
+    let listener = UdpListener::bind(localhost()).await.unwrap();
+    let client = StdUdpSocket::bind(localhost()).unwrap();
+    client
+        .send_to(&vec![7u8; LARGE], listener.local_addr().unwrap())
+        .unwrap();
+
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let mut buf = vec![0u8; 65536];
+    assert_eq!(stream.read(&mut buf).await.unwrap(), LARGE);
+}
+
+#[tokio::test]
+async fn peek_next_sees_datagrams_up_to_the_configured_size() {
+    large_buffers();
+    let listener = UdpListener::builder()
+        .stateless(true)
+        .bind(localhost())
+        .await
+        .unwrap();
+    let client = StdUdpSocket::bind(localhost()).unwrap();
+    client
+        .send_to(&vec![7u8; LARGE], listener.local_addr().unwrap())
+        .unwrap();
+
+    let (payload, _) = listener.peek_next().await.unwrap();
+    assert_eq!(payload.len(), LARGE);
+}
+
+#[tokio::test]
+async fn connect_receives_datagrams_up_to_the_configured_size() {
+    large_buffers();
+    let server = UdpSocket::bind(localhost()).await.unwrap();
+    let mut stream = UdpStream::connect(server.local_addr().unwrap())
+        .await
+        .unwrap();
+    stream.write_all(b"hello").await.unwrap();
+    reply_large(server).await;
+
+    let mut buf = vec![0u8; 65536];
+    assert_eq!(stream.read(&mut buf).await.unwrap(), LARGE);
+}
+
+#[tokio::test]
+async fn connect_direct_receives_datagrams_up_to_the_configured_size() {
+    large_buffers();
+    let server = UdpSocket::bind(localhost()).await.unwrap();
+    let mut stream = UdpStream::connect_direct(server.local_addr().unwrap())
+        .await
+        .unwrap();
+    stream.write_all(b"hello").await.unwrap();
+    reply_large(server).await;
+
+    let mut buf = vec![0u8; 65536];
+    assert_eq!(stream.read(&mut buf).await.unwrap(), LARGE);
+}
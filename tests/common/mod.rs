@@ -0,0 +1,65 @@
+//! Helpers shared by the integration tests.
+#![allow(dead_code)]
+
+use std::{
+    net::{SocketAddr, UdpSocket as StdUdpSocket},
+    time::Duration,
+};
+
+use tokio::{io::AsyncReadExt, net::UdpSocket};
+use udp_stream::UdpStream;
+
+/// How long a test waits for something that should happen right away.
+pub const PATIENCE: Duration = Duration::from_secs(2);
+
+/// How long a test lets datagrams already sent on localhost reach their
+/// destination.
+pub const SETTLE: Duration = Duration::from_millis(50);
+
+pub fn localhost() -> SocketAddr {
+    "127.0.0.1:0".parse().unwrap()
+}
+
+/// Binds a blocking socket on localhost connected to `addr`.
+pub fn client(addr: SocketAddr) -> StdUdpSocket {
+    let socket = StdUdpSocket::bind(localhost()).unwrap();
+    socket.connect(addr).unwrap();
+    socket
+}
+
+/// Binds a socket on localhost for a test to play the server.
+pub async fn server() -> UdpSocket {
+    UdpSocket::bind(localhost()).await.unwrap()
+}
+
+/// Reads the next datagram from `stream`, failing if none arrives in time.
+pub async fn read_datagram(stream: &mut UdpStream) -> Vec<u8> {
+    let mut buf = vec![0u8; 65536];
+    let len = tokio::time::timeout(PATIENCE, stream.read(&mut buf))
+        .await
+        .expect("no datagram received")
+        .unwrap();
+    buf.truncate(len);
+    buf
+}
+
+/// Receives the next datagram on `socket`, failing if none arrives in time.
+pub async fn recv_from(socket: &UdpSocket) -> (Vec<u8>, SocketAddr) {
+    let mut buf = vec![0u8; 65536];
+    let (len, addr) = tokio::time::timeout(PATIENCE, socket.recv_from(&mut buf))
+        .await
+        .expect("no datagram received")
+        .unwrap();
+    buf.truncate(len);
+    (buf, addr)
+}
+
+/// Receives the next datagram on the blocking socket `client`, failing if
+/// none arrives in time.
+pub fn recv(client: &StdUdpSocket) -> Vec<u8> {
+    client.set_read_timeout(Some(PATIENCE)).unwrap();
+    let mut buf = vec![0u8; 65536];
+    let len = client.recv(&mut buf).expect("no datagram received");
+    buf.truncate(len);
+    buf
+}
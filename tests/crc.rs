@@ -0,0 +1,53 @@
+//! Datagrams framed with their length and a checksum by the [`CrcCodec`].
+
+mod common;
+
+use std::sync::Arc;
+
+use common::{client, localhost, recv_from, server, SETTLE};
+use udp_stream::{CrcAlgorithm, CrcCodec, DatagramCodec, UdpListener, UdpStream};
+
+#[tokio::test]
+async fn framed_datagrams_round_trip_with_either_algorithm() {
+    for algorithm in [CrcAlgorithm::Crc32, CrcAlgorithm::Crc32c] {
+        let codec = Arc::new(CrcCodec::new().algorithm(algorithm));
+        let server = server().await;
+        let stream = UdpStream::builder()
+            .codec(codec.clone())
+            .connect(server.local_addr().unwrap())
+            .await
+            .unwrap();
+        stream.send_datagram(b"hello").await.unwrap();
+
+        let (wire, client_addr) = recv_from(&server).await;
+        assert_eq!(wire.len(), 4 + 5 + 4);
+        assert_eq!(wire[..4], 5u32.to_le_bytes());
+        assert_eq!(&wire[4..9], b"hello");
+        assert_eq!(codec.decode(wire.clone().into()).unwrap(), b"hello"[..]);
+        server.send_to(&wire, client_addr).await.unwrap();
+        assert_eq!(stream.recv().await.unwrap(), b"hello"[..]);
+    }
+}
+
+#[tokio::test]
+async fn corrupted_datagrams_are_dropped_and_counted() {
+    let codec = CrcCodec::new().algorithm(CrcAlgorithm::Crc32c);
+    let listener = UdpListener::builder()
+        .codec(Arc::new(codec.clone()))
+        .bind(localhost())
+        .await
+        .unwrap();
+    let client = client(listener.local_addr().unwrap());
+
+    let mut corrupted = codec.encode(b"hello").to_vec();
+    corrupted[6] ^= 0x01;
+    client.send(&corrupted).unwrap();
+    // A checksum of the other algorithm does not match either.
+    client.send(&CrcCodec::new().encode(b"hello")).unwrap();
+    client.send(&codec.encode(b"intact")).unwrap();
+
+    let (stream, _) = listener.accept().await.unwrap();
+    assert_eq!(stream.recv().await.unwrap(), b"intact"[..]);
+    tokio::time::sleep(SETTLE).await;
+    assert_eq!(listener.stats().dropped_decode, 2);
+}
@@ -0,0 +1,1540 @@
+//! The streams a [`UdpListener`] accepts and the way it tracks peers.
+
+mod common;
+
+use std::{
+    collections::HashSet,
+    future::Future,
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    pin::pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use common::{client, localhost, read_datagram, recv, PATIENCE, SETTLE};
+use tokio::io::AsyncWriteExt;
+use udp_stream::{
+    replay, DatagramTap, MemoryTap, PeerHasher, QueueFullPolicy, RecordedDatagram, UdpListener,
+    ZeroLengthPolicy,
+};
+
+#[tokio::test]
+async fn last_activity_advances_with_each_datagram() {
+    let listener = UdpListener::bind(localhost()).await.unwrap();
+    let client = client(listener.local_addr().unwrap());
+    client.send(b"first").unwrap();
+    let (mut stream, _) = listener.accept().await.unwrap();
+    read_datagram(&mut stream).await;
+    let first = stream.last_activity();
+
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    client.send(b"second").unwrap();
+    read_datagram(&mut stream).await;
+    assert!(stream.last_activity() >= first + Duration::from_millis(20));
+}
+
+#[tokio::test]
+async fn ephemeral_listeners_report_the_port_they_were_given() {
+    let listener = UdpListener::bind_ephemeral(Ipv4Addr::LOCALHOST.into())
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    assert_eq!(addr.ip(), Ipv4Addr::LOCALHOST);
+    assert_ne!(addr.port(), 0);
+
+    let client = client(addr);
+    client.send(b"ping").unwrap();
+    let (mut stream, peer_addr) = listener.accept().await.unwrap();
+    assert_eq!(peer_addr, client.local_addr().unwrap());
+    assert_eq!(read_datagram(&mut stream).await, b"ping");
+}
+
+#[tokio::test]
+async fn bind_multi_accepts_on_every_address_and_replies_from_it() {
+    let listener = UdpListener::bind_multi(&[localhost(), localhost()])
+        .await
+        .unwrap();
+    let addrs = listener.local_addrs().to_vec();
+    assert_eq!(addrs.len(), 2);
+    assert_ne!(addrs[0], addrs[1]);
+
+    for addr in addrs {
+        // A connected socket only receives from the address it connected to.
+        let client = client(addr);
+        client.set_read_timeout(Some(PATIENCE)).unwrap();
+        client.send(b"ping").unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        assert_eq!(read_datagram(&mut stream).await, b"ping");
+        stream.write_all(b"pong").await.unwrap();
+
+        let mut buf = [0u8; 16];
+        let len = client.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"pong");
+    }
+}
+
+#[test]
+fn bind_on_runs_the_listener_on_the_given_runtime() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build()
+        .unwrap();
+    // Not called from within a runtime.
+    let listener = UdpListener::bind_on(runtime.handle(), localhost()).unwrap();
+    let client = client(listener.local_addr().unwrap());
+    client.send(b"ping").unwrap();
+
+    // Another runtime only waits for the stream announced by the listener's.
+    let other = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    other.block_on(async {
+        let (mut stream, peer_addr) = tokio::time::timeout(PATIENCE, listener.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(peer_addr, client.local_addr().unwrap());
+        assert_eq!(read_datagram(&mut stream).await, b"ping");
+    });
+}
+
+#[tokio::test]
+async fn recorded_datagrams_replay_into_another_listener() {
+    let tap = Arc::new(MemoryTap::new());
+    let listener = UdpListener::builder()
+        .tap(tap.clone())
+        .bind(localhost())
+        .await
+        .unwrap();
+    let first = client(listener.local_addr().unwrap());
+    let second = client(listener.local_addr().unwrap());
+    first.send(b"one").unwrap();
+    second.send(b"two").unwrap();
+    first.send(b"three").unwrap();
+    // Once the last datagram is read, every datagram went past the tap.
+    let (mut stream, _) = listener.accept().await.unwrap();
+    assert_eq!(read_datagram(&mut stream).await, b"one");
+    assert_eq!(read_datagram(&mut stream).await, b"three");
+    let records = tap.records();
+    let seen: Vec<_> = records
+        .iter()
+        .map(|record| (record.source, &record.payload[..]))
+        .collect();
+    let (first, second) = (first.local_addr().unwrap(), second.local_addr().unwrap());
+    assert_eq!(
+        seen,
+        [(first, &b"one"[..]), (second, b"two"), (first, b"three")]
+    );
+
+    let replayed = UdpListener::bind(localhost()).await.unwrap();
+    replay(records, &replayed).await.unwrap();
+    let (mut stream, peer_addr) = replayed.accept().await.unwrap();
+    assert_eq!(peer_addr, first);
+    assert_eq!(read_datagram(&mut stream).await, b"one");
+    assert_eq!(read_datagram(&mut stream).await, b"three");
+    let (mut stream, peer_addr) = replayed.accept().await.unwrap();
+    assert_eq!(peer_addr, second);
+    assert_eq!(read_datagram(&mut stream).await, b"two");
+}
+
+#[tokio::test]
+async fn bind_retry_binds_once_the_address_is_released() {
+    let taken = UdpListener::bind(localhost()).await.unwrap();
+    let addr = taken.local_addr().unwrap();
+    let Err(err) = UdpListener::bind_retry(addr, 2, Duration::from_millis(1)).await else {
+        panic!("bound an address in use");
+    };
+    assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        drop(taken);
+    });
+    let listener = UdpListener::bind_retry(addr, 10, Duration::from_millis(10))
+        .await
+        .unwrap();
+    assert_eq!(listener.local_addr().unwrap(), addr);
+}
+
+#[tokio::test]
+async fn from_std_listens_on_a_blocking_socket() {
+    let socket = std::net::UdpSocket::bind(localhost()).unwrap();
+    let addr = socket.local_addr().unwrap();
+    let listener = UdpListener::from_std(socket).unwrap();
+    assert_eq!(listener.local_addr().unwrap(), addr);
+
+    let client = client(addr);
+    client.send(b"ping").unwrap();
+    let (mut stream, _) = tokio::time::timeout(PATIENCE, listener.accept())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(read_datagram(&mut stream).await, b"ping");
+}
+
+#[tokio::test]
+async fn buffer_pool_reuses_slabs_once_datagrams_are_dropped() {
+    let listener = UdpListener::builder()
+        .buffer_pool(2)
+        .bind(localhost())
+        .await
+        .unwrap();
+    assert_eq!(listener.pool_stats().unwrap().allocations, 2);
+    let client = client(listener.local_addr().unwrap());
+    client.send(b"hello").unwrap();
+    let (stream, _) = listener.accept().await.unwrap();
+    stream.recv().await.unwrap();
+
+    // Bursts fill the slabs through the batched receive path.
+    for _ in 0..20 {
+        for _ in 0..32 {
+            client.send(&[0u8; 1024]).unwrap();
+        }
+        for _ in 0..32 {
+            stream.recv().await.unwrap();
+        }
+    }
+    let stats = listener.pool_stats().unwrap();
+    assert!(stats.reuses > 0, "{:?}", stats);
+    assert!(stats.allocations < 10, "{:?}", stats);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn cancelled_accepts_lose_no_connection() {
+    const PEERS: usize = 200;
+    let listener = Arc::new(
+        UdpListener::builder()
+            .accept_backlog(PEERS)
+            .bind(localhost())
+            .await
+            .unwrap(),
+    );
+    let addr = listener.local_addr().unwrap();
+    let senders = tokio::spawn(async move {
+        // Kept open so that no two peers share a port.
+        let mut clients = Vec::with_capacity(PEERS);
+        for _ in 0..PEERS {
+            let client = client(addr);
+            client.send(b"hello").unwrap();
+            clients.push(client);
+            tokio::task::yield_now().await;
+        }
+        clients
+    });
+
+    let mut accepted = HashSet::new();
+    let deadline = tokio::time::Instant::now() + PATIENCE * 5;
+    let mut poll_once = false;
+    while accepted.len() < PEERS {
+        assert!(
+            tokio::time::Instant::now() < deadline,
+            "accepted {}",
+            accepted.len()
+        );
+        poll_once = !poll_once;
+        let accept = listener.accept();
+        let result = if poll_once {
+            // Polled once, then dropped if no connection is waiting.
+            tokio::select! {
+                biased;
+                result = accept => Some(result),
+                () = std::future::ready(()) => None,
+            }
+        } else {
+            tokio::time::timeout(Duration::from_micros(50), accept)
+                .await
+                .ok()
+        };
+        if let Some(result) = result {
+            let (_, peer_addr) = result.unwrap();
+            assert!(accepted.insert(peer_addr), "{} accepted twice", peer_addr);
+        }
+    }
+
+    let clients: HashSet<_> = senders
+        .await
+        .unwrap()
+        .iter()
+        .map(|client| client.local_addr().unwrap())
+        .collect();
+    assert_eq!(accepted, clients);
+    assert_eq!(listener.stats().dropped_accept_full, 0);
+}
+
+#[tokio::test]
+async fn only_peers_with_sustained_traffic_grow_their_queue() {
+    const BURST: usize = 12;
+    const LEN: usize = 100;
+    const LIGHT_PEERS: usize = 20;
+    let listener = UdpListener::builder()
+        .channel_growth(4, 16)
+        .max_buffered_bytes(usize::MAX)
+        .bind(localhost())
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    // The heavy peer overflows its initial queue, then earns larger ones as
+    // it keeps sending.
+    let heavy = client(addr);
+    let mut heavy_stream = None;
+    let mut read = Vec::new();
+    for _ in 0..3 {
+        for _ in 0..BURST {
+            heavy.send(&[1; LEN]).unwrap();
+        }
+        let stream = match &mut heavy_stream {
+            Some(stream) => stream,
+            None => heavy_stream.insert(listener.accept().await.unwrap().0),
+        };
+        tokio::time::sleep(SETTLE).await;
+        let mut count = 0;
+        while let Ok(datagram) = tokio::time::timeout(SETTLE, stream.recv()).await {
+            assert_eq!(datagram.unwrap().len(), LEN);
+            count += 1;
+        }
+        read.push(count);
+    }
+    assert_eq!(read, [4, BURST, BURST]);
+
+    // Light peers send as much at once, but never enough to grow.
+    let light: Vec<_> = (0..LIGHT_PEERS).map(|_| client(addr)).collect();
+    for client in &light {
+        for _ in 0..BURST {
+            client.send(&[2; LEN]).unwrap();
+        }
+    }
+    let mut streams = Vec::new();
+    for _ in 0..LIGHT_PEERS {
+        streams.push(listener.accept().await.unwrap().0);
+    }
+    tokio::time::sleep(SETTLE).await;
+    assert_eq!(listener.stats().buffered_bytes, LIGHT_PEERS * 4 * LEN);
+    for stream in &streams {
+        assert_eq!(stream.drain_queued(), 4);
+    }
+    assert_eq!(listener.stats().buffered_bytes, 0);
+}
+
+#[tokio::test]
+async fn broadcast_reaches_every_tracked_peer() {
+    let listener = UdpListener::bind(localhost()).await.unwrap();
+    let clients: Vec<_> = (0..3)
+        .map(|_| client(listener.local_addr().unwrap()))
+        .collect();
+    let mut streams = Vec::new();
+    for client in &clients {
+        client.send(b"join").unwrap();
+        streams.push(listener.accept().await.unwrap().0);
+    }
+
+    assert_eq!(listener.broadcast(b"tick").await.unwrap(), 3);
+    for client in &clients {
+        assert_eq!(recv(client), b"tick");
+    }
+}
+
+#[tokio::test]
+async fn buffered_bytes_stay_under_the_cap_across_peers() {
+    const LEN: usize = 1000;
+    const CAP: usize = 5 * LEN;
+    let listener = UdpListener::builder()
+        .max_buffered_bytes(CAP)
+        .bind(localhost())
+        .await
+        .unwrap();
+    let clients: Vec<_> = (0..10)
+        .map(|_| client(listener.local_addr().unwrap()))
+        .collect();
+    // Few enough that the socket receive buffer holds them all.
+    for _ in 0..4 {
+        for client in &clients {
+            client.send(&[0; LEN]).unwrap();
+        }
+    }
+    tokio::time::sleep(SETTLE).await;
+    let stats = listener.stats();
+    assert_eq!(stats.buffered_bytes, CAP);
+    assert_eq!(stats.dropped_buffer_limit, 40 - 5);
+
+    // Reading frees room for new datagrams.
+    let (mut stream, peer_addr) = listener.accept().await.unwrap();
+    read_datagram(&mut stream).await;
+    assert_eq!(listener.stats().buffered_bytes, CAP - LEN);
+    let client = clients
+        .iter()
+        .find(|client| client.local_addr().unwrap() == peer_addr)
+        .unwrap();
+    client.send(&[0; LEN]).unwrap();
+    tokio::time::sleep(SETTLE).await;
+    assert_eq!(listener.stats().buffered_bytes, CAP);
+}
+
+#[tokio::test]
+async fn the_dedup_hasher_applies_whichever_is_set_first() {
+    // Datagrams starting with the same byte count as duplicates.
+    let first_byte = |datagram: &[u8]| datagram.first().copied().unwrap_or(0).into();
+    let builders = [
+        UdpListener::builder()
+            .dedup_hasher(first_byte)
+            .dedup_window(4),
+        UdpListener::builder()
+            .dedup_window(4)
+            .dedup_hasher(first_byte),
+    ];
+    for builder in builders {
+        let listener = builder.bind(localhost()).await.unwrap();
+        let client = client(listener.local_addr().unwrap());
+        for datagram in [b"a1", b"a2", b"b1"] {
+            client.send(datagram).unwrap();
+        }
+        let (mut stream, _) = listener.accept().await.unwrap();
+
+        assert_eq!(read_datagram(&mut stream).await, b"a1");
+        assert_eq!(read_datagram(&mut stream).await, b"b1");
+        assert_eq!(listener.stats().duplicates_dropped, 1);
+    }
+}
+
+#[tokio::test]
+async fn duplicates_within_the_window_are_delivered_once() {
+    let listener = UdpListener::builder()
+        .dedup_window(4)
+        .bind(localhost())
+        .await
+        .unwrap();
+    let client = client(listener.local_addr().unwrap());
+    for datagram in [&b"request"[..], b"request", b"other"] {
+        client.send(datagram).unwrap();
+    }
+    let (mut stream, _) = listener.accept().await.unwrap();
+
+    assert_eq!(read_datagram(&mut stream).await, b"request");
+    assert_eq!(read_datagram(&mut stream).await, b"other");
+    assert_eq!(listener.stats().duplicates_dropped, 1);
+}
+
+#[tokio::test]
+async fn stateless_listeners_receive_without_streams() {
+    let listener = UdpListener::builder()
+        .stateless(true)
+        .bind(localhost())
+        .await
+        .unwrap();
+    let client = client(listener.local_addr().unwrap());
+    client.send(b"query").unwrap();
+
+    let mut storage = [0u8; 64];
+    let mut buf = tokio::io::ReadBuf::new(&mut storage);
+    let source = std::future::poll_fn(|cx| listener.poll_recv_from(cx, &mut buf))
+        .await
+        .unwrap();
+    assert_eq!(source, client.local_addr().unwrap());
+    assert_eq!(buf.filled(), b"query");
+    assert_eq!(listener.stats().peers, 0);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn a_quiet_peer_is_served_promptly_during_a_flood() {
+    let listener = UdpListener::bind(localhost()).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let flooding = Arc::new(AtomicBool::new(true));
+    let flooder = {
+        let flooding = flooding.clone();
+        let flood = client(addr);
+        std::thread::spawn(move || {
+            // Bursts the socket receive buffer can take, so the quiet peer's
+            // datagram is not lost before the listener sees it.
+            while flooding.load(Ordering::Relaxed) {
+                for _ in 0..32 {
+                    flood.send(b"flood").unwrap();
+                }
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        })
+    };
+    let (_flood_stream, _) = listener.accept().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let quiet = client(addr);
+    let sent = tokio::time::Instant::now();
+    quiet.send(b"hello").unwrap();
+    let (mut stream, peer_addr) = listener.accept().await.unwrap();
+    assert_eq!(peer_addr, quiet.local_addr().unwrap());
+    assert_eq!(read_datagram(&mut stream).await, b"hello");
+    let latency = sent.elapsed();
+    flooding.store(false, Ordering::Relaxed);
+    flooder.join().unwrap();
+
+    assert!(latency < Duration::from_millis(100), "{:?}", latency);
+    assert!(listener.stats().dropped_queue_full > 0);
+}
+
+#[tokio::test]
+async fn full_queues_hold_datagrams_back_when_waiting_for_room() {
+    const SENT: usize = 40;
+    for delivery_interval in [None, Some(Duration::from_millis(5))] {
+        let mut builder = UdpListener::builder()
+            .channel_capacity(4)
+            .queue_full(QueueFullPolicy::Wait);
+        if let Some(interval) = delivery_interval {
+            builder = builder.delivery_interval(interval);
+        }
+        let listener = builder.bind(localhost()).await.unwrap();
+        let client = client(listener.local_addr().unwrap());
+        for i in 0..SENT {
+            client.send(&[i as u8]).unwrap();
+        }
+        let (stream, _) = listener.accept().await.unwrap();
+        tokio::time::sleep(SETTLE).await;
+
+        for i in 0..SENT {
+            let datagram = tokio::time::timeout(PATIENCE, stream.recv()).await;
+            assert_eq!(datagram.unwrap().unwrap(), [i as u8][..]);
+        }
+        assert_eq!(listener.stats().dropped_queue_full, 0);
+    }
+}
+
+#[tokio::test]
+async fn datagrams_over_the_maximum_size_are_dropped() {
+    let listener = UdpListener::builder()
+        .max_datagram_size(1024)
+        .bind(localhost())
+        .await
+        .unwrap();
+    let client = client(listener.local_addr().unwrap());
+    client.send(&[1; 16 * 1024]).unwrap();
+    client.send(&[2; 512]).unwrap();
+
+    let (mut stream, _) = listener.accept().await.unwrap();
+    assert_eq!(read_datagram(&mut stream).await, [2; 512]);
+    assert_eq!(listener.stats().dropped_oversize, 1);
+}
+
+#[tokio::test]
+async fn held_back_datagrams_are_delivered_on_each_tick() {
+    const INTERVAL: Duration = Duration::from_millis(100);
+    let listener = UdpListener::builder()
+        .delivery_interval(INTERVAL)
+        .bind(localhost())
+        .await
+        .unwrap();
+    let client = client(listener.local_addr().unwrap());
+    client.send(b"hello").unwrap();
+    let (mut stream, _) = listener.accept().await.unwrap();
+    assert_eq!(read_datagram(&mut stream).await, b"hello");
+
+    for datagram in [&b"a"[..], b"b", b"c"] {
+        client.send(datagram).unwrap();
+    }
+    assert_eq!(read_datagram(&mut stream).await, b"a");
+    let tick = tokio::time::Instant::now();
+    // The rest of the batch is already there.
+    for datagram in [&b"b"[..], b"c"] {
+        let read = tokio::time::timeout(Duration::from_millis(5), read_datagram(&mut stream));
+        assert_eq!(read.await.unwrap(), datagram);
+    }
+
+    client.send(b"d").unwrap();
+    assert_eq!(read_datagram(&mut stream).await, b"d");
+    assert!(tick.elapsed() >= INTERVAL * 4 / 5, "{:?}", tick.elapsed());
+}
+
+#[tokio::test]
+async fn rebinding_keeps_streams_and_listens_on_the_new_port() {
+    let mut listener = UdpListener::bind(localhost()).await.unwrap();
+    let old_addr = listener.local_addr().unwrap();
+    let peer = std::net::UdpSocket::bind(localhost()).unwrap();
+    peer.send_to(b"before", old_addr).unwrap();
+    let (mut stream, _) = listener.accept().await.unwrap();
+    assert_eq!(read_datagram(&mut stream).await, b"before");
+
+    listener.rebind(localhost()).await.unwrap();
+    let new_addr = listener.local_addr().unwrap();
+    assert_ne!(new_addr, old_addr);
+
+    peer.send_to(b"after", new_addr).unwrap();
+    assert_eq!(read_datagram(&mut stream).await, b"after");
+    let newcomer = client(new_addr);
+    newcomer.send(b"hello").unwrap();
+    let (mut other, peer_addr) = listener.accept().await.unwrap();
+    assert_eq!(peer_addr, newcomer.local_addr().unwrap());
+    assert_eq!(read_datagram(&mut other).await, b"hello");
+}
+
+#[tokio::test]
+async fn dual_stack_listeners_can_report_ipv4_peers_as_ipv4() {
+    let Ok(listener) = UdpListener::builder()
+        .canonical_addrs(true)
+        .bind("[::]:0".parse().unwrap())
+        .await
+    else {
+        return; // IPv6 is disabled on this host.
+    };
+    let port = listener.local_addr().unwrap().port();
+    let client = client(SocketAddr::from(([127, 0, 0, 1], port)));
+    client.send(b"ping").unwrap();
+
+    let (mut stream, peer_addr) = listener.accept().await.unwrap();
+    assert_eq!(peer_addr, client.local_addr().unwrap());
+    assert_eq!(stream.peer_addr().unwrap(), peer_addr);
+    assert!(listener.contains_peer(&peer_addr));
+    stream.write_all(b"pong").await.unwrap();
+    assert_eq!(recv(&client), b"pong");
+}
+
+#[tokio::test]
+async fn peers_dropped_by_a_full_backlog_leave_nothing_behind() {
+    let listener = UdpListener::builder()
+        .accept_backlog(1)
+        .bind(localhost())
+        .await
+        .unwrap();
+    let clients: Vec<_> = (0..3)
+        .map(|_| client(listener.local_addr().unwrap()))
+        .collect();
+    for client in &clients {
+        client.send(b"hello").unwrap();
+    }
+    tokio::time::sleep(SETTLE).await;
+    let stats = listener.stats();
+    assert_eq!(stats.dropped_accept_full, 2);
+    assert_eq!(stats.peers, 1);
+    for client in &clients[1..] {
+        assert!(!listener.contains_peer(&client.local_addr().unwrap()));
+    }
+
+    // Their next datagram is announced as a new connection again.
+    let (_first, _) = listener.accept().await.unwrap();
+    clients[2].send(b"again").unwrap();
+    let (mut stream, peer_addr) = listener.accept().await.unwrap();
+    assert_eq!(peer_addr, clients[2].local_addr().unwrap());
+    assert_eq!(read_datagram(&mut stream).await, b"again");
+}
+
+#[tokio::test]
+async fn keepalives_follow_a_session_that_moved() {
+    let keepalives = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let listener = {
+        let keepalives = keepalives.clone();
+        UdpListener::builder()
+            .connection_id(|datagram| Some(u64::from_be_bytes(datagram.get(..8)?.try_into().ok()?)))
+            .zero_length(ZeroLengthPolicy::Keepalive(Arc::new(move |peer_addr| {
+                keepalives.lock().unwrap().push(peer_addr)
+            })))
+            .bind(localhost())
+            .await
+            .unwrap()
+    };
+    let addr = listener.local_addr().unwrap();
+    let first = client(addr);
+    let mut hello = 7u64.to_be_bytes().to_vec();
+    hello.extend(b"hello");
+    first.send(&hello).unwrap();
+    let (mut stream, session) = listener.accept().await.unwrap();
+    assert_eq!(read_datagram(&mut stream).await, hello);
+    first.send(b"").unwrap();
+
+    // The peer moves to another address, then only sends keepalives.
+    let moved = client(addr);
+    moved.send(&hello).unwrap();
+    assert_eq!(read_datagram(&mut stream).await, hello);
+    moved.send(b"").unwrap();
+    tokio::time::sleep(SETTLE).await;
+
+    assert_eq!(*keepalives.lock().unwrap(), [session, session]);
+    assert_eq!(listener.stats().peers, 1);
+    assert_eq!(listener.backlog_len(), 0);
+}
+
+#[tokio::test]
+async fn replies_follow_a_session_to_its_latest_address_only_when_opted_in() {
+    let mut hello = 7u64.to_be_bytes().to_vec();
+    hello.extend(b"hello");
+    for follow in [false, true] {
+        let builder = UdpListener::builder();
+        let builder = if follow {
+            builder.connection_id(|datagram| {
+                Some(u64::from_be_bytes(datagram.get(..8)?.try_into().ok()?))
+            })
+        } else {
+            builder
+        };
+        let listener = builder.bind(localhost()).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let first = client(addr);
+        first.send(&hello).unwrap();
+        let (mut stream, session) = listener.accept().await.unwrap();
+        read_datagram(&mut stream).await;
+
+        // The peer's source address changes, as after a NAT rebinding.
+        let moved = client(addr);
+        moved.send(&hello).unwrap();
+        if follow {
+            assert_eq!(read_datagram(&mut stream).await, hello);
+            stream.write_all(b"reply").await.unwrap();
+            assert_eq!(recv(&moved), b"reply");
+            assert_eq!(stream.peer_addr().unwrap(), session);
+        } else {
+            let (_, peer_addr) = listener.accept().await.unwrap();
+            assert_eq!(peer_addr, moved.local_addr().unwrap());
+            stream.write_all(b"reply").await.unwrap();
+            assert_eq!(recv(&first), b"reply");
+        }
+    }
+}
+
+#[tokio::test]
+async fn backlog_len_counts_connections_waiting_to_be_accepted() {
+    let listener = UdpListener::bind(localhost()).await.unwrap();
+    assert_eq!(listener.backlog_len(), 0);
+    let clients: Vec<_> = (0..3)
+        .map(|_| client(listener.local_addr().unwrap()))
+        .collect();
+    for client in &clients {
+        client.send(b"hello").unwrap();
+        // Later datagrams of a waiting peer are not new connections.
+        client.send(b"again").unwrap();
+    }
+    tokio::time::sleep(SETTLE).await;
+    assert_eq!(listener.backlog_len(), 3);
+
+    listener.accept().await.unwrap();
+    assert_eq!(listener.backlog_len(), 2);
+}
+
+#[tokio::test]
+async fn peeked_datagrams_are_still_received() {
+    let listener = UdpListener::builder()
+        .stateless(true)
+        .bind(localhost())
+        .await
+        .unwrap();
+    let client = client(listener.local_addr().unwrap());
+    client.send(b"first").unwrap();
+    client.send(b"second").unwrap();
+
+    let source = client.local_addr().unwrap();
+    for _ in 0..2 {
+        let (payload, peer_addr) = listener.peek_next().await.unwrap();
+        assert_eq!((&payload[..], peer_addr), (&b"first"[..], source));
+    }
+    for expected in [&b"first"[..], b"second"] {
+        let mut storage = [0u8; 64];
+        let mut buf = tokio::io::ReadBuf::new(&mut storage);
+        let peer_addr = std::future::poll_fn(|cx| listener.poll_recv_from(cx, &mut buf))
+            .await
+            .unwrap();
+        assert_eq!((buf.filled(), peer_addr), (expected, source));
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_acceptors_each_get_distinct_connections() {
+    const CLIENTS: usize = 16;
+    let listener = UdpListener::bind(localhost()).await.unwrap();
+    let acceptors: Vec<_> = (0..4)
+        .map(|_| {
+            let listener = listener.clone();
+            tokio::spawn(async move {
+                let mut accepted = Vec::new();
+                while let Ok(Ok((_, peer_addr))) =
+                    tokio::time::timeout(PATIENCE / 4, listener.accept()).await
+                {
+                    accepted.push(peer_addr);
+                }
+                accepted
+            })
+        })
+        .collect();
+
+    let addr = listener.local_addr().unwrap();
+    let clients: Vec<_> = (0..CLIENTS).map(|_| client(addr)).collect();
+    for client in &clients {
+        client.send(b"hello").unwrap();
+    }
+
+    let mut accepted = Vec::new();
+    for acceptor in acceptors {
+        accepted.extend(acceptor.await.unwrap());
+    }
+    assert_eq!(
+        accepted.len(),
+        CLIENTS,
+        "a connection was accepted twice or lost"
+    );
+    let expected: HashSet<_> = clients.iter().map(|c| c.local_addr().unwrap()).collect();
+    assert_eq!(accepted.into_iter().collect::<HashSet<_>>(), expected);
+}
+
+#[tokio::test]
+async fn active_sessions_end_after_the_maximum_duration() {
+    let listener = UdpListener::builder()
+        .max_session_duration(Duration::from_millis(200))
+        .bind(localhost())
+        .await
+        .unwrap();
+    let client = client(listener.local_addr().unwrap());
+    client.send(b"hello").unwrap();
+    let (stream, _) = listener.accept().await.unwrap();
+
+    let start = std::time::Instant::now();
+    let err = loop {
+        client.send(b"still here").unwrap();
+        match tokio::time::timeout(PATIENCE, stream.recv()).await.unwrap() {
+            Ok(_) => tokio::time::sleep(Duration::from_millis(20)).await,
+            Err(err) => break err,
+        }
+    };
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    let lasted = start.elapsed();
+    assert!(
+        (Duration::from_millis(150)..PATIENCE).contains(&lasted),
+        "the session lasted {:?}",
+        lasted
+    );
+
+    client.send(b"again").unwrap();
+    let (stream, _) = tokio::time::timeout(PATIENCE, listener.accept())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(!stream.recv().await.unwrap().is_empty());
+}
+
+/// Windows reports the ICMP error caused by a send to a closed port on the
+/// next receive of the socket, which the listener shares among all peers.
+#[cfg(windows)]
+#[tokio::test]
+async fn icmp_errors_from_one_peer_do_not_stop_the_listener() {
+    let listener = UdpListener::bind(localhost()).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let gone = client(addr);
+    gone.send(b"hello").unwrap();
+    let (mut stream, _) = listener.accept().await.unwrap();
+    drop(gone);
+    stream.write_all(b"reply").await.unwrap();
+    tokio::time::sleep(SETTLE).await;
+
+    let other = client(addr);
+    other.send(b"hello").unwrap();
+    let (_, peer_addr) = tokio::time::timeout(PATIENCE, listener.accept())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(peer_addr, other.local_addr().unwrap());
+}
+
+#[tokio::test]
+async fn peers_are_told_apart_with_the_fx_hasher() {
+    let listener = UdpListener::builder()
+        .peer_hasher(PeerHasher::Fx)
+        .bind(localhost())
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    let clients: Vec<_> = (0..8).map(|_| client(addr)).collect();
+    for client in &clients {
+        client.send(b"hello").unwrap();
+    }
+    let mut streams = Vec::new();
+    for _ in &clients {
+        let (stream, peer_addr) = listener.accept().await.unwrap();
+        assert!(listener.contains_peer(&peer_addr));
+        streams.push(stream);
+    }
+
+    for client in &clients {
+        client
+            .send(client.local_addr().unwrap().to_string().as_bytes())
+            .unwrap();
+    }
+    for stream in &streams {
+        assert_eq!(stream.recv().await.unwrap(), b"hello"[..]);
+        let reply = stream.recv().await.unwrap();
+        assert_eq!(reply, stream.peer_addr().unwrap().to_string().as_bytes());
+    }
+}
+
+#[tokio::test]
+async fn admission_refuses_new_peers_above_a_threshold_only() {
+    let listener = UdpListener::builder()
+        .admit(|_, stats| stats.peers < 2)
+        .bind(localhost())
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    let admitted = [client(addr), client(addr)];
+    let mut streams = Vec::new();
+    for client in &admitted {
+        client.send(b"hello").unwrap();
+        streams.push(listener.accept().await.unwrap().0);
+    }
+
+    let refused = client(addr);
+    refused.send(b"hello").unwrap();
+    tokio::time::sleep(SETTLE).await;
+    assert!(!listener.contains_peer(&refused.local_addr().unwrap()));
+    assert_eq!(listener.backlog_len(), 0);
+    assert_eq!(listener.stats().dropped_admission, 1);
+
+    for (client, stream) in admitted.iter().zip(&streams) {
+        client.send(b"still served").unwrap();
+        assert_eq!(stream.recv().await.unwrap(), b"hello"[..]);
+        assert_eq!(stream.recv().await.unwrap(), b"still served"[..]);
+    }
+}
+
+#[tokio::test]
+async fn closed_resolves_once_the_peer_is_reaped() {
+    let listener = UdpListener::builder()
+        .max_session_duration(Duration::from_millis(100))
+        .bind(localhost())
+        .await
+        .unwrap();
+    let client = client(listener.local_addr().unwrap());
+    client.send(b"hello").unwrap();
+    let (stream, peer_addr) = listener.accept().await.unwrap();
+    assert!(tokio::time::timeout(SETTLE, stream.closed()).await.is_err());
+
+    tokio::time::timeout(PATIENCE, stream.closed())
+        .await
+        .expect("the reaped stream never closed");
+    assert!(!listener.contains_peer(&peer_addr));
+    assert_eq!(stream.recv().await.unwrap(), b"hello"[..]);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn dropped_streams_are_removed_promptly_during_a_flood() {
+    let listener = UdpListener::bind(localhost()).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let flooder = client(addr);
+    flooder.send(b"flood").unwrap();
+    let (_flooded, _) = listener.accept().await.unwrap();
+
+    let peers: Vec<_> = (0..8).map(|_| client(addr)).collect();
+    let mut streams = Vec::new();
+    for peer in &peers {
+        peer.send(b"hello").unwrap();
+        streams.push(listener.accept().await.unwrap().0);
+    }
+
+    let flooding = Arc::new(AtomicBool::new(true));
+    let flood = std::thread::spawn({
+        let flooding = flooding.clone();
+        move || {
+            while flooding.load(Ordering::Relaxed) {
+                let _ = flooder.send(&[0u8; 512]);
+            }
+        }
+    });
+    tokio::time::sleep(SETTLE).await;
+    drop(streams);
+
+    let start = std::time::Instant::now();
+    while peers
+        .iter()
+        .any(|peer| listener.contains_peer(&peer.local_addr().unwrap()))
+    {
+        assert!(
+            start.elapsed() < PATIENCE / 4,
+            "dropped streams are still tracked during the flood"
+        );
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    flooding.store(false, Ordering::Relaxed);
+    flood.join().unwrap();
+}
+
+#[tokio::test]
+async fn the_first_datagram_is_read_first_from_late_accepted_streams() {
+    for lazy in [false, true] {
+        let listener = UdpListener::builder()
+            .lazy_accept(lazy)
+            .bind(localhost())
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let clients: Vec<_> = (0..8).map(|_| client(addr)).collect();
+        for client in &clients {
+            let first = format!("first from {}", client.local_addr().unwrap());
+            client.send(first.as_bytes()).unwrap();
+            client.send(b"second").unwrap();
+        }
+        tokio::time::sleep(SETTLE).await;
+
+        for _ in &clients {
+            let (stream, peer_addr) = listener.accept().await.unwrap();
+            let first = format!("first from {}", peer_addr);
+            assert_eq!(
+                stream.recv().await.unwrap(),
+                first.as_bytes(),
+                "lazy: {}",
+                lazy
+            );
+        }
+    }
+}
+
+/// Ends the receive loop by unwinding on datagrams reading `boom`, without
+/// the noise of a panic message.
+struct FailingTap;
+
+impl DatagramTap for FailingTap {
+    fn record(&self, datagram: &RecordedDatagram) {
+        if datagram.payload == b"boom"[..] {
+            std::panic::resume_unwind(Box::new("tap failed"));
+        }
+    }
+}
+
+#[tokio::test]
+async fn a_restarted_listener_accepts_again() {
+    let mut listener = UdpListener::builder()
+        .tap(Arc::new(FailingTap))
+        .bind(localhost())
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    let before = client(addr);
+    before.send(b"hello").unwrap();
+    let (stream, _) = listener.accept().await.unwrap();
+    stream.recv().await.unwrap();
+
+    before.send(b"boom").unwrap();
+    tokio::time::sleep(SETTLE).await;
+    listener.restart().unwrap();
+    let ended = tokio::time::timeout(PATIENCE, stream.recv()).await.unwrap();
+    assert!(ended.is_err(), "a stream of the ended loop still reads");
+
+    let after = client(addr);
+    after.send(b"hello again").unwrap();
+    let (stream, peer_addr) = tokio::time::timeout(PATIENCE, listener.accept())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(peer_addr, after.local_addr().unwrap());
+    assert_eq!(stream.recv().await.unwrap(), b"hello again"[..]);
+}
+
+#[tokio::test]
+async fn peers_are_tracked_until_their_stream_is_dropped() {
+    let listener = UdpListener::bind(localhost()).await.unwrap();
+    let client = client(listener.local_addr().unwrap());
+    let client_addr = client.local_addr().unwrap();
+    assert!(!listener.contains_peer(&client_addr));
+
+    client.send(b"hello").unwrap();
+    let (stream, peer_addr) = listener.accept().await.unwrap();
+    assert!(listener.contains_peer(&peer_addr));
+    drop(stream);
+
+    let start = std::time::Instant::now();
+    while listener.contains_peer(&peer_addr) {
+        assert!(
+            start.elapsed() < PATIENCE,
+            "the dropped peer is still tracked"
+        );
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    assert_eq!(listener.stats().peers, 0);
+}
+
+#[tokio::test]
+async fn a_late_datagram_within_the_close_grace_revives_the_session() {
+    let listener = UdpListener::builder()
+        .close_grace(Duration::from_millis(200))
+        .bind(localhost())
+        .await
+        .unwrap();
+    let client = client(listener.local_addr().unwrap());
+    client.send(b"hello").unwrap();
+    let (stream, peer_addr) = listener.accept().await.unwrap();
+    assert_eq!(stream.recv().await.unwrap(), b"hello"[..]);
+
+    stream.shutdown();
+    tokio::time::sleep(SETTLE).await;
+    client.send(b"late").unwrap();
+    assert_eq!(stream.recv().await.unwrap(), b"late"[..]);
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(listener.contains_peer(&peer_addr));
+    assert_eq!(listener.backlog_len(), 0);
+
+    stream.shutdown();
+    let ended = tokio::time::timeout(PATIENCE, stream.recv()).await.unwrap();
+    assert!(ended.is_err(), "the stream outlived the close grace");
+    assert!(!listener.contains_peer(&peer_addr));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn shutdown_timeout_delivers_queued_datagrams_and_meets_its_deadline() {
+    let listener = UdpListener::bind(localhost()).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let quiet = client(addr);
+    quiet.send(b"queued").unwrap();
+    let (stream, _) = listener.accept().await.unwrap();
+
+    // A peer that never lets the sockets go quiet.
+    let flooder = client(addr);
+    let flooding = Arc::new(AtomicBool::new(true));
+    let flood = std::thread::spawn({
+        let flooding = flooding.clone();
+        move || {
+            while flooding.load(Ordering::Relaxed) {
+                let _ = flooder.send(&[0u8; 64]);
+                std::thread::sleep(Duration::from_micros(200));
+            }
+        }
+    });
+    tokio::time::sleep(SETTLE).await;
+
+    let deadline = Duration::from_millis(100);
+    let start = std::time::Instant::now();
+    listener.shutdown_timeout(deadline).await;
+    let took = start.elapsed();
+    flooding.store(false, Ordering::Relaxed);
+    flood.join().unwrap();
+    assert!(
+        (deadline..deadline + PATIENCE / 4).contains(&took),
+        "shutdown took {:?}",
+        took
+    );
+
+    assert_eq!(stream.recv().await.unwrap(), b"queued"[..]);
+    assert!(stream.recv().await.is_err());
+}
+
+#[tokio::test(start_paused = true)]
+async fn session_expiry_and_idle_reaping_follow_the_paused_clock() {
+    let listener = UdpListener::builder()
+        .max_session_duration(Duration::from_secs(60))
+        .close_grace(Duration::from_secs(10))
+        .bind(localhost())
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (long_lived, idle) = (client(addr), client(addr));
+    long_lived.send(b"hello").unwrap();
+    let (long_lived, long_lived_addr) = listener.accept().await.unwrap();
+    idle.send(b"hello").unwrap();
+    let (idle, idle_addr) = listener.accept().await.unwrap();
+    long_lived.recv().await.unwrap();
+    idle.recv().await.unwrap();
+
+    let start = std::time::Instant::now();
+    // The idle peer sends nothing during its close grace.
+    idle.shutdown();
+    tokio::time::sleep(Duration::from_secs(9)).await;
+    assert!(listener.contains_peer(&idle_addr));
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    assert!(!listener.contains_peer(&idle_addr));
+    assert_eq!(
+        idle.recv().await.unwrap_err().kind(),
+        io::ErrorKind::BrokenPipe
+    );
+
+    assert!(listener.contains_peer(&long_lived_addr));
+    tokio::time::sleep(Duration::from_secs(60)).await;
+    assert!(!listener.contains_peer(&long_lived_addr));
+    assert_eq!(
+        long_lived.recv().await.unwrap_err().kind(),
+        io::ErrorKind::TimedOut
+    );
+    assert!(start.elapsed() < PATIENCE, "the clock did not stay paused");
+}
+
+#[tokio::test]
+async fn datagrams_of_high_priority_peers_are_delivered_first() {
+    let listener = UdpListener::bind(localhost()).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (low, high) = (client(addr), client(addr));
+    low.send(b"hello").unwrap();
+    let (low_stream, _) = listener.accept().await.unwrap();
+    high.send(b"hello").unwrap();
+    let (high_stream, _) = listener.accept().await.unwrap();
+    high_stream.set_priority(10);
+    low_stream.recv().await.unwrap();
+    high_stream.recv().await.unwrap();
+
+    // The receive loop shares this thread, so it only sees the datagrams
+    // once they are all waiting, the high priority one last.
+    for _ in 0..20 {
+        low.send(b"bulk").unwrap();
+    }
+    high.send(b"urgent").unwrap();
+    assert_eq!(high_stream.recv().await.unwrap(), b"urgent"[..]);
+    for _ in 0..20 {
+        low_stream.recv().await.unwrap();
+    }
+    // Each stream is touched when a datagram is handed to it.
+    assert!(
+        high_stream.last_activity() < low_stream.last_activity(),
+        "the urgent datagram was handed over after the bulk ones"
+    );
+}
+
+#[tokio::test]
+async fn quiesce_resolves_once_the_queued_datagrams_are_read() {
+    let listener = UdpListener::bind(localhost()).await.unwrap();
+    let client = client(listener.local_addr().unwrap());
+    for datagram in [b"one", b"two", b"six"] {
+        client.send(datagram).unwrap();
+    }
+    let (mut stream, _) = listener.accept().await.unwrap();
+    tokio::time::sleep(SETTLE).await;
+
+    let err = listener.quiesce(SETTLE).await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+    for expected in [b"one", b"two", b"six"] {
+        assert_eq!(read_datagram(&mut stream).await, expected);
+    }
+    listener.quiesce(PATIENCE).await.unwrap();
+}
+
+// Only Linux routes the whole of 127.0.0.0/8 to the loopback interface.
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn peers_per_ip_are_capped_for_each_address_separately() {
+    const LIMIT: usize = 3;
+    let listener = UdpListener::builder()
+        .max_peers_per_ip(LIMIT)
+        .bind(localhost())
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut streams = Vec::new();
+    for _ in 0..LIMIT {
+        client(addr).send(b"hello").unwrap();
+        streams.push(listener.accept().await.unwrap().0);
+    }
+
+    let refused: Vec<_> = (0..10).map(|_| client(addr)).collect();
+    for client in &refused {
+        client.send(b"hello").unwrap();
+    }
+    tokio::time::sleep(SETTLE).await;
+    for client in &refused {
+        assert!(!listener.contains_peer(&client.local_addr().unwrap()));
+    }
+    assert_eq!(listener.backlog_len(), 0);
+    assert_eq!(listener.stats().dropped_peers_per_ip, refused.len() as u64);
+
+    for _ in 0..LIMIT {
+        let other = std::net::UdpSocket::bind("127.0.0.2:0").unwrap();
+        other.send_to(b"hello", addr).unwrap();
+        let (_, peer_addr) = tokio::time::timeout(PATIENCE, listener.accept())
+            .await
+            .expect("peer of another address refused")
+            .unwrap();
+        assert_eq!(peer_addr, other.local_addr().unwrap());
+    }
+
+    // Untracking a peer frees its slot for another port of the address.
+    drop(streams.pop());
+    tokio::time::sleep(SETTLE).await;
+    let admitted = client(addr);
+    admitted.send(b"hello").unwrap();
+    let (_, peer_addr) = tokio::time::timeout(PATIENCE, listener.accept())
+        .await
+        .expect("freed slot not reused")
+        .unwrap();
+    assert_eq!(peer_addr, admitted.local_addr().unwrap());
+}
+
+/// Sends a numbered sequence from several peers of `listener`, each at a
+/// different priority, and checks every stream reads its own in order.
+async fn assert_peers_read_in_order(listener: UdpListener) {
+    const PEERS: u32 = 4;
+    const COUNT: u32 = 500;
+    let addr = listener.local_addr().unwrap();
+    let clients: Vec<_> = (0..PEERS).map(|_| client(addr)).collect();
+    let mut streams = Vec::new();
+    for (priority, client) in clients.iter().enumerate() {
+        client.send(&0u32.to_be_bytes()).unwrap();
+        let (stream, peer_addr) = listener.accept().await.unwrap();
+        assert_eq!(peer_addr, client.local_addr().unwrap());
+        stream.set_priority(priority as u8);
+        streams.push(stream);
+    }
+
+    for seq in 1..COUNT {
+        for client in &clients {
+            client.send(&seq.to_be_bytes()).unwrap();
+        }
+        // Lets the listener drain its socket before the kernel drops any.
+        if seq % 25 == 0 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+    for stream in &mut streams {
+        for expected in 0..COUNT {
+            let datagram = read_datagram(stream).await;
+            assert_eq!(
+                u32::from_be_bytes(datagram[..].try_into().unwrap()),
+                expected
+            );
+        }
+    }
+}
+
+#[tokio::test]
+async fn each_peer_is_read_in_the_order_it_was_received() {
+    let builder = || UdpListener::builder().channel_capacity(1000);
+    assert_peers_read_in_order(builder().bind(localhost()).await.unwrap()).await;
+    assert_peers_read_in_order(
+        builder()
+            .delivery_interval(Duration::from_millis(5))
+            .bind(localhost())
+            .await
+            .unwrap(),
+    )
+    .await;
+    #[cfg(target_os = "linux")]
+    assert_peers_read_in_order(builder().demux_workers(4).bind(localhost()).await.unwrap()).await;
+}
+
+#[tokio::test]
+async fn crossing_the_high_watermark_is_reported_once_per_crossing() {
+    let listener = UdpListener::builder()
+        .channel_capacity(10)
+        .channel_high_watermark(0.5)
+        .bind(localhost())
+        .await
+        .unwrap();
+    let client = client(listener.local_addr().unwrap());
+    client.send(b"hello").unwrap();
+    let (mut stream, _) = listener.accept().await.unwrap();
+
+    // Filling the queue up to its capacity crosses the watermark once.
+    for _ in 1..10 {
+        client.send(b"queued").unwrap();
+    }
+    tokio::time::sleep(SETTLE).await;
+    assert_eq!(listener.stats().high_watermark_crossings, 1);
+
+    // Draining it below the watermark, then filling it again, is a second
+    // crossing.
+    for _ in 0..10 {
+        read_datagram(&mut stream).await;
+    }
+    client.send(b"below").unwrap();
+    tokio::time::sleep(SETTLE).await;
+    assert_eq!(listener.stats().high_watermark_crossings, 1);
+    for _ in 0..6 {
+        client.send(b"queued").unwrap();
+    }
+    tokio::time::sleep(SETTLE).await;
+    assert_eq!(listener.stats().high_watermark_crossings, 2);
+}
+
+#[tokio::test]
+async fn accept_with_first_returns_the_first_datagram_and_reads_the_next() {
+    let listener = UdpListener::bind(localhost()).await.unwrap();
+    let client = client(listener.local_addr().unwrap());
+    client.send(b"first").unwrap();
+    client.send(b"second").unwrap();
+
+    let (mut stream, peer_addr, first) = listener.accept_with_first().await.unwrap();
+    assert_eq!(peer_addr, client.local_addr().unwrap());
+    assert_eq!(&first[..], b"first");
+    assert_eq!(read_datagram(&mut stream).await, b"second");
+    assert_eq!(stream.stats().datagrams_received, 2);
+}
+
+#[tokio::test]
+async fn listeners_sized_for_many_peers_serve_them_all() {
+    let listener = UdpListener::builder()
+        .expected_peers(100_000)
+        .bind(localhost())
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    let clients: Vec<_> = (0..100).map(|_| client(addr)).collect();
+    let mut streams = Vec::new();
+    for client in &clients {
+        client.send(b"hello").unwrap();
+        streams.push(listener.accept().await.unwrap());
+    }
+    assert_eq!(listener.stats().peers, clients.len());
+
+    for (client, (stream, peer_addr)) in clients.iter().zip(&mut streams) {
+        assert_eq!(*peer_addr, client.local_addr().unwrap());
+        assert!(listener.contains_peer(peer_addr));
+        assert_eq!(read_datagram(stream).await, b"hello");
+        stream.write_all(b"served").await.unwrap();
+        assert_eq!(recv(client), b"served");
+    }
+}
+
+#[tokio::test]
+async fn muted_peers_are_dropped_until_unmuted_and_can_still_be_written_to() {
+    let listener = UdpListener::bind(localhost()).await.unwrap();
+    let client = client(listener.local_addr().unwrap());
+    client.send(b"hello").unwrap();
+    let (mut stream, peer_addr) = listener.accept().await.unwrap();
+    assert_eq!(read_datagram(&mut stream).await, b"hello");
+
+    assert!(listener.mute(&peer_addr));
+    for _ in 0..3 {
+        client.send(b"muted").unwrap();
+    }
+    tokio::time::sleep(SETTLE).await;
+    assert_eq!(listener.stats().dropped_muted, 3);
+    assert!(listener.contains_peer(&peer_addr));
+    stream.write_all(b"still writable").await.unwrap();
+    assert_eq!(recv(&client), b"still writable");
+
+    assert!(listener.unmute(&peer_addr));
+    client.send(b"heard").unwrap();
+    assert_eq!(read_datagram(&mut stream).await, b"heard");
+    assert_eq!(listener.stats().dropped_muted, 3);
+
+    let unknown = "127.0.0.1:9".parse().unwrap();
+    assert!(!listener.mute(&unknown));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn reconnecting_while_muting_leaves_one_stream_per_session_and_no_leaks() {
+    const ROUNDS: u32 = 300;
+    let listener = UdpListener::bind(localhost()).await.unwrap();
+    let client = client(listener.local_addr().unwrap());
+    let client_addr = client.local_addr().unwrap();
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let toggler = tokio::spawn({
+        let listener = listener.clone();
+        let stop = stop.clone();
+        async move {
+            while !stop.load(Ordering::Relaxed) {
+                listener.mute(&client_addr);
+                tokio::task::yield_now().await;
+                listener.unmute(&client_addr);
+                tokio::task::yield_now().await;
+            }
+        }
+    });
+
+    for round in 0..ROUNDS {
+        // The first datagram of a round may reach the session being torn
+        // down and be dropped with it, so it is sent until accepted. A resent
+        // datagram of the previous round may also start a session of its
+        // own once that round's stream is dropped, which is ended in turn.
+        let deadline = tokio::time::Instant::now() + PATIENCE;
+        loop {
+            assert!(
+                tokio::time::Instant::now() < deadline,
+                "round {} not accepted",
+                round
+            );
+            client.send(&round.to_be_bytes()).unwrap();
+            let retry = Duration::from_millis(5);
+            let Ok(accepted) = tokio::time::timeout(retry, listener.accept()).await else {
+                continue;
+            };
+            let (mut stream, peer_addr) = accepted.unwrap();
+            assert_eq!(peer_addr, client_addr);
+            assert_eq!(listener.backlog_len(), 0, "round {} accepted twice", round);
+            let first = read_datagram(&mut stream).await;
+            let first = u32::from_be_bytes(first[..].try_into().unwrap());
+            assert!(first <= round, "round {} read {}", round, first);
+            if first == round {
+                break;
+            }
+        }
+    }
+    stop.store(true, Ordering::Relaxed);
+    toggler.await.unwrap();
+
+    // So are sessions started by resent datagrams of the last round.
+    while let Ok(accepted) = tokio::time::timeout(SETTLE, listener.accept()).await {
+        drop(accepted.unwrap());
+    }
+    tokio::time::sleep(SETTLE).await;
+    assert!(!listener.contains_peer(&client_addr));
+    assert_eq!(listener.stats().peers, 0);
+    assert_eq!(listener.backlog_len(), 0);
+}
+
+#[tokio::test]
+async fn lazy_accept_keeps_one_datagram_of_a_flooding_unaccepted_peer() {
+    const FLOOD: usize = 1000;
+    const LEN: usize = 1000;
+    for lazy in [false, true] {
+        let listener = UdpListener::builder()
+            .lazy_accept(lazy)
+            .channel_capacity(64)
+            // Only tracks the buffered bytes.
+            .max_buffered_bytes(usize::MAX)
+            .bind(localhost())
+            .await
+            .unwrap();
+        let client = client(listener.local_addr().unwrap());
+        for sent in 1..=FLOOD {
+            client.send(&[7u8; LEN]).unwrap();
+            // Lets the listener drain its socket before the kernel drops any.
+            if sent % 25 == 0 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        }
+        tokio::time::sleep(SETTLE).await;
+
+        let stats = listener.stats();
+        assert_eq!(listener.backlog_len(), 1);
+        if lazy {
+            assert_eq!(stats.buffered_bytes, LEN);
+            assert_eq!(stats.dropped_unaccepted, FLOOD as u64 - 1);
+        } else {
+            assert_eq!(stats.buffered_bytes, 64 * LEN);
+            assert_eq!(stats.dropped_queue_full, FLOOD as u64 - 64);
+        }
+
+        // Once accepted, the peer's datagrams are queued again.
+        let (stream, _) = listener.accept().await.unwrap();
+        stream.recv().await.unwrap();
+        if lazy {
+            client.send(b"accepted").unwrap();
+            assert_eq!(stream.recv().await.unwrap(), b"accepted"[..]);
+        }
+    }
+}
+
+/// Polls `future` once, without yielding to the runtime.
+fn poll_once<F: Future>(future: F) -> Poll<F::Output> {
+    let mut cx = Context::from_waker(Waker::noop());
+    pin!(future).poll(&mut cx)
+}
+
+#[tokio::test]
+async fn held_streams_fail_to_read_once_the_last_listener_handle_is_dropped() {
+    let workers = if cfg!(target_os = "linux") { 4 } else { 1 };
+    for workers in [1, workers] {
+        let listener = UdpListener::builder()
+            .demux_workers(workers)
+            .bind(localhost())
+            .await
+            .unwrap();
+        let client = client(listener.local_addr().unwrap());
+        client.send(b"hello").unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        read_datagram(&mut stream).await;
+
+        let clone = listener.clone();
+        drop(listener);
+        assert!(poll_once(stream.recv()).is_pending());
+
+        drop(clone);
+        let Poll::Ready(Err(err)) = poll_once(stream.recv()) else {
+            panic!("stream still open after the listener was dropped");
+        };
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    }
+}
@@ -0,0 +1,53 @@
+//! Datagrams compressed with the [`Lz4Codec`] of the `lz4` feature.
+#![cfg(feature = "lz4")]
+
+mod common;
+
+use std::sync::Arc;
+
+use common::{client, localhost, recv_from, server, SETTLE};
+use udp_stream::{DatagramCodec, Lz4Codec, UdpListener, UdpStream};
+
+/// A datagram that compresses well.
+fn plaintext() -> Vec<u8> {
+    b"a compressible line of text\n".repeat(40)
+}
+
+#[tokio::test]
+async fn compressed_datagrams_are_smaller_on_the_wire_and_round_trip() {
+    let server = server().await;
+    let stream = UdpStream::builder()
+        .codec(Arc::new(Lz4Codec::new()))
+        .connect(server.local_addr().unwrap())
+        .await
+        .unwrap();
+    let plaintext = plaintext();
+    stream.send_datagram(&plaintext).await.unwrap();
+
+    let (wire, client_addr) = recv_from(&server).await;
+    assert!(
+        wire.len() < plaintext.len() / 4,
+        "{} bytes on the wire for {}",
+        wire.len(),
+        plaintext.len()
+    );
+    server.send_to(&wire, client_addr).await.unwrap();
+    assert_eq!(stream.recv().await.unwrap(), plaintext);
+}
+
+#[tokio::test]
+async fn datagrams_failing_to_decompress_are_dropped_and_counted() {
+    let listener = UdpListener::builder()
+        .codec(Arc::new(Lz4Codec::new()))
+        .bind(localhost())
+        .await
+        .unwrap();
+    let client = client(listener.local_addr().unwrap());
+    client.send(b"not lz4 at all").unwrap();
+    client.send(&Lz4Codec::new().encode(&plaintext())).unwrap();
+
+    let (stream, _) = listener.accept().await.unwrap();
+    assert_eq!(stream.recv().await.unwrap(), plaintext());
+    tokio::time::sleep(SETTLE).await;
+    assert_eq!(listener.stats().dropped_decode, 1);
+}
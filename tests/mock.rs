@@ -0,0 +1,59 @@
+//! Streams created with [`UdpStream::mock`] under the `test-util` feature.
+#![cfg(feature = "test-util")]
+
+use std::net::SocketAddr;
+
+use bytes::Bytes;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::mpsc,
+};
+use udp_stream::UdpStream;
+
+fn peer() -> SocketAddr {
+    "192.0.2.1:4000".parse().unwrap()
+}
+
+/// Answers every datagram read from `stream` with the same datagram in
+/// upper case, as a protocol under test would.
+async fn shout(mut stream: UdpStream) {
+    let mut buf = [0u8; 64];
+    loop {
+        let len = stream.read(&mut buf).await.unwrap();
+        if len == 0 {
+            break;
+        }
+        stream
+            .write_all(&buf[..len].to_ascii_uppercase())
+            .await
+            .unwrap();
+    }
+}
+
+#[tokio::test]
+async fn injected_datagrams_are_read_and_writes_captured() {
+    let (incoming, rx) = mpsc::channel(4);
+    let (stream, mut written) = UdpStream::mock(peer(), rx).unwrap();
+    assert_eq!(stream.peer_addr().unwrap(), peer());
+    let server = tokio::spawn(shout(stream));
+
+    incoming.send(Bytes::from_static(b"hello")).await.unwrap();
+    assert_eq!(written.recv().await.unwrap(), b"HELLO"[..]);
+    incoming.send(Bytes::from_static(b"bye")).await.unwrap();
+    assert_eq!(written.recv().await.unwrap(), b"BYE"[..]);
+
+    drop(incoming);
+    server.await.unwrap();
+    assert!(written.recv().await.is_none());
+}
+
+#[tokio::test]
+async fn send_all_on_a_mock_is_captured_in_order() {
+    let (_incoming, rx) = mpsc::channel(1);
+    let (stream, mut written) = UdpStream::mock(peer(), rx).unwrap();
+    let datagrams: [&[u8]; 3] = [b"one", b"two", b"three"];
+    assert_eq!(stream.send_all(&datagrams).await.unwrap(), 3);
+    for datagram in datagrams {
+        assert_eq!(written.recv().await.unwrap(), datagram);
+    }
+}
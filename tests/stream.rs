@@ -0,0 +1,855 @@
+//! Reading from and writing to a [`UdpStream`].
+
+mod common;
+
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket as StdUdpSocket},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll, Wake},
+    time::Duration,
+};
+
+use bytes::BytesMut;
+use common::{client, localhost, read_datagram, recv, recv_from, server, PATIENCE, SETTLE};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ReadBuf};
+use udp_stream::{
+    AsyncReadWrite, FrameReassembler, Framing, InvalidPeer, RttTracker, SequenceTracker,
+    UdpListener, UdpStream, ZeroLengthPolicy,
+};
+
+#[tokio::test]
+async fn read_buf_appends_one_datagram_per_call() {
+    let listener = UdpListener::bind(localhost()).await.unwrap();
+    let client = client(listener.local_addr().unwrap());
+    for datagram in [&b"one"[..], b"two", b"three"] {
+        client.send(datagram).unwrap();
+    }
+    let (stream, _) = listener.accept().await.unwrap();
+
+    let mut dst = BytesMut::new();
+    assert_eq!(stream.read_buf(&mut dst).await.unwrap(), 3);
+    assert_eq!(stream.read_buf(&mut dst).await.unwrap(), 3);
+    assert_eq!(stream.read_buf(&mut dst).await.unwrap(), 5);
+    assert_eq!(&dst[..], b"onetwothree");
+}
+
+#[tokio::test]
+async fn zero_length_datagrams_are_empty_messages_skipped_by_async_read() {
+    let listener = UdpListener::bind(localhost()).await.unwrap();
+    let client = client(listener.local_addr().unwrap());
+    for datagram in [&b"a"[..], b"", b"b", b"", b"c"] {
+        client.send(datagram).unwrap();
+    }
+    let (mut stream, _) = listener.accept().await.unwrap();
+
+    assert_eq!(&stream.recv().await.unwrap()[..], b"a");
+    assert!(stream.recv().await.unwrap().is_empty());
+    // An empty read would mean EOF.
+    assert_eq!(read_datagram(&mut stream).await, b"b");
+    assert_eq!(read_datagram(&mut stream).await, b"c");
+}
+
+#[tokio::test]
+async fn a_closed_stream_reads_as_eof_unlike_a_zero_length_datagram() {
+    let listener = UdpListener::bind(localhost()).await.unwrap();
+    let client = client(listener.local_addr().unwrap());
+    for datagram in [&b""[..], b"x", b""] {
+        client.send(datagram).unwrap();
+    }
+    let (mut stream, _) = listener.accept().await.unwrap();
+    tokio::time::sleep(SETTLE).await;
+    drop(listener);
+
+    // Only the message API surfaces the zero-length datagrams.
+    assert!(stream.recv().await.unwrap().is_empty());
+    assert_eq!(read_datagram(&mut stream).await, b"x");
+    assert!(read_datagram(&mut stream).await.is_empty());
+    assert!(stream.recv().await.is_err());
+}
+
+#[tokio::test]
+async fn discarded_zero_length_datagrams_announce_no_stream() {
+    let listener = UdpListener::builder()
+        .zero_length(ZeroLengthPolicy::Discard)
+        .bind(localhost())
+        .await
+        .unwrap();
+    let client = client(listener.local_addr().unwrap());
+    client.send(b"").unwrap();
+    client.send(b"").unwrap();
+    client.send(b"data").unwrap();
+
+    let (mut stream, _) = listener.accept().await.unwrap();
+    assert_eq!(read_datagram(&mut stream).await, b"data");
+    assert_eq!(listener.backlog_len(), 0);
+}
+
+#[tokio::test]
+async fn queued_writes_are_sent_when_the_stream_is_dropped() {
+    let server = server().await;
+    let mut stream = UdpStream::connect(server.local_addr().unwrap())
+        .await
+        .unwrap();
+    stream.set_nodelay(false);
+    stream.write_all(b"first").await.unwrap();
+    stream.write_all(b"second").await.unwrap();
+    let mut buf = [0u8; 16];
+    assert_eq!(
+        server.try_recv_from(&mut buf).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+
+    drop(stream);
+    assert_eq!(recv_from(&server).await.0, b"first");
+    assert_eq!(recv_from(&server).await.0, b"second");
+}
+
+#[tokio::test]
+async fn written_datagrams_are_sent_even_if_the_stream_is_dropped_at_once() {
+    let server = server().await;
+    let mut stream = UdpStream::connect(server.local_addr().unwrap())
+        .await
+        .unwrap();
+    stream.write_all(b"first").await.unwrap();
+    stream.write_all(b"second").await.unwrap();
+
+    drop(stream);
+    assert_eq!(recv_from(&server).await.0, b"first");
+    assert_eq!(recv_from(&server).await.0, b"second");
+}
+
+#[tokio::test]
+async fn queued_writes_are_sent_when_an_accepted_stream_is_shut_down() {
+    let listener = UdpListener::bind(localhost()).await.unwrap();
+    let client = client(listener.local_addr().unwrap());
+    client.send(b"hello").unwrap();
+    let (mut stream, peer_addr) = listener.accept().await.unwrap();
+    stream.set_nodelay(false);
+    stream.write_all(b"first").await.unwrap();
+    stream.write_all(b"second").await.unwrap();
+
+    stream.shutdown();
+    assert_eq!(recv(&client), b"first");
+    assert_eq!(recv(&client), b"second");
+    tokio::time::sleep(SETTLE).await;
+    assert!(!listener.contains_peer(&peer_addr));
+}
+
+#[tokio::test]
+async fn immediate_writes_arrive_at_once_and_queued_ones_on_flush() {
+    let server = server().await;
+    let mut stream = UdpStream::connect(server.local_addr().unwrap())
+        .await
+        .unwrap();
+    assert!(stream.nodelay());
+    stream.write_all(b"immediate").await.unwrap();
+    tokio::time::sleep(SETTLE).await;
+    let mut buf = [0u8; 16];
+    let (len, _) = server.try_recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..len], b"immediate");
+
+    stream.set_nodelay(false);
+    stream.write_all(b"queued").await.unwrap();
+    tokio::time::sleep(SETTLE).await;
+    assert_eq!(
+        server.try_recv_from(&mut buf).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+    stream.flush().await.unwrap();
+    assert_eq!(recv_from(&server).await.0, b"queued");
+}
+
+#[tokio::test]
+async fn a_full_write_queue_is_sent_before_taking_more() {
+    let server = server().await;
+    let mut stream = UdpStream::connect(server.local_addr().unwrap())
+        .await
+        .unwrap();
+    stream.set_nodelay(false);
+    for i in 0..64u8 {
+        stream.write_all(&[i]).await.unwrap();
+    }
+    tokio::time::sleep(SETTLE).await;
+    let mut buf = [0u8; 16];
+    assert_eq!(
+        server.try_recv_from(&mut buf).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+
+    stream.write_all(&[64]).await.unwrap();
+    for i in 0..64u8 {
+        assert_eq!(recv_from(&server).await.0, [i]);
+    }
+    stream.flush().await.unwrap();
+    assert_eq!(recv_from(&server).await.0, [64]);
+}
+
+#[tokio::test]
+async fn direct_sends_follow_the_queued_writes() {
+    let server = server().await;
+    let mut stream = UdpStream::connect(server.local_addr().unwrap())
+        .await
+        .unwrap();
+    stream.set_nodelay(false);
+    stream.write_all(b"queued").await.unwrap();
+    stream.send_datagram(b"datagram").await.unwrap();
+    stream.write_all(b"queued again").await.unwrap();
+    stream.send_all(&[b"first", b"second"]).await.unwrap();
+
+    for expected in [
+        &b"queued"[..],
+        b"datagram",
+        b"queued again",
+        b"first",
+        b"second",
+    ] {
+        assert_eq!(recv_from(&server).await.0, expected);
+    }
+}
+
+#[tokio::test]
+async fn drain_queued_discards_received_datagrams_only() {
+    let listener = UdpListener::bind(localhost()).await.unwrap();
+    let client = client(listener.local_addr().unwrap());
+    for datagram in [&b"stale"[..], b"stale", b"stale"] {
+        client.send(datagram).unwrap();
+    }
+    let (mut stream, _) = listener.accept().await.unwrap();
+    tokio::time::sleep(SETTLE).await;
+
+    assert_eq!(stream.drain_queued(), 3);
+    assert_eq!(stream.drain_queued(), 0);
+    client.send(b"fresh").unwrap();
+    assert_eq!(read_datagram(&mut stream).await, b"fresh");
+}
+
+#[tokio::test]
+async fn drain_queued_empties_the_socket_of_a_direct_stream() {
+    let server = server().await;
+    let mut stream = UdpStream::connect_direct(server.local_addr().unwrap())
+        .await
+        .unwrap();
+    let client_addr = stream.local_addr().unwrap();
+    for _ in 0..2 {
+        server.send_to(b"stale", client_addr).await.unwrap();
+    }
+    tokio::time::sleep(SETTLE).await;
+
+    assert_eq!(stream.drain_queued(), 2);
+    server.send_to(b"fresh", client_addr).await.unwrap();
+    assert_eq!(read_datagram(&mut stream).await, b"fresh");
+}
+
+#[tokio::test]
+async fn reads_time_out_without_data() {
+    let server = server().await;
+    let mut stream = UdpStream::connect(server.local_addr().unwrap())
+        .await
+        .unwrap();
+    stream.set_read_timeout(Some(SETTLE));
+
+    let started = std::time::Instant::now();
+    let mut buf = [0u8; 16];
+    let err = stream.read(&mut buf).await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    assert!(started.elapsed() >= SETTLE);
+
+    // The timeout applies to each read anew.
+    stream.write_all(b"ping").await.unwrap();
+    let (_, client_addr) = recv_from(&server).await;
+    server.send_to(b"pong", client_addr).await.unwrap();
+    assert_eq!(read_datagram(&mut stream).await, b"pong");
+}
+
+#[tokio::test]
+async fn send_to_addr_replies_elsewhere_than_the_peer() {
+    let listener = UdpListener::bind(localhost()).await.unwrap();
+    let peer_a = client(listener.local_addr().unwrap());
+    let peer_b = server().await;
+    peer_a.send(b"request").unwrap();
+    let (mut stream, peer_addr) = listener.accept().await.unwrap();
+    assert_eq!(read_datagram(&mut stream).await, b"request");
+
+    let control = peer_b.local_addr().unwrap();
+    assert_eq!(stream.send_to_addr(b"reply", control).await.unwrap(), 5);
+    assert_eq!(
+        recv_from(&peer_b).await,
+        (b"reply".to_vec(), listener.local_addr().unwrap())
+    );
+    assert_eq!(stream.peer_addr().unwrap(), peer_addr);
+
+    // Peer A still feeds the same stream.
+    peer_a.send(b"again").unwrap();
+    assert_eq!(read_datagram(&mut stream).await, b"again");
+}
+
+#[tokio::test]
+async fn boxed_streams_sit_alongside_other_transports() {
+    let listener = UdpListener::bind(localhost()).await.unwrap();
+    let udp_addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let datagram = read_datagram(&mut stream).await;
+        stream.write_all(&datagram).await.unwrap();
+    });
+    let tcp = tokio::net::TcpListener::bind(localhost()).await.unwrap();
+    let tcp_addr = tcp.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = tcp.accept().await.unwrap();
+        let (mut reader, mut writer) = stream.split();
+        tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+    });
+    let (memory, mut echo) = tokio::io::duplex(64);
+    tokio::spawn(async move {
+        let mut buf = [0u8; 4];
+        echo.read_exact(&mut buf).await.unwrap();
+        echo.write_all(&buf).await.unwrap();
+    });
+
+    let transports: Vec<Box<dyn AsyncReadWrite + Unpin + Send>> = vec![
+        UdpStream::connect(udp_addr).await.unwrap().into_boxed_io(),
+        Box::new(tokio::net::TcpStream::connect(tcp_addr).await.unwrap()),
+        Box::new(memory),
+    ];
+    for mut transport in transports {
+        transport.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        transport.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+}
+
+#[tokio::test]
+async fn an_oversize_write_leaves_the_stream_usable() {
+    let server = server().await;
+    let mut stream = UdpStream::connect(server.local_addr().unwrap())
+        .await
+        .unwrap();
+    // Larger than any UDP datagram, so the send fails with EMSGSIZE.
+    assert!(stream.write(&vec![0u8; 70_000]).await.is_err());
+
+    stream.write_all(b"fits").await.unwrap();
+    assert_eq!(recv_from(&server).await.0, b"fits");
+}
+
+#[tokio::test]
+async fn an_oversize_write_keeps_an_accepted_stream_tracked() {
+    let listener = UdpListener::bind(localhost()).await.unwrap();
+    let client = client(listener.local_addr().unwrap());
+    client.send(b"hello").unwrap();
+    let (mut stream, peer_addr) = listener.accept().await.unwrap();
+    assert!(stream.write(&vec![0u8; 70_000]).await.is_err());
+    tokio::time::sleep(SETTLE).await;
+    assert!(listener.contains_peer(&peer_addr));
+
+    stream.write_all(b"fits").await.unwrap();
+    assert_eq!(recv(&client), b"fits");
+}
+
+#[tokio::test]
+async fn only_client_streams_are_connected() {
+    let server = server().await;
+    let stream = UdpStream::connect(server.local_addr().unwrap())
+        .await
+        .unwrap();
+    assert!(stream.is_connected());
+    let direct = UdpStream::connect_direct(server.local_addr().unwrap())
+        .await
+        .unwrap();
+    assert!(direct.is_connected());
+
+    let listener = UdpListener::bind(localhost()).await.unwrap();
+    let client = client(listener.local_addr().unwrap());
+    client.send(b"hello").unwrap();
+    let (accepted, _) = listener.accept().await.unwrap();
+    assert!(!accepted.is_connected());
+}
+
+#[tokio::test]
+async fn cancelled_receives_do_not_block_other_readers() {
+    let listener = UdpListener::bind(localhost()).await.unwrap();
+    let client = client(listener.local_addr().unwrap());
+    client.send(b"hello").unwrap();
+    let (stream, _) = listener.accept().await.unwrap();
+    let stream = std::sync::Arc::new(stream);
+    stream.recv().await.unwrap();
+
+    // Receives cancelled while waiting, holding the queue or not.
+    for _ in 0..3 {
+        assert!(tokio::time::timeout(SETTLE, stream.recv()).await.is_err());
+    }
+    let readers: Vec<_> = (0..3)
+        .map(|_| {
+            let stream = stream.clone();
+            tokio::spawn(async move { stream.recv().await.unwrap() })
+        })
+        .collect();
+    tokio::task::yield_now().await;
+    for datagram in [&b"a"[..], b"b", b"c"] {
+        client.send(datagram).unwrap();
+    }
+    let mut received = Vec::new();
+    for reader in readers {
+        let datagram = tokio::time::timeout(PATIENCE, reader)
+            .await
+            .expect("reader hung")
+            .unwrap();
+        received.push(datagram);
+    }
+    received.sort();
+    assert_eq!(received, [&b"a"[..], b"b", b"c"]);
+}
+
+#[tokio::test]
+async fn rtt_of_a_delayed_echo_is_measured() {
+    const DELAY: Duration = Duration::from_millis(50);
+    let server = server().await;
+    let server_addr = server.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (datagram, client_addr) = recv_from(&server).await;
+        tokio::time::sleep(DELAY).await;
+        server.send_to(&datagram, client_addr).await.unwrap();
+    });
+    let mut stream = UdpStream::connect(server_addr).await.unwrap();
+    stream.set_rtt_tracker(RttTracker::new(|datagram| {
+        Some(u64::from_be_bytes(datagram.get(..8)?.try_into().ok()?))
+    }));
+    assert_eq!(stream.last_rtt(), None);
+
+    stream.write_all(&7u64.to_be_bytes()).await.unwrap();
+    assert_eq!(read_datagram(&mut stream).await, 7u64.to_be_bytes());
+    let rtt = stream.last_rtt().unwrap();
+    assert!(rtt >= DELAY && rtt < DELAY + PATIENCE / 10, "{:?}", rtt);
+}
+
+#[tokio::test]
+async fn shutdown_read_keeps_the_write_side() {
+    let server = server().await;
+    let mut stream = UdpStream::connect(server.local_addr().unwrap())
+        .await
+        .unwrap();
+    stream.write_all(b"hello").await.unwrap();
+    let (_, client_addr) = recv_from(&server).await;
+    server.send_to(b"unread", client_addr).await.unwrap();
+    tokio::time::sleep(SETTLE).await;
+
+    stream.shutdown_read();
+    server.send_to(b"late", client_addr).await.unwrap();
+    let mut buf = [0u8; 16];
+    assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+    assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+
+    stream.write_all(b"still writing").await.unwrap();
+    assert_eq!(recv_from(&server).await.0, b"still writing");
+}
+
+#[tokio::test]
+async fn connect_with_port_sends_from_the_given_port() {
+    let server = server().await;
+    let port = std::net::UdpSocket::bind("0.0.0.0:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let mut stream = UdpStream::connect_with_port(server.local_addr().unwrap(), port)
+        .await
+        .unwrap();
+    assert_eq!(stream.local_addr().unwrap().port(), port);
+    stream.write_all(b"hello").await.unwrap();
+    assert_eq!(recv_from(&server).await.1.port(), port);
+
+    let Err(err) = UdpStream::connect_with_port(server.local_addr().unwrap(), port).await else {
+        panic!("the port was bound twice");
+    };
+    assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+}
+
+#[tokio::test]
+async fn frames_split_across_datagrams_are_reassembled() {
+    let listener = UdpListener::bind(localhost()).await.unwrap();
+    let client = client(listener.local_addr().unwrap());
+    let frame: Vec<u8> = (0..3000u32).map(|i| i as u8).collect();
+    let mut wire = (frame.len() as u32).to_be_bytes().to_vec();
+    wire.extend(&frame);
+    // The next frame starts in the last datagram.
+    wire.extend(2u32.to_be_bytes());
+    wire.extend(b"ok");
+    for part in wire.chunks(wire.len().div_ceil(3)) {
+        client.send(part).unwrap();
+    }
+
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut frames = FrameReassembler::new(stream, Framing::LengthPrefixed);
+    assert_eq!(frames.next_frame().await.unwrap().unwrap(), frame);
+    assert_eq!(&frames.next_frame().await.unwrap().unwrap()[..], b"ok");
+}
+
+#[tokio::test]
+async fn demux_by_splits_interleaved_datagrams_by_key() {
+    let listener = UdpListener::bind(localhost()).await.unwrap();
+    let client = client(listener.local_addr().unwrap());
+    for datagram in [&b"a1"[..], b"b1", b"a2", b"b2", b"a3"] {
+        client.send(datagram).unwrap();
+    }
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut substreams = stream.demux_by(|datagram| datagram[0]);
+
+    let (key, mut a) = substreams.next().await.unwrap();
+    assert_eq!(key, b'a');
+    let (key, mut b) = substreams.next().await.unwrap();
+    assert_eq!(key, b'b');
+    for expected in [&b"a1"[..], b"a2", b"a3"] {
+        assert_eq!(&a.recv().await.unwrap()[..], expected);
+    }
+    let mut buf = [0u8; 16];
+    for expected in [&b"b1"[..], b"b2"] {
+        let len = b.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], expected);
+    }
+
+    // Writes of any sub-stream go to the peer.
+    b.write_all(b"reply").await.unwrap();
+    assert_eq!(recv(&client), b"reply");
+}
+
+#[tokio::test]
+async fn sequence_gaps_and_reorders_are_counted() {
+    let listener = UdpListener::bind(localhost()).await.unwrap();
+    let client = client(listener.local_addr().unwrap());
+    // A one-byte type, then a two-byte sequence number.
+    for seq in [1u16, 2, 4, 3] {
+        let [high, low] = seq.to_be_bytes();
+        client.send(&[0xAA, high, low]).unwrap();
+    }
+    let (mut stream, _) = listener.accept().await.unwrap();
+    stream.set_sequence_tracker(SequenceTracker::new(1, 2));
+
+    for _ in 0..3 {
+        stream.recv().await.unwrap();
+    }
+    assert_eq!((stream.sequence_gaps(), stream.sequence_reorders()), (1, 0));
+    stream.recv().await.unwrap();
+    assert_eq!((stream.sequence_gaps(), stream.sequence_reorders()), (1, 1));
+}
+
+#[tokio::test]
+async fn connecting_without_ipv6_names_the_missing_family() {
+    if std::net::UdpSocket::bind("[::]:0").is_ok() {
+        eprintln!("IPv6 is available, skipping");
+        return;
+    }
+    let Err(err) = UdpStream::connect("[::1]:4000".parse().unwrap()).await else {
+        panic!("connected over IPv6 on a host without it");
+    };
+    assert!(err.to_string().contains("IPv6 is not available"), "{}", err);
+}
+
+/// Returns the address `err` says a stream could not be connected to.
+fn invalid_peer(err: &io::Error) -> SocketAddr {
+    err.get_ref()
+        .and_then(|err| err.downcast_ref::<InvalidPeer>())
+        .unwrap_or_else(|| panic!("not an invalid peer: {}", err))
+        .addr()
+}
+
+#[tokio::test]
+async fn unspecified_addresses_and_port_zero_are_invalid_peers() {
+    for addr in ["0.0.0.0:4000", "127.0.0.1:0"] {
+        let addr = addr.parse().unwrap();
+        let Err(err) = UdpStream::connect(addr).await else {
+            panic!("connected to {}", addr);
+        };
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(invalid_peer(&err), addr);
+    }
+
+    let server = server().await;
+    UdpStream::connect(server.local_addr().unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn send_all_sends_each_datagram_separately() {
+    let server = server().await;
+    let stream = UdpStream::connect(server.local_addr().unwrap())
+        .await
+        .unwrap();
+    let datagrams: [&[u8]; 5] = [b"one", b"two", b"three", b"four", b"five"];
+    assert_eq!(stream.send_all(&datagrams).await.unwrap(), 5);
+    for datagram in datagrams {
+        assert_eq!(recv_from(&server).await.0, datagram);
+    }
+}
+
+#[tokio::test]
+async fn send_all_reports_where_it_stopped() {
+    let server = server().await;
+    let stream = UdpStream::connect(server.local_addr().unwrap())
+        .await
+        .unwrap();
+    let oversize = vec![0u8; 70_000];
+    let datagrams: [&[u8]; 4] = [b"one", b"two", &oversize, b"four"];
+    let err = stream.send_all(&datagrams).await.unwrap_err();
+    assert_eq!(err.sent(), 2);
+    assert_eq!(recv_from(&server).await.0, b"one");
+    assert_eq!(recv_from(&server).await.0, b"two");
+    tokio::time::sleep(SETTLE).await;
+    let mut buf = [0u8; 16];
+    assert_eq!(
+        server.try_recv_from(&mut buf).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+}
+
+#[tokio::test]
+async fn a_closed_peer_port_reads_as_peer_unreachable_once() {
+    let server = server().await;
+    let addr = server.local_addr().unwrap();
+    drop(server);
+    let mut stream = UdpStream::connect(addr).await.unwrap();
+    stream.write_all(b"anyone there?").await.unwrap();
+
+    let mut buf = [0u8; 16];
+    let err = tokio::time::timeout(PATIENCE, stream.read(&mut buf))
+        .await
+        .unwrap()
+        .unwrap_err();
+    assert!(
+        err.get_ref()
+            .is_some_and(|err| err.is::<udp_stream::PeerUnreachable>()),
+        "{:?}",
+        err
+    );
+
+    let server = tokio::net::UdpSocket::bind(addr).await.unwrap();
+    server
+        .send_to(b"back", stream.local_addr().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(read_datagram(&mut stream).await, b"back");
+}
+
+#[tokio::test]
+async fn the_shutdown_payload_is_sent_once_when_a_client_stream_ends() {
+    let server = server().await;
+    let mut stream = UdpStream::builder()
+        .shutdown_payload(&b"bye"[..])
+        .connect(server.local_addr().unwrap())
+        .await
+        .unwrap();
+    stream.write_all(b"hello").await.unwrap();
+    AsyncWriteExt::shutdown(&mut stream).await.unwrap();
+    drop(stream);
+
+    assert_eq!(recv_from(&server).await.0, b"hello");
+    assert_eq!(recv_from(&server).await.0, b"bye");
+    tokio::time::sleep(SETTLE).await;
+    let mut buf = [0u8; 16];
+    assert_eq!(
+        server.try_recv_from(&mut buf).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+}
+
+#[tokio::test]
+async fn the_shutdown_payload_is_sent_when_an_accepted_stream_is_dropped() {
+    let listener = UdpListener::builder()
+        .shutdown_payload(&b"bye"[..])
+        .bind(localhost())
+        .await
+        .unwrap();
+    let client = client(listener.local_addr().unwrap());
+    client.send(b"hello").unwrap();
+    let (stream, _) = listener.accept().await.unwrap();
+    drop(stream);
+    assert_eq!(recv(&client), b"bye");
+}
+
+#[tokio::test]
+async fn bursts_are_paced_at_the_send_rate() {
+    let server = server().await;
+    let mut stream = UdpStream::connect(server.local_addr().unwrap())
+        .await
+        .unwrap();
+    // A bucket of 1000 bytes, refilled at 10 bytes a millisecond.
+    stream.set_send_rate(Some(10_000));
+
+    let start = std::time::Instant::now();
+    for _ in 0..30 {
+        stream.write_all(&[0u8; 100]).await.unwrap();
+    }
+    let elapsed = start.elapsed();
+    // 1000 bytes go out at once and the other 2000 take 200 milliseconds.
+    assert!(
+        (Duration::from_millis(180)..Duration::from_millis(400)).contains(&elapsed),
+        "3000 bytes sent in {:?}",
+        elapsed
+    );
+    assert_eq!(stream.stats().datagrams_sent, 30);
+}
+
+/// The default receive buffer size.
+const BUFFER_SIZE: usize = 17480;
+
+#[tokio::test]
+async fn datagrams_of_the_buffer_size_reach_client_streams_whole() {
+    let server = server().await;
+    let addr = server.local_addr().unwrap();
+    for stream in [
+        UdpStream::connect(addr).await.unwrap(),
+        UdpStream::connect_direct(addr).await.unwrap(),
+    ] {
+        stream.send_datagram(b"hello").await.unwrap();
+        let (_, client_addr) = recv_from(&server).await;
+        let sizes = [BUFFER_SIZE - 1, BUFFER_SIZE, BUFFER_SIZE, 1, BUFFER_SIZE];
+        for size in sizes {
+            server.send_to(&vec![7u8; size], client_addr).await.unwrap();
+        }
+        for size in sizes {
+            let datagram = tokio::time::timeout(PATIENCE, stream.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(datagram.len(), size);
+        }
+    }
+}
+
+#[tokio::test]
+async fn stats_count_round_tripped_datagrams_and_bytes() {
+    let listener = UdpListener::bind(localhost()).await.unwrap();
+    let client = client(listener.local_addr().unwrap());
+    for datagram in [&b"one"[..], b"three", b"seventh"] {
+        client.send(datagram).unwrap();
+    }
+    let (mut stream, _) = listener.accept().await.unwrap();
+    stream.recv().await.unwrap();
+    assert_eq!(read_datagram(&mut stream).await, b"three");
+    assert_eq!(read_datagram(&mut stream).await, b"seventh");
+
+    stream.write_all(b"reply").await.unwrap();
+    stream.send_datagram(b"and more").await.unwrap();
+    assert!(stream.send_datagram(&vec![0u8; 70_000]).await.is_err());
+    assert_eq!(recv(&client), b"reply");
+    assert_eq!(recv(&client), b"and more");
+
+    let stats = stream.stats();
+    assert_eq!((stats.datagrams_received, stats.bytes_received), (3, 15));
+    assert_eq!((stats.datagrams_sent, stats.bytes_sent), (2, 13));
+    assert_eq!(stats.send_errors, 1);
+}
+
+#[tokio::test]
+async fn receive_metadata_reports_only_the_source_by_default() {
+    let listener = UdpListener::bind(localhost()).await.unwrap();
+    let client = client(listener.local_addr().unwrap());
+    client.send(b"ping").unwrap();
+    let (stream, peer_addr) = listener.accept().await.unwrap();
+
+    let (payload, meta) = stream.recv_meta().await.unwrap();
+    assert_eq!(&payload[..], b"ping");
+    assert_eq!(meta.source, peer_addr);
+    assert_eq!(meta.source, client.local_addr().unwrap());
+    assert_eq!(meta.local_addr, None);
+    assert_eq!(meta.timestamp, None);
+    assert_eq!(meta.ecn, None);
+}
+
+#[tokio::test]
+async fn client_streams_hand_their_socket_over_for_native_use() {
+    let server = server().await;
+    let server_addr = server.local_addr().unwrap();
+    let stream = UdpStream::connect(server_addr).await.unwrap();
+    let local_addr = stream.local_addr().unwrap();
+
+    let socket = stream.into_socket().await.unwrap();
+    assert_eq!(socket.local_addr().unwrap(), local_addr);
+    assert_eq!(socket.peer_addr().unwrap(), server_addr);
+    socket.send(b"ping").await.unwrap();
+    let (datagram, addr) = recv_from(&server).await;
+    assert_eq!((&datagram[..], addr), (&b"ping"[..], local_addr));
+
+    server.send_to(b"pong", local_addr).await.unwrap();
+    let mut buf = [0u8; 16];
+    let len = tokio::time::timeout(PATIENCE, socket.recv(&mut buf))
+        .await
+        .expect("no datagram received")
+        .unwrap();
+    assert_eq!(&buf[..len], b"pong");
+}
+
+#[tokio::test]
+async fn accepted_streams_keep_the_shared_socket() {
+    let listener = UdpListener::bind(localhost()).await.unwrap();
+    let client = client(listener.local_addr().unwrap());
+    client.send(b"ping").unwrap();
+    let (stream, _) = listener.accept().await.unwrap();
+
+    let mut stream = stream.into_socket().await.unwrap_err();
+    assert_eq!(read_datagram(&mut stream).await, b"ping");
+}
+
+/// Counts how many times the task it wakes was woken.
+#[derive(Default)]
+struct CountingWaker(AtomicUsize);
+
+impl Wake for CountingWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Polls `stream` for a datagram while it has none, then checks a single
+/// datagram sent by `send` wakes the reader exactly once and is read.
+async fn assert_reader_woken_once(mut stream: UdpStream, send: impl FnOnce()) {
+    let counter = Arc::new(CountingWaker::default());
+    let waker = counter.clone().into();
+    let mut cx = Context::from_waker(&waker);
+    let mut buf = [0u8; 16];
+    let mut read_buf = ReadBuf::new(&mut buf);
+    for _ in 0..3 {
+        assert!(Pin::new(&mut stream)
+            .poll_read(&mut cx, &mut read_buf)
+            .is_pending());
+    }
+    tokio::time::sleep(SETTLE).await;
+    assert_eq!(counter.0.load(Ordering::Relaxed), 0);
+
+    send();
+    tokio::time::sleep(SETTLE).await;
+    assert_eq!(counter.0.load(Ordering::Relaxed), 1);
+    assert!(matches!(
+        Pin::new(&mut stream).poll_read(&mut cx, &mut read_buf),
+        Poll::Ready(Ok(()))
+    ));
+    assert_eq!(read_buf.filled(), b"wake up");
+}
+
+#[tokio::test]
+async fn a_waiting_reader_is_woken_once_by_a_single_datagram() {
+    let listener = UdpListener::bind(localhost()).await.unwrap();
+    let client = client(listener.local_addr().unwrap());
+    client.send(b"hello").unwrap();
+    let (mut stream, _) = listener.accept().await.unwrap();
+    read_datagram(&mut stream).await;
+    assert_reader_woken_once(stream, || {
+        client.send(b"wake up").unwrap();
+    })
+    .await;
+
+    let server = StdUdpSocket::bind(localhost()).unwrap();
+    let stream = UdpStream::connect(server.local_addr().unwrap())
+        .await
+        .unwrap();
+    let stream_addr = stream.local_addr().unwrap();
+    assert_reader_woken_once(stream, || {
+        server.send_to(b"wake up", stream_addr).unwrap();
+    })
+    .await;
+}
@@ -0,0 +1,358 @@
+//! Socket options and ancillary data, which are only all supported on Linux.
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, UdpSocket as StdUdpSocket},
+    os::fd::AsRawFd,
+    time::{Duration, SystemTime},
+};
+
+use bytes::Bytes;
+use common::{client, localhost, server, SETTLE};
+use tokio::net::UdpSocket;
+use udp_stream::{Ecn, InvalidPeer, UdpListener, UdpStream};
+
+#[tokio::test]
+async fn path_mtu_of_a_connected_stream_is_plausible() {
+    let server = server().await;
+    let socket = UdpSocket::bind(localhost()).await.unwrap();
+    socket.connect(server.local_addr().unwrap()).await.unwrap();
+    let stream = UdpStream::from_tokio(socket, server.local_addr().unwrap())
+        .await
+        .unwrap();
+    let mtu = stream.path_mtu().unwrap();
+    // The loopback interface, at most 64 KiB.
+    assert!((1280..=65536).contains(&mtu), "path MTU {}", mtu);
+}
+
+#[tokio::test]
+async fn path_mtu_requires_a_connected_socket() {
+    let listener = UdpListener::bind(localhost()).await.unwrap();
+    let client = client(listener.local_addr().unwrap());
+    client.send(b"ping").unwrap();
+    let (stream, _) = listener.accept().await.unwrap();
+    assert!(stream.path_mtu().is_err());
+}
+
+/// Reads an int socket option of `socket`.
+fn getsockopt_int(socket: &impl AsRawFd, level: libc::c_int, name: libc::c_int) -> libc::c_int {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: `value` and `len` are valid for writes and describe an int.
+    let ret = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    assert_eq!(ret, 0, "{}", std::io::Error::last_os_error());
+    value
+}
+
+#[tokio::test]
+async fn set_dontfrag_switches_path_mtu_discovery() {
+    let listener = UdpListener::bind(localhost()).await.unwrap();
+    let mtu_discover = || getsockopt_int(&listener, libc::IPPROTO_IP, libc::IP_MTU_DISCOVER);
+    listener.set_dontfrag(true).unwrap();
+    assert_eq!(mtu_discover(), libc::IP_PMTUDISC_DO);
+    listener.set_dontfrag(false).unwrap();
+    assert_eq!(mtu_discover(), libc::IP_PMTUDISC_WANT);
+}
+
+#[tokio::test]
+async fn set_dontfrag_covers_ipv6_sockets() {
+    let Ok(listener) = UdpListener::bind("[::1]:0".parse().unwrap()).await else {
+        return; // IPv6 is disabled on this host.
+    };
+    listener.set_dontfrag(true).unwrap();
+    assert_eq!(
+        getsockopt_int(&listener, libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER),
+        libc::IPV6_PMTUDISC_DO
+    );
+}
+
+#[tokio::test]
+async fn kernel_timestamps_increase_across_datagrams() {
+    let listener = UdpListener::builder()
+        .timestamps(true)
+        .bind(localhost())
+        .await
+        .unwrap();
+    let client = client(listener.local_addr().unwrap());
+    // The kernel switches timestamping on lazily the first time a socket asks
+    // for it, stamping datagrams as they are read until then.
+    client.send(b"warm up").unwrap();
+    let (stream, _) = listener.accept().await.unwrap();
+    stream.recv().await.unwrap();
+    tokio::time::sleep(SETTLE).await;
+
+    let before = SystemTime::now();
+    for i in 0..5u8 {
+        client.send(&[i]).unwrap();
+        std::thread::sleep(Duration::from_millis(2));
+    }
+
+    let mut timestamps = Vec::new();
+    for i in 0..5u8 {
+        let (payload, timestamp) = stream.recv_with_timestamp().await.unwrap();
+        assert_eq!(payload[..], [i]);
+        timestamps.push(timestamp);
+    }
+    // Taken as the datagrams arrived, not as they were read.
+    let read = SystemTime::now();
+    assert!(timestamps[0] >= before);
+    assert!(
+        timestamps.windows(2).all(|pair| pair[0] < pair[1]),
+        "{:?}",
+        timestamps
+    );
+    assert!(read.duration_since(timestamps[0]).unwrap() >= Duration::from_millis(8));
+}
+
+#[tokio::test]
+async fn the_raw_fd_is_the_listening_socket() {
+    let listener = UdpListener::bind(localhost()).await.unwrap();
+    assert!(listener.as_raw_fd() >= 0);
+    assert_eq!(
+        getsockopt_int(&listener, libc::SOL_SOCKET, libc::SO_TYPE),
+        libc::SOCK_DGRAM
+    );
+
+    // Borrowing the fd leaves the listener's socket open and usable.
+    let fd = borrow_fd(&listener);
+    let local_addr = socket2::SockRef::from(&fd).local_addr().unwrap();
+    assert_eq!(local_addr.as_socket(), Some(listener.local_addr().unwrap()));
+}
+
+#[tokio::test]
+async fn ecn_codepoints_are_sent_and_read_back() {
+    let listener = UdpListener::builder()
+        .ecn(true)
+        .bind(localhost())
+        .await
+        .unwrap();
+    let stream = UdpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    for ecn in [Ecn::Ect0, Ecn::Ect1, Ecn::Ce] {
+        stream.send_ecn(b"marked", ecn).await.unwrap();
+    }
+    stream.send_datagram(b"plain").await.unwrap();
+    // Sent through the same path as any other datagram.
+    assert_eq!(stream.stats().datagrams_sent, 4);
+
+    let (accepted, _) = listener.accept().await.unwrap();
+    for ecn in [Ecn::Ect0, Ecn::Ect1, Ecn::Ce] {
+        assert_eq!(
+            accepted.recv_ecn().await.unwrap(),
+            (Bytes::from_static(b"marked"), Some(ecn))
+        );
+    }
+    assert_eq!(
+        accepted.recv_ecn().await.unwrap(),
+        (Bytes::from_static(b"plain"), Some(Ecn::NotEct))
+    );
+}
+
+/// Borrows the socket of `listener` to read its options back.
+fn borrow_fd(listener: &UdpListener) -> std::os::fd::BorrowedFd<'_> {
+    // SAFETY: the listener keeps the socket open for as long as it is borrowed.
+    unsafe { std::os::fd::BorrowedFd::borrow_raw(listener.as_raw_fd()) }
+}
+
+#[tokio::test]
+async fn multicast_options_are_applied_to_the_socket() {
+    let listener = UdpListener::bind(localhost()).await.unwrap();
+    listener.set_multicast_if_v4(Ipv4Addr::LOCALHOST).unwrap();
+    listener.set_multicast_ttl_v4(4).unwrap();
+    listener.set_multicast_loop_v4(false).unwrap();
+    let fd = borrow_fd(&listener);
+    let socket = socket2::SockRef::from(&fd);
+    assert_eq!(socket.multicast_if_v4().unwrap(), Ipv4Addr::LOCALHOST);
+    assert_eq!(socket.multicast_ttl_v4().unwrap(), 4);
+    assert!(!socket.multicast_loop_v4().unwrap());
+
+    let Ok(listener) = UdpListener::bind("[::1]:0".parse().unwrap()).await else {
+        return; // IPv6 is disabled on this host.
+    };
+    // The loopback interface.
+    listener.set_multicast_if_v6(1).unwrap();
+    listener.set_multicast_loop_v6(false).unwrap();
+    let fd = borrow_fd(&listener);
+    let socket = socket2::SockRef::from(&fd);
+    assert_eq!(socket.multicast_if_v6().unwrap(), 1);
+    assert!(!socket.multicast_loop_v6().unwrap());
+}
+
+/// Returns a link-local IPv6 address of this host with the scope id of its
+/// interface and port 0, if any interface has one.
+fn link_local() -> Option<SocketAddrV6> {
+    let interfaces = std::fs::read_to_string("/proc/net/if_inet6").ok()?;
+    interfaces.lines().find_map(|line| {
+        // The address in hex, then the interface index in hex.
+        let mut fields = line.split_whitespace();
+        let ip = u128::from_str_radix(fields.next()?, 16).ok()?;
+        let scope_id = u32::from_str_radix(fields.next()?, 16).ok()?;
+        let ip = Ipv6Addr::from(ip);
+        ip.is_unicast_link_local()
+            .then(|| SocketAddrV6::new(ip, 0, 0, scope_id))
+    })
+}
+
+#[tokio::test]
+async fn link_local_listeners_keep_the_scope_id() {
+    let Some(addr) = link_local() else {
+        eprintln!("no link-local IPv6 address, skipping");
+        return;
+    };
+    let listener = UdpListener::bind(SocketAddr::V6(addr)).await.unwrap();
+    let SocketAddr::V6(local_addr) = listener.local_addr().unwrap() else {
+        panic!("an IPv6 listener has an IPv4 address");
+    };
+    assert_eq!(
+        (*local_addr.ip(), local_addr.scope_id()),
+        (*addr.ip(), addr.scope_id())
+    );
+
+    let client = StdUdpSocket::bind(addr).unwrap();
+    client.send_to(b"hello", local_addr).unwrap();
+    let (_, peer_addr) = listener.accept().await.unwrap();
+    let SocketAddr::V6(peer_addr) = peer_addr else {
+        panic!("an IPv6 peer has an IPv4 address");
+    };
+    assert_eq!(peer_addr.scope_id(), addr.scope_id());
+}
+
+/// Has 16 clients send to `listener` and checks each is accepted once with
+/// its datagram.
+async fn accepts_every_client(listener: &UdpListener, addr: SocketAddr) {
+    let clients: Vec<_> = (0..16).map(|_| client(addr)).collect();
+    for client in &clients {
+        client.send(b"hello").unwrap();
+    }
+    let mut accepted = Vec::new();
+    for _ in &clients {
+        let (stream, peer_addr) = listener.accept().await.unwrap();
+        assert_eq!(stream.recv().await.unwrap(), b"hello"[..]);
+        accepted.push(peer_addr);
+    }
+    accepted.sort();
+    let mut expected: Vec<_> = clients.iter().map(|c| c.local_addr().unwrap()).collect();
+    expected.sort();
+    assert_eq!(accepted, expected);
+}
+
+#[tokio::test]
+async fn every_constructor_binds_a_socket_per_demux_worker() {
+    const WORKERS: usize = 3;
+    let builder = || UdpListener::builder().demux_workers(WORKERS);
+    let listeners = [
+        builder().bind(localhost()).await.unwrap(),
+        builder()
+            .bind_ephemeral(Ipv4Addr::LOCALHOST.into())
+            .await
+            .unwrap(),
+        builder()
+            .bind_retry(localhost(), 1, Duration::from_millis(1))
+            .await
+            .unwrap(),
+        builder()
+            .bind_on(&tokio::runtime::Handle::current(), localhost())
+            .unwrap(),
+    ];
+    for listener in &listeners {
+        let addr = listener.local_addr().unwrap();
+        assert_eq!(listener.local_addrs(), [addr; WORKERS]);
+        accepts_every_client(listener, addr).await;
+    }
+
+    let listener = builder()
+        .bind_multi(&[localhost(), localhost()])
+        .await
+        .unwrap();
+    let addrs = listener.local_addrs().to_vec();
+    assert_eq!(addrs.len(), 2 * WORKERS);
+    assert_eq!(addrs[..WORKERS], [addrs[0]; WORKERS]);
+    assert_eq!(addrs[WORKERS..], [addrs[WORKERS]; WORKERS]);
+    accepts_every_client(&listener, addrs[0]).await;
+    accepts_every_client(&listener, addrs[WORKERS]).await;
+}
+
+#[tokio::test]
+async fn from_std_refuses_several_demux_workers() {
+    let socket = StdUdpSocket::bind(localhost()).unwrap();
+    let Err(err) = UdpListener::builder().demux_workers(2).from_std(socket) else {
+        panic!("a single socket was split between workers");
+    };
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[tokio::test]
+async fn datagrams_dropped_by_a_full_receive_buffer_are_counted() {
+    const SENT: usize = 200;
+    let listener = UdpListener::builder()
+        .kernel_drops(true)
+        .channel_capacity(SENT)
+        .bind(localhost())
+        .await
+        .unwrap();
+    // The kernel raises this to its minimum, a few datagrams at most.
+    socket2::SockRef::from(&borrow_fd(&listener))
+        .set_recv_buffer_size(1)
+        .unwrap();
+    let client = client(listener.local_addr().unwrap());
+
+    // The receive loop cannot run while the test sends, so the buffer fills.
+    for _ in 0..SENT - 1 {
+        client.send(&[7u8; 1000]).unwrap();
+    }
+    let (stream, _) = listener.accept().await.unwrap();
+    tokio::time::sleep(SETTLE).await;
+    assert_eq!(listener.stats().dropped_kernel, 0);
+
+    // The counter comes with the next datagram read.
+    client.send(&[7u8; 1000]).unwrap();
+    tokio::time::sleep(SETTLE).await;
+    let dropped = listener.stats().dropped_kernel;
+    assert!(dropped > 0);
+    let mut received = 0;
+    while tokio::time::timeout(SETTLE, stream.recv()).await.is_ok() {
+        received += 1;
+    }
+    assert_eq!(received + dropped, SENT as u64);
+}
+
+#[tokio::test]
+async fn broadcast_and_unscoped_multicast_peers_are_refused() {
+    let broadcast = SocketAddr::from((Ipv4Addr::BROADCAST, 4000));
+    let Err(err) = UdpStream::connect(broadcast).await else {
+        panic!("connected to the broadcast address");
+    };
+    assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    let peer = err
+        .get_ref()
+        .unwrap()
+        .downcast_ref::<InvalidPeer>()
+        .unwrap();
+    assert_eq!(peer.addr(), broadcast);
+
+    if StdUdpSocket::bind("[::1]:0").is_ok() {
+        let multicast = SocketAddr::from((Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1), 4000));
+        let Err(err) = UdpStream::connect(multicast).await else {
+            panic!("connected to a link-local multicast address without a scope");
+        };
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.get_ref().unwrap().is::<InvalidPeer>());
+    }
+
+    // Multicast groups with a route can be sent to, as documented.
+    let multicast = SocketAddr::from((Ipv4Addr::new(224, 0, 0, 1), 4000));
+    let stream = UdpStream::connect(multicast).await.unwrap();
+    stream.send_datagram(b"hello").await.unwrap();
+}
@@ -0,0 +1,60 @@
+//! The spans recorded with the `tracing` feature.
+#![cfg(feature = "tracing")]
+
+mod common;
+
+use std::sync::{Arc, Mutex};
+
+use common::{client, localhost, read_datagram};
+use tracing::{
+    field::{Field, Visit},
+    span::{Attributes, Id},
+    Subscriber,
+};
+use tracing_subscriber::{layer::Context, prelude::*, Layer};
+use udp_stream::UdpListener;
+
+/// The name and `peer` field of a span.
+type SpanRecord = (&'static str, Option<String>);
+
+/// Records every new span.
+#[derive(Clone, Default)]
+struct Spans(Arc<Mutex<Vec<SpanRecord>>>);
+
+struct PeerField(Option<String>);
+
+impl Visit for PeerField {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "peer" {
+            self.0 = Some(format!("{:?}", value));
+        }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for Spans {
+    fn on_new_span(&self, attrs: &Attributes<'_>, _: &Id, _: Context<'_, S>) {
+        let mut peer = PeerField(None);
+        attrs.record(&mut peer);
+        self.0
+            .lock()
+            .unwrap()
+            .push((attrs.metadata().name(), peer.0));
+    }
+}
+
+#[tokio::test]
+async fn every_peer_gets_a_span_with_its_address() {
+    let spans = Spans::default();
+    let _guard = tracing_subscriber::registry()
+        .with(spans.clone())
+        .set_default();
+
+    let listener = UdpListener::bind(localhost()).await.unwrap();
+    let client = client(listener.local_addr().unwrap());
+    client.send(b"hello").unwrap();
+    let (mut stream, peer_addr) = listener.accept().await.unwrap();
+    read_datagram(&mut stream).await;
+
+    let spans = spans.0.lock().unwrap();
+    assert_eq!(*spans, [("udp_peer", Some(peer_addr.to_string()))]);
+}
@@ -0,0 +1,73 @@
+//! Datagrams forwarded with `UdpStream::send_bytes` without being copied.
+//!
+//! Copies are spotted by a global allocator counting the large allocations of
+//! the test thread, so these tests have a binary of their own.
+
+mod common;
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+};
+
+use common::{client, localhost, recv_from, server};
+use udp_stream::{UdpListener, UdpStream};
+
+/// The size of the forwarded datagrams, larger than anything else the test
+/// thread allocates while sending.
+const LARGE: usize = 8192;
+
+thread_local! {
+    static LARGE_ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Counts the allocations of at least `LARGE` bytes made by each thread.
+struct CountingAllocator;
+
+// SAFETY: every call is forwarded to the system allocator unchanged.
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if layout.size() >= LARGE {
+            LARGE_ALLOCATIONS.with(|count| count.set(count.get() + 1));
+        }
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn large_allocations() -> usize {
+    LARGE_ALLOCATIONS.with(Cell::get)
+}
+
+// The receive loops run on worker threads, so only the allocations of the
+// test itself are counted on its thread.
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn received_datagrams_are_forwarded_without_a_copy() {
+    let listener = UdpListener::bind(localhost()).await.unwrap();
+    let client = client(listener.local_addr().unwrap());
+    let server = server().await;
+    let upstream = UdpStream::connect(server.local_addr().unwrap())
+        .await
+        .unwrap();
+
+    client.send(&[7u8; LARGE]).unwrap();
+    let (stream, _) = listener.accept().await.unwrap();
+    let datagram = stream.recv().await.unwrap();
+    assert_eq!(datagram.len(), LARGE);
+
+    let before = large_allocations();
+    assert_eq!(upstream.send_bytes(datagram.clone()).await.unwrap(), LARGE);
+    assert_eq!(large_allocations(), before);
+    // A copy would have been counted.
+    std::hint::black_box(datagram.to_vec());
+    assert_eq!(large_allocations(), before + 1);
+
+    let (forwarded, _) = recv_from(&server).await;
+    assert_eq!(forwarded, datagram);
+}